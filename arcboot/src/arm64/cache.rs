@@ -0,0 +1,3 @@
+// Cache and TLB maintenance lives in arcboot_api so kernels get the same functions. See arcboot_api::cache
+
+pub use arcboot_api::cache::*;
@@ -0,0 +1,77 @@
+// What the CPU implements, from the ID_AA64* registers. In the same bits kernels use to declare what they need
+
+use arcboot_api::note::{
+    CPU_FEATURE_ASIMD, CPU_FEATURE_ATOMICS, CPU_FEATURE_BTI, CPU_FEATURE_FP, CPU_FEATURE_PAN,
+    CPU_FEATURE_PAUTH, CPU_FEATURE_RNDR, CPU_FEATURE_SVE,
+};
+use core::arch::asm;
+
+/// Read an ID register by name
+macro_rules! read_id_reg {
+    ($reg:literal) => {{
+        let value: u64;
+        unsafe { asm!(concat!("mrs {}, ", $reg), out(reg) value, options(nomem, nostack)) };
+        value
+    }};
+}
+
+/// 4 bit field of an ID register
+fn field(reg: u64, shift: u64) -> u64 {
+    (reg >> shift) & 0xF
+}
+
+/// CPU_FEATURE_* bits of everything this CPU has
+pub fn features() -> u64 {
+    let pfr0 = read_id_reg!("ID_AA64PFR0_EL1");
+    let pfr1 = read_id_reg!("ID_AA64PFR1_EL1");
+    let isar0 = read_id_reg!("ID_AA64ISAR0_EL1");
+    let isar1 = read_id_reg!("ID_AA64ISAR1_EL1");
+    let mmfr1 = read_id_reg!("ID_AA64MMFR1_EL1");
+
+    let mut features = 0;
+
+    // FP and AdvSIMD are 0xF when not implemented
+    if field(pfr0, 16) != 0xF {
+        features |= CPU_FEATURE_FP;
+    }
+    if field(pfr0, 20) != 0xF {
+        features |= CPU_FEATURE_ASIMD;
+    }
+    if field(pfr0, 32) != 0 {
+        features |= CPU_FEATURE_SVE;
+    }
+    if field(pfr1, 0) != 0 {
+        features |= CPU_FEATURE_BTI;
+    }
+    if field(isar0, 20) >= 2 {
+        features |= CPU_FEATURE_ATOMICS;
+    }
+    if field(isar0, 60) != 0 {
+        features |= CPU_FEATURE_RNDR;
+    }
+    // APA or API
+    if field(isar1, 4) != 0 || field(isar1, 8) != 0 {
+        features |= CPU_FEATURE_PAUTH;
+    }
+    if field(mmfr1, 20) != 0 {
+        features |= CPU_FEATURE_PAN;
+    }
+
+    features
+}
+
+/// ID_AA64MMFR0_EL1 and ID_AA64MMFR2_EL1. Granules, PA and VA sizes
+pub fn memory_model() -> (u64, u64) {
+    (
+        read_id_reg!("ID_AA64MMFR0_EL1"),
+        read_id_reg!("ID_AA64MMFR2_EL1"),
+    )
+}
+
+/// Exception level we are running at
+pub fn current_el() -> u32 {
+    let current_el: u64;
+    unsafe { asm!("mrs {}, CurrentEL", out(reg) current_el, options(nomem, nostack)) };
+
+    ((current_el >> 2) & 0b11) as u32
+}
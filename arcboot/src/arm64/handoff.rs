@@ -0,0 +1,203 @@
+// ---------------
+// MMU HANDOFF
+// ---------------
+
+// Gets from UEFI's translation into the kernel's. Everything up to the trampoline runs on UEFI's identity map with the MMU on
+// The trampoline is identity mapped in both UEFI's tables and ours (TTBR0), so it can turn the MMU off, swap every register and turn it back on
+// while the PC stays valid. Nothing touches memory while the MMU is off, so the caches never disagree with what the kernel sees
+
+use alloc::vec;
+use arcboot_api::{AddressRange, MemoryMap, MemoryRegion, MemoryRegionType};
+use core::arch::global_asm;
+use cortex_a::registers::*;
+use tock_registers::interfaces::Readable;
+
+use super::{
+    cache,
+    interrupt::KernelVectors,
+    memory::{mmu_config, KernelPageTable, VaRange},
+};
+use crate::boot::KernelSwitch;
+use crate::memory::{
+    frame::FrameAllocator,
+    mmu::{IdentityMapped, MapError, PageMapper},
+    PageFlags, PAGE_SIZE,
+};
+
+/// Tables set aside for the identity map. Enough for the trampoline, the stack and a few buffers without any of them sharing tables
+const IDENTITY_TABLES: u64 = 16;
+
+/// SCTLR_EL1.{M, C, I}
+const SCTLR_MMU_AND_CACHES: u64 = 1 | 1 << 2 | 1 << 12;
+
+/// What the trampoline loads before it turns the MMU off. The layout is fixed, see the ldp's
+#[repr(C)]
+struct TrampolineArgs {
+    mair: u64,
+    tcr: u64,
+    ttbr0: u64,
+    ttbr1: u64,
+    sctlr: u64,
+    sp: u64,
+    entry: u64,
+    arg: u64,
+    vbar: u64,
+}
+
+global_asm!(
+    ".text",
+    ".balign 16",
+    ".global arcboot_trampoline",
+    "arcboot_trampoline:",
+    // x0 = &TrampolineArgs. Loaded while UEFI's tables are still up
+    "msr daifset, #0xf",
+    "ldp x1, x2, [x0]",
+    "ldp x3, x4, [x0, #16]",
+    "ldp x5, x6, [x0, #32]",
+    "ldp x7, x8, [x0, #48]",
+    "ldr x10, [x0, #64]",
+    // page tables, kernel and boot info all written
+    "dsb ish",
+    // MMU and data cache off. This page is identity mapped, so the next fetch is the same either way
+    "mrs x9, sctlr_el1",
+    "bic x9, x9, #1",
+    "bic x9, x9, #(1 << 2)",
+    "msr sctlr_el1, x9",
+    "isb",
+    // nothing from UEFI's tables can be used again
+    "tlbi vmalle1",
+    "dsb nsh",
+    "isb",
+    "msr mair_el1, x1",
+    "msr tcr_el1, x2",
+    "msr ttbr0_el1, x3",
+    "msr ttbr1_el1, x4",
+    "msr vbar_el1, x10",
+    "isb",
+    "msr sctlr_el1, x5",
+    "isb",
+    // stale lines from before UEFI loaded anything. The kernel's segments were cleaned to PoU when they were loaded
+    "ic iallu",
+    "dsb nsh",
+    "isb",
+    "mov sp, x6",
+    "mov x0, x8",
+    "mov x29, xzr",
+    "mov x30, xzr",
+    "br x7",
+    ".global arcboot_trampoline_end",
+    "arcboot_trampoline_end:",
+);
+
+extern "C" {
+    fn arcboot_trampoline(args: *const TrampolineArgs) -> !;
+    static arcboot_trampoline_end: u8;
+}
+
+/// The switch from UEFI's tables to the kernel's, through arcboot_trampoline
+/// TTBR0 gets an identity map of the trampoline, arcboot's stack and whatever identity_map is given, in the same mode as the kernel's tables
+/// VBAR_EL1 gets the kernel's vector table
+pub struct Arm64Handoff {
+    identity: KernelPageTable,
+    ttbr1: u64,
+    vectors: KernelVectors,
+    /// Frames for the identity tables, taken out of the main allocator up front so they are BootAllocated in the kernel's memory map
+    frames: FrameAllocator,
+}
+
+impl Arm64Handoff {
+    /// Has to be made before FrameAllocator::memory_map, since it takes frames for its tables
+    pub fn new(
+        kernel_tables: &KernelPageTable,
+        vectors: &KernelVectors,
+        free_frames: &mut FrameAllocator,
+    ) -> Result<Self, MapError> {
+        let mode = *kernel_tables.mode();
+        let size = IDENTITY_TABLES * mode.page_size();
+        let start = free_frames
+            .allocate_contiguous(size / PAGE_SIZE, mode.page_size())
+            .ok_or(MapError::OutOfFrames)?;
+        let mut frames = FrameAllocator::new(&MemoryMap::new(vec![MemoryRegion::new(
+            MemoryRegionType::Standard,
+            (start, start + size),
+        )]));
+
+        let mem = unsafe { IdentityMapped::new() };
+        let identity = KernelPageTable::new(mem, VaRange::Lower, mode, &mut frames)?;
+        let mut res = Self {
+            identity,
+            ttbr1: kernel_tables.root(),
+            vectors: *vectors,
+            frames,
+        };
+
+        let trampoline = arcboot_trampoline as usize as u64;
+        let trampoline_end = unsafe { &arcboot_trampoline_end as *const u8 as u64 };
+        res.map((trampoline, trampoline_end), PageFlags::KERNEL_CODE)?;
+
+        Ok(res)
+    }
+
+    /// Identity map every page range touches that isnt mapped yet
+    fn map(&mut self, range: AddressRange, flags: PageFlags) -> Result<(), MapError> {
+        let page_size = self.identity.page_size();
+        let mut page = range.0 & !(page_size - 1);
+        while page < range.1 {
+            if self.identity.translate(page).is_none() {
+                self.identity.map(page, page, flags, &mut self.frames)?;
+            }
+            page += page_size;
+        }
+
+        Ok(())
+    }
+}
+
+impl KernelSwitch for Arm64Handoff {
+    fn vector_table(&self) -> Option<(u64, u64)> {
+        Some((self.vectors.vaddr, self.vectors.handlers))
+    }
+
+    fn kernel_tables(&self) -> u64 {
+        self.ttbr1
+    }
+
+    fn identity_map(&mut self, range: AddressRange) {
+        self.map(range, PageFlags::KERNEL_DATA)
+            .unwrap_or_else(|err| panic!("Could not identity map {:#X}: {err}", range.0));
+    }
+
+    unsafe fn switch(mut self, entry: u64, stack_top: u64, arg: u64) -> ! {
+        if super::cpu::current_el() != 1 {
+            panic!("The MMU handoff only knows EL1's registers");
+        }
+
+        // the stack we are on now, in case anything is still pointed at it when the kernel starts
+        let sp = SP.get();
+        self.identity_map((sp, sp + 1));
+
+        let config = mmu_config(self.identity.mode(), true);
+        let args = TrampolineArgs {
+            mair: config.mair(),
+            tcr: config.tcr(),
+            ttbr0: self.identity.root(),
+            ttbr1: self.ttbr1,
+            sctlr: SCTLR_EL1.get() | SCTLR_MMU_AND_CACHES,
+            sp: stack_top,
+            entry,
+            arg,
+            vbar: self.vectors.vaddr,
+        };
+        info!(
+            "Handing off to {entry:#X} with TTBR0 = {:#X}, TTBR1 = {:#X}",
+            args.ttbr0, args.ttbr1
+        );
+
+        // fetched with the MMU off, so it has to be in memory and not just the data cache
+        let trampoline = arcboot_trampoline as usize as u64;
+        let trampoline_end = &arcboot_trampoline_end as *const u8 as u64;
+        cache::clean_dcache_range(trampoline, trampoline_end - trampoline);
+
+        arcboot_trampoline(&args)
+    }
+}
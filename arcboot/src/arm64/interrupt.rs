@@ -1,29 +1,259 @@
-/// Interrupt handling and setup for ARM64
-// Would usually be 0x0 as I've heard, or 0x4000_0000... since we can just set the interrupt vector reg
-// pub const INTERRUPT_VECTOR_ADDR_BASE: u64 = 0x0;
-use aarch64::regs::VBAR_EL1;
+// ---------------
+// EXCEPTION VECTORS
+// ---------------
+
+// Arcboot's own vector table. Every entry saves a full TrapFrame and calls arcboot_trap, which says what went wrong and dumps the registers
+// There is one table for EL1 and one for EL2, since the ELR/SPSR/ESR/FAR it saves depend on the EL it runs at
+// Only installed once boot services are gone. UEFI takes its timer IRQs through its own table, and a vector cant hand one on without a spare register
+//
+// The kernel gets a copy of a third table, which calls whatever ArcServices::register_interrupt_handler put in its handler slots.
+// It is position independent, so it runs from wherever it is mapped in TTBR1
+
+use arcboot_api::exception::{Exception, TrapFrame, VectorKind, TRAP_FRAME_SIZE, VECTOR_ENTRIES};
+use arcboot_api::InterruptHandler;
+use core::arch::{asm, global_asm};
+use core::mem::size_of;
+use cortex_a::registers::{VBAR_EL1, VBAR_EL2};
 use tock_registers::interfaces::Writeable;
 
-/// A full table has defined methods for sync, async, nmi (timers and important signals) and maskable interrupts
-/// Set this up (before paging?) and set the interrupt reg
-pub struct InterruptVectorTable {
-    interrupt_vector_addr_base: u64,
+use super::{cache, memory::KernelPageTable};
+use crate::memory::{
+    frame::FrameAllocator,
+    mmu::{MapError, PageMapper},
+    DirectMap, PageFlags, PAGE_SIZE,
+};
+
+/// SPSR.{I, F}
+const SPSR_IRQ_FIQ_MASKED: u64 = 1 << 7 | 1 << 6;
+
+/// Where the kernel's copy of the vector table is mapped. After the MMIO window
+pub const KERNEL_VECTORS_VADDR: u64 = 0xFFFF_C000_0000_0000;
+
+global_asm!(
+    // one 0x80 byte entry. x0 and x1 are saved first, so the slot number can go in x1
+    ".macro arcboot_vector slot, common",
+    ".balign 0x80",
+    "sub sp, sp, #{frame_size}",
+    "stp x0, x1, [sp]",
+    "mov x1, #\\slot",
+    "b \\common",
+    ".endm",
+    // 16 entries in VectorSlot order
+    ".macro arcboot_vector_entries common",
+    "arcboot_vector 0, \\common",
+    "arcboot_vector 1, \\common",
+    "arcboot_vector 2, \\common",
+    "arcboot_vector 3, \\common",
+    "arcboot_vector 4, \\common",
+    "arcboot_vector 5, \\common",
+    "arcboot_vector 6, \\common",
+    "arcboot_vector 7, \\common",
+    "arcboot_vector 8, \\common",
+    "arcboot_vector 9, \\common",
+    "arcboot_vector 10, \\common",
+    "arcboot_vector 11, \\common",
+    "arcboot_vector 12, \\common",
+    "arcboot_vector 13, \\common",
+    "arcboot_vector 14, \\common",
+    "arcboot_vector 15, \\common",
+    ".endm",
+    // the rest of the frame, see TrapFrame. x1 is still the slot number
+    ".macro arcboot_save_frame el",
+    "stp x2, x3, [sp, #16]",
+    "stp x4, x5, [sp, #32]",
+    "stp x6, x7, [sp, #48]",
+    "stp x8, x9, [sp, #64]",
+    "stp x10, x11, [sp, #80]",
+    "stp x12, x13, [sp, #96]",
+    "stp x14, x15, [sp, #112]",
+    "stp x16, x17, [sp, #128]",
+    "stp x18, x19, [sp, #144]",
+    "stp x20, x21, [sp, #160]",
+    "stp x22, x23, [sp, #176]",
+    "stp x24, x25, [sp, #192]",
+    "stp x26, x27, [sp, #208]",
+    "stp x28, x29, [sp, #224]",
+    "mrs x2, elr_el\\el",
+    "stp x30, x2, [sp, #240]",
+    "mrs x2, spsr_el\\el",
+    "mrs x3, esr_el\\el",
+    "stp x2, x3, [sp, #256]",
+    "mrs x2, far_el\\el",
+    "stp x2, x1, [sp, #272]",
+    ".endm",
+    // the handler can change where eret goes, and with what PSTATE
+    ".macro arcboot_restore_frame el",
+    "ldp x2, x3, [sp, #248]",
+    "msr elr_el\\el, x2",
+    "msr spsr_el\\el, x3",
+    "ldp x0, x1, [sp]",
+    "ldp x2, x3, [sp, #16]",
+    "ldp x4, x5, [sp, #32]",
+    "ldp x6, x7, [sp, #48]",
+    "ldp x8, x9, [sp, #64]",
+    "ldp x10, x11, [sp, #80]",
+    "ldp x12, x13, [sp, #96]",
+    "ldp x14, x15, [sp, #112]",
+    "ldp x16, x17, [sp, #128]",
+    "ldp x18, x19, [sp, #144]",
+    "ldp x20, x21, [sp, #160]",
+    "ldp x22, x23, [sp, #176]",
+    "ldp x24, x25, [sp, #192]",
+    "ldp x26, x27, [sp, #208]",
+    "ldp x28, x29, [sp, #224]",
+    "ldr x30, [sp, #240]",
+    "add sp, sp, #{frame_size}",
+    "eret",
+    ".endm",
+    // arcboot's tables
+    ".macro arcboot_vectors el",
+    ".balign 2048",
+    ".global arcboot_vectors_el\\el",
+    "arcboot_vectors_el\\el:",
+    "arcboot_vector_entries arcboot_trap_el\\el",
+    "arcboot_trap_el\\el:",
+    "arcboot_save_frame \\el",
+    "mov x0, sp",
+    "bl arcboot_trap",
+    "arcboot_restore_frame \\el",
+    ".endm",
+    ".text",
+    "arcboot_vectors 1",
+    "arcboot_vectors 2",
+    // the kernel's table. Copied out by KernelVectors, so nothing in here can point outside of it
+    ".balign 2048",
+    ".global arcboot_kernel_vectors",
+    "arcboot_kernel_vectors:",
+    "arcboot_vector_entries arcboot_kernel_trap",
+    "arcboot_kernel_trap:",
+    "arcboot_save_frame 1",
+    "ldr x2, arcboot_kernel_handlers",
+    "ldr x2, [x2, x1, lsl #3]",
+    "cbz x2, 1f",
+    "mov x0, sp",
+    "blr x2",
+    "arcboot_restore_frame 1",
+    // no handler, and nobody to tell
+    "1:",
+    "wfe",
+    "b 1b",
+    ".balign 8",
+    // vaddr of the handler slots, filled in by KernelVectors
+    ".global arcboot_kernel_handlers",
+    "arcboot_kernel_handlers:",
+    ".quad 0",
+    ".global arcboot_kernel_vectors_end",
+    "arcboot_kernel_vectors_end:",
+    frame_size = const TRAP_FRAME_SIZE,
+);
+
+extern "C" {
+    static arcboot_vectors_el1: u8;
+    static arcboot_vectors_el2: u8;
+    static arcboot_kernel_vectors: u8;
+    static arcboot_kernel_handlers: u8;
+    static arcboot_kernel_vectors_end: u8;
 }
 
-pub type HandlerFn = fn();
+/// Point VBAR at the table for the EL we are at
+pub fn install() {
+    unsafe {
+        match super::cpu::current_el() {
+            2 => VBAR_EL2.set(&arcboot_vectors_el2 as *const u8 as u64),
+            _ => VBAR_EL1.set(&arcboot_vectors_el1 as *const u8 as u64),
+        }
+        asm!("isb", options(nostack));
+    }
+}
+
+/// The vector table the kernel is entered with, and the handler slots it calls. See ArcServices::register_interrupt_handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelVectors {
+    /// What VBAR_EL1 gets. Executable, at KERNEL_VECTORS_VADDR
+    pub vaddr: u64,
+    /// VECTOR_ENTRIES Option<InterruptHandler>s, zeroed. Through the direct map
+    pub handlers: u64,
+}
 
-impl InterruptVectorTable {
-    pub fn new(interrupt_vector_addr_base: u64) -> Self {
-        Self {
-            interrupt_vector_addr_base,
+impl KernelVectors {
+    /// Copy the kernel's table into a frame and map it. Has to be made before FrameAllocator::memory_map, like the rest of the kernel's tables
+    pub fn new(
+        tables: &mut KernelPageTable,
+        direct_map: &DirectMap,
+        frames: &mut FrameAllocator,
+    ) -> Result<Self, MapError> {
+        let page_size = tables.page_size();
+        let code = frames
+            .allocate_contiguous(page_size / PAGE_SIZE, page_size)
+            .ok_or(MapError::OutOfFrames)?;
+        let handlers = frames.allocate().ok_or(MapError::OutOfFrames)?;
+
+        // frames are identity mapped until the handoff
+        unsafe {
+            let start = &arcboot_kernel_vectors as *const u8;
+            let size = &arcboot_kernel_vectors_end as *const u8 as usize - start as usize;
+            let literal = &arcboot_kernel_handlers as *const u8 as usize - start as usize;
+
+            core::ptr::write_bytes(code as *mut u8, 0, page_size as usize);
+            core::ptr::copy_nonoverlapping(start, code as *mut u8, size);
+            *((code as usize + literal) as *mut u64) = direct_map.hhdm_offset + handlers;
+            core::ptr::write_bytes(
+                handlers as *mut u8,
+                0,
+                VECTOR_ENTRIES * size_of::<Option<InterruptHandler>>(),
+            );
+
+            // the trampoline turns the caches off before anything runs from here
+            cache::clean_dcache_range(code, page_size);
+            cache::clean_dcache_range(handlers, PAGE_SIZE);
         }
+
+        tables.map(KERNEL_VECTORS_VADDR, code, PageFlags::KERNEL_CODE, frames)?;
+
+        Ok(Self {
+            vaddr: KERNEL_VECTORS_VADDR,
+            handlers: direct_map.hhdm_offset + handlers,
+        })
     }
+}
+
+/// Every entry of arcboot's tables ends up here
+#[no_mangle]
+extern "C" fn arcboot_trap(frame: &mut TrapFrame) {
+    let slot = frame
+        .vector_slot()
+        .expect("Vector table passed a bad slot number");
 
-    /// Depending on the types of interrupts. Save your stack in your handler
-    /// Simply maps the handler function somewhere in kernel core memory and points VBAR.handler_id to it
-    pub fn register_handler(&self, addr: u64, handler: HandlerFn) {}
+    match slot.kind {
+        VectorKind::Sync => {
+            let exception = frame.syndrome().decode();
+            if let Exception::Brk(imm) = exception {
+                // left in on purpose, keep going
+                warn!("BRK #{imm:#X} at {:#X}\n{frame}", frame.elr);
+                frame.skip_instruction();
+                return;
+            }
 
-    pub fn set_interrupt_register_base_addr(addr: u64) {
-        VBAR_EL1.set(addr);
+            error!("{slot}: {exception}");
+            if let Exception::DataAbort {
+                far_valid: true, ..
+            }
+            | Exception::InstructionAbort { .. } = exception
+            {
+                error!("Faulting address {:#X}", frame.far);
+            }
+            error!("Registers:\n{frame}");
+            panic!("Unhandled exception at {:#X}", frame.elr);
+        }
+        // arcboot never unmasks anything. Whatever it was stays masked until the kernel wants it
+        VectorKind::Irq | VectorKind::Fiq => {
+            warn!("Spurious {slot} at {:#X}, masking it", frame.elr);
+            frame.spsr |= SPSR_IRQ_FIQ_MASKED;
+        }
+        VectorKind::SError => {
+            error!("{slot}: {}", frame.syndrome().decode());
+            error!("Registers:\n{frame}");
+            panic!("SError at {:#X}", frame.elr);
+        }
     }
 }
@@ -1,4 +1,8 @@
 pub mod drivers;
 pub mod setup;
 pub mod memory;
+pub mod handoff;
+pub mod cache;
 pub mod interrupt;
+pub mod rng;
+pub mod cpu;
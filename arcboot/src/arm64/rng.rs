@@ -0,0 +1,36 @@
+// ARMv8.5 RNG. For when the firmware doesnt have EFI_RNG_PROTOCOL
+
+use core::arch::asm;
+
+/// ID_AA64ISAR0_EL1.RNDR, bits 63:60
+const ID_AA64ISAR0_RNDR_SHIFT: u64 = 60;
+
+/// Whether RNDR and RNDRRS are implemented
+pub fn has_rndr() -> bool {
+    let isar0: u64;
+    unsafe { asm!("mrs {}, ID_AA64ISAR0_EL1", out(reg) isar0, options(nomem, nostack)) };
+
+    (isar0 >> ID_AA64ISAR0_RNDR_SHIFT) & 0xF != 0
+}
+
+/// Read RNDR. None if not implemented, or if it couldnt produce a number in time (PSTATE.Z set)
+pub fn rndr() -> Option<u64> {
+    if !has_rndr() {
+        return None;
+    }
+
+    let value: u64;
+    let failed: u64;
+    unsafe {
+        // RNDR = s3_3_c2_c4_0. Sets NZCV to 0b0100 on failure
+        asm!(
+            "mrs {value}, s3_3_c2_c4_0",
+            "cset {failed}, eq",
+            value = out(reg) value,
+            failed = out(reg) failed,
+            options(nomem, nostack),
+        );
+    }
+
+    (failed == 0).then(|| value)
+}
@@ -1,111 +1,1963 @@
-use core::{arch::asm, intrinsics::transmute};
+use core::{fmt, ops::RangeInclusive};
 
-use alloc::vec::Vec;
-use arcboot_api::{make_default, ArcServices, DefaultServices};
+use alloc::{string::String, vec, vec::Vec};
+use arcboot_api::{
+    bootinfo::BootInfoWriter,
+    load_segment, make_default,
+    note::{self, KernelRequirements, NoteError},
+    AddressRange, MemoryMap, MemoryRegionType, ARC_SERVICES_VERSION,
+};
 use goblin::{
     container::{Container, Ctx},
-    elf::{program_header, Elf, ProgramHeader},
+    elf::{
+        dynamic::{self, Dynamic},
+        header, program_header, reloc,
+        reloc::RelocSection,
+        Elf, ProgramHeader,
+    },
 };
 
-use crate::memory::{map_segment, set_stack};
+use crate::config::BootModule;
+use crate::memory::{
+    frame::FrameAllocator,
+    mmu::{MapError, PageMapper},
+    DirectMap, PageFlags, PAGE_SIZE,
+};
 
 const ELF64_HDR_SIZE: usize = 64;
+const ELF64_PHDR_SIZE: usize = 56;
 
-/// Given a kernel ELF img in bytes, parse and load its segments
-/// Then pass off execution to it. Should prob have setup TTBR1 and using those addresses (0xffff__ffff_ffff_ffff - 0x0000_ffff_ffff_ffff)
-pub fn load_kernel(kernel_img: &[u8]) -> ! {
-    // PARSE KERNEL ELF
+/// e_machine of kernels that can run on this arch
+#[cfg(target_arch = "aarch64")]
+pub const NATIVE_MACHINE: u16 = header::EM_AARCH64;
+#[cfg(target_arch = "x86_64")]
+pub const NATIVE_MACHINE: u16 = header::EM_X86_64;
+#[cfg(target_arch = "riscv64")]
+pub const NATIVE_MACHINE: u16 = header::EM_RISCV;
 
-    let header =
-        Elf::parse_header(&kernel_img[..ELF64_HDR_SIZE]).map_err(|_| "parse elf header error");
+/// Higher half of a 48 bit VA space (TTBR1 on arm64). Where kernels are expected to be linked
+pub const KERNEL_VA_WINDOW: RangeInclusive<u64> = 0xFFFF_0000_0000_0000..=0xFFFF_FFFF_FFFF_FFFF;
 
-    let header = match header {
-        Ok(h) => h,
-        Err(err) => panic!("Error! {err}"),
-    };
+/// Where KASLR can put a kernel. The first 512 GiB of TTBR1, i.e. what the first L0 entry covers
+pub const KASLR_WINDOW: RangeInclusive<u64> = 0xFFFF_0000_0000_0000..=0xFFFF_007F_FFFF_FFFF;
+
+/// KASLR bases are at least 2 MiB aligned so the kernel can still be block mapped
+pub const KASLR_ALIGN: u64 = 0x20_0000;
+
+/// Where position independent kernels go in TTBR1 without KASLR. Their frames come from wherever there is free RAM, see reserve_kernel_segments
+pub const DEFAULT_PIE_VADDR: u64 = 0xFFFF_0000_0000_0000;
+
+/// Most boot stack + heap a kernel can ask for. Has to come out of the boot frames along with the page tables
+pub const DEFAULT_BOOT_MEMORY_LIMIT: u64 = 256 * PAGE_SIZE;
+
+// ---------------
+// ERRORS
+// ---------------
+
+/// Why a kernel ELF was rejected. Nothing has been jumped to, so arcboot can try another boot entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelLoadError {
+    /// Img is smaller than an ELF64 header
+    TruncatedHeader,
+    /// Img does not start with \x7FELF
+    BadMagic,
+    /// Only little endian ELF64 is supported
+    UnsupportedFormat,
+    /// e_type is not something arcboot can load
+    UnsupportedType(u16),
+    /// Kernel was built for another arch
+    WrongMachine { expected: u16, found: u16 },
+    /// Program header table goes past the end of the img, or has the wrong entry size
+    TruncatedProgramHeaders,
+    /// There are no PT_LOAD segments
+    NoLoadableSegments,
+    /// A segment's file bytes go past the end of the img, or p_filesz > p_memsz
+    TruncatedSegment { vaddr: u64 },
+    /// p_align is not a power of 2, or vaddr/paddr/offset disagree modulo p_align
+    UnalignedSegment { vaddr: u64 },
+    /// Two segments share virtual or physical memory
+    OverlappingSegments { first: u64, second: u64 },
+    /// Entry point is not aligned to an instruction boundary
+    UnalignedEntry(u64),
+    /// A segment is not fully inside any of the allowed VA windows
+    SegmentOutsideWindow { vaddr: u64 },
+    /// A relocatable kernel that takes up more than KASLR_WINDOW. span is its size in bytes
+    KernelTooLargeForKaslr { span: u64 },
+    /// PT_DYNAMIC or the relocation tables it points at are malformed, or use DT_REL
+    BadDynamicSection,
+    /// Only R_*_RELATIVE relocations are supported, kernels cant import symbols
+    UnsupportedRelocation(u32),
+    /// A relocation patches memory that isnt part of any PT_LOAD segment
+    RelocationOutsideSegments(u64),
+    /// A page would be both writable and executable, and the boot entry doesnt allow it
+    WritableAndExecutable { vaddr: u64 },
+    /// The arcboot note is truncated or from a note version arcboot doesnt know
+    BadRequirementsNote(NoteError),
+    /// Kernel wants a newer ArcServices than this arcboot provides
+    ServicesTooOld { required: u32, provided: u32 },
+    /// Kernel wants to be entered at an EL arcboot cant enter it at
+    UnsupportedEntryLevel(u32),
+    /// CPU_FEATURE_* bits the kernel needs that the CPU doesnt have
+    MissingCpuFeatures(u64),
+    /// Boot stack + heap is more than arcboot can set aside
+    BootMemoryTooLarge { requested: u64, limit: u64 },
+    /// Boot stack or heap is unaligned, outside the VA windows, or overlaps a segment or each other
+    BadBootLayout { vaddr: u64 },
+    /// An ET_EXEC kernel's segments need frames that arent free RAM, e.g. arcboot's heap
+    FramesNotFree(AddressRange),
+    /// No free RAM big enough for an ET_DYN kernel's segments
+    NoFramesForKernel { size: u64 },
+}
+
+impl fmt::Display for KernelLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TruncatedHeader => write!(f, "img is too small for an ELF64 header"),
+            Self::BadMagic => write!(f, "not an ELF file"),
+            Self::UnsupportedFormat => write!(f, "only little endian ELF64 is supported"),
+            Self::UnsupportedType(e_type) => write!(f, "cannot load ELF of type {e_type}"),
+            Self::WrongMachine { expected, found } => {
+                write!(f, "kernel is for e_machine {found}, expected {expected}")
+            }
+            Self::TruncatedProgramHeaders => write!(f, "program headers are truncated"),
+            Self::NoLoadableSegments => write!(f, "no PT_LOAD segments"),
+            Self::TruncatedSegment { vaddr } => write!(f, "segment at {vaddr:#X} is truncated"),
+            Self::UnalignedSegment { vaddr } => {
+                write!(f, "segment at {vaddr:#X} does not respect its p_align")
+            }
+            Self::OverlappingSegments { first, second } => {
+                write!(f, "segments at {first:#X} and {second:#X} overlap")
+            }
+            Self::UnalignedEntry(entry) => write!(f, "entry point {entry:#X} is unaligned"),
+            Self::SegmentOutsideWindow { vaddr } => {
+                write!(f, "segment at {vaddr:#X} is outside the allowed VA windows")
+            }
+            Self::KernelTooLargeForKaslr { span } => {
+                write!(
+                    f,
+                    "kernel needs {span:#X} bytes of VA, more than the KASLR window"
+                )
+            }
+            Self::BadDynamicSection => write!(f, "dynamic section is malformed"),
+            Self::UnsupportedRelocation(r_type) => {
+                write!(f, "relocation type {r_type} is not supported")
+            }
+            Self::RelocationOutsideSegments(offset) => {
+                write!(
+                    f,
+                    "relocation at {offset:#X} is outside the loaded segments"
+                )
+            }
+            Self::WritableAndExecutable { vaddr } => {
+                write!(f, "segment at {vaddr:#X} would be writable and executable")
+            }
+            Self::BadRequirementsNote(err) => write!(f, "arcboot note is malformed: {err:?}"),
+            Self::ServicesTooOld { required, provided } => write!(
+                f,
+                "kernel needs ArcServices version {required}, arcboot provides {provided}"
+            ),
+            Self::UnsupportedEntryLevel(el) => write!(f, "cannot enter the kernel at EL{el}"),
+            Self::MissingCpuFeatures(features) => {
+                write!(f, "CPU is missing required features {features:#X}")
+            }
+            Self::BootMemoryTooLarge { requested, limit } => write!(
+                f,
+                "kernel wants {requested:#X} bytes of boot stack and heap, limit is {limit:#X}"
+            ),
+            Self::BadBootLayout { vaddr } => {
+                write!(f, "boot stack or heap at {vaddr:#X} cannot be mapped")
+            }
+            Self::FramesNotFree((start, end)) => {
+                write!(f, "segments at {start:#X}..{end:#X} are not in free RAM")
+            }
+            Self::NoFramesForKernel { size } => {
+                write!(f, "no free RAM for {size:#X} bytes of segments")
+            }
+        }
+    }
+}
+
+// ---------------
+// KERNEL SEGMENTS
+// ---------------
+
+/// A PT_LOAD segment of the kernel ELF
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelSegment {
+    /// Where the kernel expects the segment in its (TTBR1) address space
+    pub vaddr: u64,
+    /// Where the segment should be placed in physical memory
+    pub paddr: u64,
+    /// Offset of the segment's bytes in the ELF img
+    pub offset: u64,
+    /// Bytes to copy from the ELF img
+    pub file_size: u64,
+    /// Bytes the segment takes up in memory. Anything past file_size is .bss and zeroed
+    pub mem_size: u64,
+    /// p_align. vaddr, paddr and offset must all be congruent modulo this
+    pub align: u64,
+    /// PF_R, PF_W, PF_X
+    pub flags: u32,
+}
+
+impl KernelSegment {
+    pub fn new(header: &ProgramHeader) -> Self {
+        Self {
+            vaddr: header.p_vaddr,
+            paddr: header.p_paddr,
+            offset: header.p_offset,
+            file_size: header.p_filesz,
+            mem_size: header.p_memsz,
+            align: header.p_align,
+            flags: header.p_flags,
+        }
+    }
+
+    /// Vaddr of the first page the segment touches. page_size is the granule it gets mapped with
+    pub fn page_start(&self, page_size: u64) -> u64 {
+        self.vaddr & !(page_size - 1)
+    }
+
+    /// Paddr of the first frame the segment touches, i.e. what page_start maps to
+    pub fn frame_start(&self, page_size: u64) -> u64 {
+        self.paddr & !(page_size - 1)
+    }
+
+    /// Vaddr of the last page the segment touches. A segment with no memory still touches the page at vaddr
+    pub fn last_page(&self, page_size: u64) -> u64 {
+        // parse rejects segments that wrap around
+        self.last_vaddr().unwrap_or(u64::MAX) & !(page_size - 1)
+    }
+
+    /// Number of pages needed to cover the segment in memory
+    pub fn n_pages(&self, page_size: u64) -> u64 {
+        if self.mem_size == 0 {
+            return 0;
+        }
+        // from the last byte, vaddr + mem_size overflows for a segment that ends at the top of the address space
+        (self.last_page(page_size) - self.page_start(page_size)) / page_size + 1
+    }
+
+    /// Last vaddr of the segment. None if it wraps around the address space
+    pub fn last_vaddr(&self) -> Option<u64> {
+        self.vaddr.checked_add(self.mem_size.max(1) - 1)
+    }
+
+    /// Last paddr of the segment. None if it wraps around the address space
+    pub fn last_paddr(&self) -> Option<u64> {
+        self.paddr.checked_add(self.mem_size.max(1) - 1)
+    }
+
+    /// The bytes of the segment that are stored in the ELF img
+    pub fn file_bytes<'a>(&self, kernel_img: &'a [u8]) -> &'a [u8] {
+        let offset = self.offset as usize;
+        &kernel_img[offset..offset + self.file_size as usize]
+    }
+
+    /// vaddr and paddr have to sit at the same offset into a page, or the pages cant be mapped to frames
+    /// p_align of 0 or 1 means no alignment. Otherwise it has to be a power of 2 and vaddr/paddr have to sit at the same offset into an alignment block as the file offset does
+    pub fn is_aligned(&self, page_size: u64) -> bool {
+        if self.vaddr % page_size != self.paddr % page_size {
+            return false;
+        }
+        if self.align <= 1 {
+            return true;
+        }
+
+        self.align.is_power_of_two()
+            && self.vaddr % self.align == self.offset % self.align
+            && self.paddr % self.align == self.vaddr % self.align
+    }
+
+    /// What the segment's pages get mapped with
+    pub fn page_flags(&self) -> PageFlags {
+        PageFlags::from_elf(self.flags)
+    }
+
+    /// Whether the segment and `other` touch the same page
+    pub fn shares_page(&self, other: &KernelSegment, page_size: u64) -> bool {
+        self.page_start(page_size) <= other.last_page(page_size)
+            && other.page_start(page_size) <= self.last_page(page_size)
+    }
+
+    /// Whether `len` bytes at `vaddr` are all inside the segment's memory
+    pub fn contains(&self, vaddr: u64, len: u64) -> bool {
+        vaddr >= self.vaddr
+            && vaddr
+                .checked_add(len)
+                .is_some_and(|end| end - self.vaddr <= self.mem_size)
+    }
+
+    /// Whether the segment's memory shares any bytes with `other`, virtually or physically
+    pub fn overlaps(&self, other: &KernelSegment) -> bool {
+        // anything that wraps around overlaps everything
+        match (
+            self.last_vaddr(),
+            self.last_paddr(),
+            other.last_vaddr(),
+            other.last_paddr(),
+        ) {
+            (
+                Some(last_vaddr),
+                Some(last_paddr),
+                Some(other_last_vaddr),
+                Some(other_last_paddr),
+            ) => {
+                (self.vaddr <= other_last_vaddr && other.vaddr <= last_vaddr)
+                    || (self.paddr <= other_last_paddr && other.paddr <= last_paddr)
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Provides the memory to load kernel segments into
+pub trait SegmentMapper {
+    /// Map every page of the segment at its vaddr, backed by the frames at its paddr. Returns the `mem_size` bytes starting at the segment's paddr so they can be filled in
+    fn map_segment(&mut self, segment: &KernelSegment) -> &mut [u8];
+
+    /// The segment is fully written, relocations included. E.g. clean the caches so the kernel's instruction fetches see it
+    fn segment_loaded(&mut self, _segment: &KernelSegment) {}
+}
+
+/// Map the pages of segment in tables, for a SegmentMapper. Segments only share pages at their ends, and parse made sure a shared page is the same frame for both
+/// So a page another segment already mapped is kept, with the permissions of both
+pub fn map_segment_pages(
+    tables: &mut impl PageMapper,
+    segment: &KernelSegment,
+    frames: &mut FrameAllocator,
+) -> Result<(), MapError> {
+    if segment.mem_size == 0 {
+        return Ok(());
+    }
+
+    let page_size = tables.page_size();
+    let flags = segment.page_flags();
+    let mut first = segment.page_start(page_size);
+    let mut frame = segment.frame_start(page_size);
+    let mut last = segment.last_page(page_size);
+
+    if share_page(tables, first, flags)? {
+        if first == last {
+            return Ok(());
+        }
+        first += page_size;
+        frame += page_size;
+    }
+    if share_page(tables, last, flags)? {
+        if first == last {
+            return Ok(());
+        }
+        last -= page_size;
+    }
+
+    tables.map_range(first, frame, last - first + page_size, flags, frames)
+}
+
+/// If page is already mapped, add flags to it
+fn share_page(tables: &mut impl PageMapper, page: u64, flags: PageFlags) -> Result<bool, MapError> {
+    match tables.translate(page) {
+        Some((_, mapped)) => {
+            tables.protect(page, mapped.union(flags))?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Gets the CPU from arcboot's translation into the kernel's. Arch specific, see arm64::handoff::Arm64Handoff
+pub trait KernelSwitch {
+    /// What VBAR will point at when the kernel starts, and the handler slots that table calls. Both kernel vaddrs
+    fn vector_table(&self) -> Option<(u64, u64)> {
+        None
+    }
+
+    /// Paddr of the root of the kernel's TTBR1 tables, for ArcServices
+    fn kernel_tables(&self) -> u64;
+
+    /// Keep the physical range reachable at its paddr until the kernel has its own TTBR0, e.g. the handoff buffer
+    fn identity_map(&mut self, range: AddressRange);
+
+    /// Switch to the kernel's tables, set SP to stack_top and branch to entry with arg as its first argument
+    ///
+    /// # Safety
+    /// The kernel has to be loaded, and its tables have to map entry and the stack below stack_top
+    unsafe fn switch(self, entry: u64, stack_top: u64, arg: u64) -> !;
+}
+
+// ---------------
+// KERNEL IMAGE
+// ---------------
+
+/// What kernels the loader accepts
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    /// e_machine the kernel has to be built for
+    pub machine: u16,
+    /// Every segment has to fit inside one of these
+    pub va_windows: Vec<RangeInclusive<u64>>,
+    /// Where to put ET_DYN kernels in TTBR1. Should be aligned to the kernel's biggest p_align. Ignored for ET_EXEC
+    pub pie_vaddr: u64,
+    /// Random number to pick the vaddr of ET_DYN kernels with (KASLR). None loads them at pie_vaddr, which is easier to debug
+    pub kaslr_seed: Option<u64>,
+    /// Let segments (or pages shared by segments) be writable and executable. Only for boot entries that ask for it
+    pub allow_wx: bool,
+    /// ArcServices version arcboot will pass to the kernel
+    pub services_version: u32,
+    /// CPU_FEATURE_* bits this CPU has
+    pub cpu_features: u64,
+    /// EL arcboot runs at. Kernels get entered here, arcboot doesnt drop or raise levels
+    pub current_el: u32,
+    /// Most boot stack + heap a kernel can ask for, in bytes
+    pub boot_memory_limit: u64,
+    /// Where RAM and MMIO get mapped in TTBR1
+    pub direct_map: DirectMap,
+    /// Granule the kernel gets mapped with. Segments are mapped in whole pages of it, the boot stack and heap have to be aligned to it
+    pub page_size: u64,
+}
+
+impl LoadOptions {
+    pub fn new(machine: u16, va_windows: Vec<RangeInclusive<u64>>) -> Self {
+        Self {
+            machine,
+            va_windows,
+            pie_vaddr: DEFAULT_PIE_VADDR,
+            kaslr_seed: None,
+            allow_wx: false,
+            services_version: ARC_SERVICES_VERSION,
+            cpu_features: 0,
+            current_el: 1,
+            boot_memory_limit: DEFAULT_BOOT_MEMORY_LIMIT,
+            direct_map: DirectMap::default(),
+            page_size: PAGE_SIZE,
+        }
+    }
+
+    /// The B + A relocation for this arch
+    pub fn relative_relocation(&self) -> u32 {
+        match self.machine {
+            header::EM_AARCH64 => reloc::R_AARCH64_RELATIVE,
+            header::EM_RISCV => reloc::R_RISCV_RELATIVE,
+            _ => reloc::R_X86_64_RELATIVE,
+        }
+    }
+
+    /// Instructions are 4 bytes on arm64, 2 with the RISC-V C extension and any size on x86
+    pub fn entry_alignment(&self) -> u64 {
+        match self.machine {
+            header::EM_AARCH64 => 4,
+            header::EM_RISCV => 2,
+            _ => 1,
+        }
+    }
+}
+
+impl Default for LoadOptions {
+    /// Kernels for this arch, linked in the higher half
+    fn default() -> Self {
+        Self::new(NATIVE_MACHINE, vec![KERNEL_VA_WINDOW])
+    }
+}
+
+/// An R_*_RELATIVE relocation: write `value` as a u64 at `vaddr`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Relocation {
+    pub vaddr: u64,
+    pub value: u64,
+}
+
+/// A parsed kernel ELF. Still needs to be loaded into memory
+#[derive(Debug, Clone)]
+pub struct KernelImage {
+    /// Entry point, with the load bias applied
+    pub entry: u64,
+    /// PT_LOAD segments, with the load bias applied
+    pub segments: Vec<KernelSegment>,
+    /// What was added to the link addresses. 0 for ET_EXEC kernels
+    pub load_bias: u64,
+    /// Patches to apply after loading. Empty for ET_EXEC kernels
+    pub relocations: Vec<Relocation>,
+    /// Biggest p_align of an ET_DYN kernel, whose paddrs are its p_paddrs until reserve_kernel_segments finds it frames. None for ET_EXEC kernels
+    pub pie_align: Option<u64>,
+    /// From the kernel's arcboot note, with defaults filled in. Stack and heap addresses are absolute, KASLR doesnt move them
+    pub requirements: KernelRequirements,
+}
+
+impl KernelImage {
+    /// Parse the ELF header and program headers and collect the PT_LOAD segments. Checks everything that can be checked before touching memory
+    pub fn parse(kernel_img: &[u8], options: &LoadOptions) -> Result<Self, KernelLoadError> {
+        if kernel_img.len() < ELF64_HDR_SIZE {
+            return Err(KernelLoadError::TruncatedHeader);
+        }
+        if &kernel_img[..header::SELFMAG] != header::ELFMAG {
+            return Err(KernelLoadError::BadMagic);
+        }
+        if kernel_img[header::EI_CLASS] != header::ELFCLASS64
+            || kernel_img[header::EI_DATA] != header::ELFDATA2LSB
+        {
+            return Err(KernelLoadError::UnsupportedFormat);
+        }
+
+        let header = Elf::parse_header(&kernel_img[..ELF64_HDR_SIZE])
+            .map_err(|_| KernelLoadError::UnsupportedFormat)?;
+
+        // ET_EXEC kernels stay where they were linked, ET_DYN kernels get moved
+        let is_pie = match header.e_type {
+            header::ET_EXEC => false,
+            header::ET_DYN => true,
+            e_type => return Err(KernelLoadError::UnsupportedType(e_type)),
+        };
+        if header.e_machine != options.machine {
+            return Err(KernelLoadError::WrongMachine {
+                expected: options.machine,
+                found: header.e_machine,
+            });
+        }
+
+        // program header table has to be fully inside the img
+        let program_table_size = header.e_phnum as usize * ELF64_PHDR_SIZE;
+        let program_table_fits = (header.e_phoff as usize)
+            .checked_add(program_table_size)
+            .is_some_and(|end| end <= kernel_img.len());
+        if header.e_phentsize as usize != ELF64_PHDR_SIZE || !program_table_fits {
+            return Err(KernelLoadError::TruncatedProgramHeaders);
+        }
 
-    let program_hdr_table_size = (header.e_phnum * header.e_phentsize) as usize;
-    let program_table = &kernel_img[ELF64_HDR_SIZE..ELF64_HDR_SIZE + program_hdr_table_size];
+        let ctx = Ctx {
+            le: scroll::Endian::Little,
+            container: Container::Big,
+        };
 
-    let mut elf = Elf::lazy_parse(header)
-        .map_err(|_| "cannot parse ELF file")
-        .unwrap();
+        // parse the program headers in place. e_phoff is relative to the start of the img
+        let program_headers = ProgramHeader::parse(
+            kernel_img,
+            header.e_phoff as usize,
+            header.e_phnum as usize,
+            ctx,
+        )
+        .map_err(|_| KernelLoadError::TruncatedProgramHeaders)?;
 
-    let ctx = Ctx {
-        le: scroll::Endian::Little,
-        container: Container::Big,
+        let loads = program_headers
+            .iter()
+            .filter(|h| h.p_type == program_header::PT_LOAD);
+
+        // only the vaddr is decided here. Where the frames go depends on what RAM is free, see reserve_kernel_segments
+        let (vaddr_bias, pie_align) = if is_pie {
+            let align = loads.clone().map(|h| h.p_align).max().unwrap_or(1);
+            (pie_vaddr(loads.clone(), options)?, Some(align.max(1)))
+        } else {
+            (0, None)
+        };
+
+        let segments: Vec<KernelSegment> = loads
+            .map(|h| {
+                let mut segment = KernelSegment::new(h);
+                segment.vaddr = segment.vaddr.wrapping_add(vaddr_bias);
+                segment
+            })
+            .collect();
+
+        if segments.is_empty() {
+            return Err(KernelLoadError::NoLoadableSegments);
+        }
+
+        for (index, segment) in segments.iter().enumerate() {
+            let file_end = segment.offset.checked_add(segment.file_size);
+            if segment.file_size > segment.mem_size
+                || file_end.is_none_or(|end| end > kernel_img.len() as u64)
+            {
+                return Err(KernelLoadError::TruncatedSegment {
+                    vaddr: segment.vaddr,
+                });
+            }
+
+            if !segment.is_aligned(options.page_size) {
+                return Err(KernelLoadError::UnalignedSegment {
+                    vaddr: segment.vaddr,
+                });
+            }
+
+            let in_window = segment.last_vaddr().is_some_and(|last| {
+                options
+                    .va_windows
+                    .iter()
+                    .any(|w| w.contains(&segment.vaddr) && w.contains(&last))
+            });
+            if !in_window {
+                return Err(KernelLoadError::SegmentOutsideWindow {
+                    vaddr: segment.vaddr,
+                });
+            }
+
+            // a page two segments share is backed by one frame, so it has to be the same frame for both
+            if let Some(other) = segments[..index].iter().find(|s| {
+                s.overlaps(segment)
+                    || (s.shares_page(segment, options.page_size)
+                        && s.vaddr.wrapping_sub(s.paddr)
+                            != segment.vaddr.wrapping_sub(segment.paddr))
+            }) {
+                return Err(KernelLoadError::OverlappingSegments {
+                    first: other.vaddr,
+                    second: segment.vaddr,
+                });
+            }
+
+            // W^X. A page shared by a writable and an executable segment ends up both
+            if !options.allow_wx {
+                let flags = segment.page_flags();
+                let wx_with_neighbour = segments[..index].iter().any(|s| {
+                    let other_flags = s.page_flags();
+                    s.shares_page(segment, options.page_size)
+                        && ((flags.writable && other_flags.executable)
+                            || (flags.executable && other_flags.writable))
+                });
+
+                if (flags.writable && flags.executable) || wx_with_neighbour {
+                    return Err(KernelLoadError::WritableAndExecutable {
+                        vaddr: segment.vaddr,
+                    });
+                }
+            }
+        }
+
+        let entry = header.e_entry.wrapping_add(vaddr_bias);
+        if entry % options.entry_alignment() != 0 {
+            return Err(KernelLoadError::UnalignedEntry(entry));
+        }
+
+        let relocations = if is_pie {
+            parse_relocations(kernel_img, &program_headers, ctx, options, vaddr_bias)?
+        } else {
+            Vec::new()
+        };
+
+        if let Some(r) = relocations
+            .iter()
+            .find(|r| !segments.iter().any(|s| s.contains(r.vaddr, 8)))
+        {
+            return Err(KernelLoadError::RelocationOutsideSegments(r.vaddr));
+        }
+
+        let requirements = parse_requirements(kernel_img, &program_headers, options, &segments)?;
+
+        Ok(Self {
+            entry,
+            segments,
+            load_bias: vaddr_bias,
+            relocations,
+            pie_align,
+            requirements,
+        })
+    }
+
+    /// Map each segment, copy its bytes from the img and zero its .bss
+    pub fn load(&self, kernel_img: &[u8], mapper: &mut impl SegmentMapper) {
+        for segment in &self.segments {
+            info!(
+                "Loading segment at vaddr {:#X} (paddr {:#X}), {:#X} bytes in file, {:#X} bytes in memory",
+                segment.vaddr, segment.paddr, segment.file_size, segment.mem_size
+            );
+
+            let dest = mapper.map_segment(segment);
+            load_segment(segment.file_bytes(kernel_img), dest);
+
+            // patch the segment while we still have its memory. Has to happen before anything jumps into it
+            for relocation in self
+                .relocations
+                .iter()
+                .filter(|r| segment.contains(r.vaddr, 8))
+            {
+                let offset = (relocation.vaddr - segment.vaddr) as usize;
+                dest[offset..offset + 8].copy_from_slice(&relocation.value.to_le_bytes());
+            }
+
+            mapper.segment_loaded(segment);
+        }
+    }
+}
+
+/// Pick a base in KASLR_WINDOW for a kernel that takes up `span` bytes from its base. `align` gets raised to KASLR_ALIGN. None if the kernel doesnt fit
+pub fn kaslr_base(random: u64, span: u64, align: u64) -> Option<u64> {
+    let align = align.max(KASLR_ALIGN);
+    let window_size = KASLR_WINDOW.end() - KASLR_WINDOW.start() + 1;
+
+    // every aligned base that still leaves room for the whole kernel
+    let n_bases = window_size.checked_sub(span)? / align + 1;
+    // scale random to 0..n_bases. Unlike random % n_bases, no base is likelier than another
+    let index = ((random as u128 * n_bases as u128) >> 64) as u64;
+
+    Some(KASLR_WINDOW.start() + index * align)
+}
+
+/// Vaddr to load an ET_DYN kernel at. Random if there is a kaslr_seed, otherwise pie_vaddr
+fn pie_vaddr<'a>(
+    loads: impl Iterator<Item = &'a ProgramHeader>,
+    options: &LoadOptions,
+) -> Result<u64, KernelLoadError> {
+    let random = match options.kaslr_seed {
+        Some(r) => r,
+        None => return Ok(options.pie_vaddr),
     };
 
-    // parse and assemble the program headers
-    elf.program_headers = match ProgramHeader::parse(
-        &program_table,
-        header.e_phoff as usize,
-        header.e_phnum as usize,
-        ctx,
-    )
-    .map_err(|_| "parse program headers error")
-    {
-        Ok(r) => r,
-        Err(err) => panic!("Error! {err}"),
+    // link addresses of PIE kernels start around 0, so the end of the last segment is how much VA the kernel needs
+    let mut span = 0;
+    let mut align = 1;
+    for h in loads {
+        let end = h
+            .p_vaddr
+            .checked_add(h.p_memsz)
+            .ok_or(KernelLoadError::SegmentOutsideWindow { vaddr: h.p_vaddr })?;
+        span = span.max(end);
+        align = align.max(h.p_align);
+    }
+
+    let base =
+        kaslr_base(random, span, align).ok_or(KernelLoadError::KernelTooLargeForKaslr { span })?;
+    info!("KASLR: loading kernel at {base:#X}");
+
+    Ok(base)
+}
+
+/// Collect the RELA relocations of an ET_DYN kernel from its PT_DYNAMIC. Each one gets the load bias added to where it patches and what it writes
+fn parse_relocations(
+    kernel_img: &[u8],
+    program_headers: &[ProgramHeader],
+    ctx: Ctx,
+    options: &LoadOptions,
+    bias: u64,
+) -> Result<Vec<Relocation>, KernelLoadError> {
+    let dynamic = match Dynamic::parse(kernel_img, program_headers, ctx) {
+        Ok(Some(d)) => d,
+        // statically linked PIE with nothing to relocate
+        Ok(None) => return Ok(Vec::new()),
+        Err(_) => return Err(KernelLoadError::BadDynamicSection),
     };
+    let info = &dynamic.info;
 
-    // NOTE: addresses should start with 1s for arm64 and x86_64
-    // for riscv, prob similar..?
+    // aarch64, x86_64 and riscv64 all use RELA
+    if info.relsz != 0 {
+        return Err(KernelLoadError::BadDynamicSection);
+    }
 
-    let sections: Vec<&[u8]> = elf
-        .program_headers
-        .iter()
-        .map(|h| {
-            if h.p_type == program_header::PT_INTERP && h.p_filesz != 0 {
-                let offset = h.p_filesz as usize;
-                let count = h.p_offset as usize;
+    // .rela.dyn, and .rela.plt if there is one
+    let mut tables = vec![(info.rela, info.relasz)];
+    if info.pltrelsz != 0 {
+        if info.pltrel != dynamic::DT_RELA {
+            return Err(KernelLoadError::BadDynamicSection);
+        }
+        tables.push((info.jmprel, info.pltrelsz));
+    }
+
+    let mut relocations = Vec::new();
+    for (offset, size) in tables.into_iter().filter(|(_, size)| *size != 0) {
+        // DT_RELA that isnt inside any segment
+        if offset == 0 {
+            return Err(KernelLoadError::BadDynamicSection);
+        }
+
+        let section = RelocSection::parse(kernel_img, offset, size, true, ctx)
+            .map_err(|_| KernelLoadError::BadDynamicSection)?;
 
-                &kernel_img[offset..offset + count]
-            } else {
-                &[0; 1]
+        for r in section.iter() {
+            if r.r_type == 0 {
+                // R_*_NONE
+                continue;
             }
+            if r.r_type != options.relative_relocation() || r.r_sym != 0 {
+                return Err(KernelLoadError::UnsupportedRelocation(r.r_type));
+            }
+
+            relocations.push(Relocation {
+                vaddr: r.r_offset.wrapping_add(bias),
+                value: bias.wrapping_add(r.r_addend.unwrap_or(0) as u64),
+            });
+        }
+    }
+
+    Ok(relocations)
+}
+
+/// Find the kernel's arcboot note in its PT_NOTE segments and check arcboot can give it what it asks for. No note means the defaults
+fn parse_requirements(
+    kernel_img: &[u8],
+    program_headers: &[ProgramHeader],
+    options: &LoadOptions,
+    segments: &[KernelSegment],
+) -> Result<KernelRequirements, KernelLoadError> {
+    let mut requirements = None;
+    for h in program_headers
+        .iter()
+        .filter(|h| h.p_type == program_header::PT_NOTE)
+    {
+        let notes = (h.p_offset as usize)
+            .checked_add(h.p_filesz as usize)
+            .and_then(|end| kernel_img.get(h.p_offset as usize..end))
+            .ok_or(KernelLoadError::BadRequirementsNote(NoteError::Truncated))?;
+
+        if let Some(r) =
+            note::find_requirements(notes).map_err(KernelLoadError::BadRequirementsNote)?
+        {
+            requirements = Some(r);
+            break;
+        }
+    }
+
+    let requirements = requirements.unwrap_or_default().with_defaults();
+
+    if requirements.min_services_version > options.services_version {
+        return Err(KernelLoadError::ServicesTooOld {
+            required: requirements.min_services_version,
+            provided: options.services_version,
+        });
+    }
+    if requirements.entry_el != options.current_el {
+        return Err(KernelLoadError::UnsupportedEntryLevel(
+            requirements.entry_el,
+        ));
+    }
+    let missing = requirements.cpu_features & !options.cpu_features;
+    if missing != 0 {
+        return Err(KernelLoadError::MissingCpuFeatures(missing));
+    }
+
+    // mapped in whole pages of the granule
+    let page_size = options.page_size;
+    let stack_size = requirements.stack_size.div_ceil(page_size) * page_size;
+    let heap_size = requirements.heap_size.div_ceil(page_size) * page_size;
+    let requested = stack_size.saturating_add(heap_size);
+    if requested > options.boot_memory_limit {
+        return Err(KernelLoadError::BootMemoryTooLarge {
+            requested,
+            limit: options.boot_memory_limit,
+        });
+    }
+
+    // both regions get mapped into the same address space as the segments, as (first vaddr, last vaddr)
+    let stack = requirements
+        .stack_top
+        .checked_sub(stack_size)
+        .map(|start| (start, requirements.stack_top - 1));
+    let heap = requirements
+        .heap_start
+        .checked_add(heap_size - 1)
+        .map(|last| (requirements.heap_start, last));
+
+    let mut regions = Vec::new();
+    for (region, vaddr) in [
+        (stack, requirements.stack_top),
+        (heap, requirements.heap_start),
+    ] {
+        let (start, last) = region.ok_or(KernelLoadError::BadBootLayout { vaddr })?;
+
+        let in_window = options
+            .va_windows
+            .iter()
+            .any(|w| w.contains(&start) && w.contains(&last));
+        let overlaps =
+            |(other_start, other_last): &(u64, u64)| start <= *other_last && *other_start <= last;
+        let overlaps_segment = segments.iter().any(|s| {
+            overlaps(&(
+                s.page_start(page_size),
+                s.last_page(page_size) + (page_size - 1),
+            ))
+        });
+
+        if start % page_size != 0 || !in_window || overlaps_segment || regions.iter().any(overlaps)
+        {
+            return Err(KernelLoadError::BadBootLayout { vaddr: start });
+        }
+        regions.push((start, last));
+    }
+
+    Ok(requirements)
+}
+
+/// Takes the kernel's frames out of free_frames, so page tables never land on the kernel image. Call it before setup_kernel_tables
+/// ET_EXEC kernels get the frames at their p_paddr. ET_DYN kernels get frames wherever there are enough free, aligned to their biggest p_align, and their paddrs are moved there
+/// Whole pages of page_size, the granule the segments get mapped with
+pub fn reserve_kernel_segments(
+    free_frames: &mut FrameAllocator,
+    kernel: &mut KernelImage,
+    page_size: u64,
+) -> Result<(), KernelLoadError> {
+    // segments can share a page at their ends, so merge them first or the shared frames get reserved twice
+    let mut ranges: Vec<AddressRange> = kernel
+        .segments
+        .iter()
+        .filter(|segment| segment.mem_size != 0)
+        .map(|segment| {
+            let frame_start = segment.frame_start(page_size);
+            (
+                frame_start,
+                frame_start + segment.n_pages(page_size) * page_size,
+            )
         })
         .collect();
+    ranges.sort_unstable();
+
+    let mut merged: Vec<AddressRange> = Vec::new();
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    if let Some(align) = kernel.pie_align {
+        let align = align.max(page_size);
+        let first = merged.first().map_or(0, |range| range.0) & !(align - 1);
+        let last = merged.last().map_or(first, |range| range.1);
+        let base = free_frames
+            .allocate_contiguous((last - first) / PAGE_SIZE, align)
+            .ok_or(KernelLoadError::NoFramesForKernel { size: last - first })?;
+        for segment in &mut kernel.segments {
+            segment.paddr = segment.paddr - first + base;
+        }
+        return Ok(());
+    }
+
+    for range in merged {
+        if !free_frames.reserve(range) {
+            return Err(KernelLoadError::FramesNotFree(range));
+        }
+    }
+
+    Ok(())
+}
+
+/// Given a kernel ELF img in bytes, parse it, find it frames and load its segments. On error, nothing has been mapped and arcboot can fall back to another kernel
+pub fn load_kernel(
+    kernel_img: &[u8],
+    options: &LoadOptions,
+    frames: &mut FrameAllocator,
+    mapper: &mut impl SegmentMapper,
+) -> Result<KernelImage, KernelLoadError> {
+    // PARSE KERNEL ELF
 
-    // LOAD SECTIONS
+    let mut kernel = KernelImage::parse(kernel_img, options)?;
+    reserve_kernel_segments(frames, &mut kernel, options.page_size)?;
 
-    // .text should be checked for higher half. It should be higher half in most cases, hopefully they linked it right
+    // LOAD SEGMENTS
 
-    elf.program_headers.iter().enumerate().for_each(|(ind, p)| {
-        // segment should usually be 1, 6, 5, 4
-        map_segment(sections[ind], p.p_vaddr);
-    });
+    kernel.load(kernel_img, mapper);
 
-    // set SP = elf.sp?? Nah just set it at 0xffff_ffff_....
-    // assume that paging has been setup properly for TTBR1 and we have switched to it. Beforehand, we should have also mapped 64K worth of frames down from 0xffff_...
-    set_stack(0xFFFF_FFFF_FFFF_FFFF);
+    Ok(kernel)
+}
 
+/// Pass off execution to a loaded kernel, through switch. The kernel gets a pointer to its ArcServices, identity mapped
+/// memory_map is the final one, see FrameAllocator::memory_map. direct_map is what setup_kernel_tables mapped
+/// boot_info has the tags for the boot entry, like its command line. It is copied in next to ArcServices, with a TAG_MODULE for each module
+pub fn enter_kernel(
+    kernel: &KernelImage,
+    memory_map: MemoryMap,
+    direct_map: &DirectMap,
+    mut boot_info: BootInfoWriter,
+    modules: &[LoadedModule],
+    mut switch: impl KernelSwitch,
+) -> ! {
     // Pass ArcServices to the kernel
-    // * stack allocated, will disappear normally, so maybe ensure this function doesnt return too early or not at all? Also will be in the bootloader's stack, unless we reset the sp to the kernel's beforehand
     let mut arcservices = make_default();
-    let mut point_arc = &mut arcservices as *mut DefaultServices;
+    arcservices.set_kaslr_slide(kernel.load_bias);
+    arcservices.set_paging(switch.kernel_tables());
+    arcservices.set_memory_map(memory_map);
+    arcservices.set_direct_map(direct_map.hhdm_offset, direct_map.mmio_offset);
+    if let Some((vector_table, handlers)) = switch.vector_table() {
+        arcservices.set_interrupts(vector_table, handlers);
+    }
+    for module in modules {
+        arcservices.add_module(module.range, module.vaddr, &module.name, &module.args);
+        boot_info.module(module.range.0, module.range.1, &module.name);
+    }
+    arcservices.set_boot_info(boot_info.finish());
+
+    // leaked so arcboot never reuses it, the kernel owns it from here. Identity mapped, so the kernel sees it where we do
+    let handoff_size = arcservices.handoff_size();
+    let handoff = vec![0u64; handoff_size.div_ceil(8)].leak();
+    let handoff_vaddr = handoff.as_ptr() as u64;
+    let arcservices = arcservices
+        .write(handoff, handoff_vaddr)
+        .expect("handoff buffer is sized from handoff_size");
+    switch.identity_map((handoff_vaddr, handoff_vaddr + handoff_size as u64));
+
+    // the stack the kernel asked for was mapped down from stack_top by setup_kernel_tables
+    // never returns, the kernel owns the machine now
+    unsafe {
+        switch.switch(
+            kernel.entry,
+            kernel.requirements.stack_top,
+            arcservices as u64,
+        )
+    }
+}
+
+// ---------------
+// BOOT MODULES
+// ---------------
+
+/// A module copied into frames of its own, see load_modules
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadedModule {
+    /// Paddr of the first byte and after the last one
+    pub range: AddressRange,
+    /// Where the kernel sees it, through the direct map
+    pub vaddr: u64,
+    /// Path it was loaded from
+    pub name: String,
+    pub args: String,
+}
+
+/// Not enough free RAM for a module
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleLoadError {
+    pub path: String,
+    pub size: u64,
+}
+
+impl fmt::Display for ModuleLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no room for module {} ({} bytes)", self.path, self.size)
+    }
+}
+
+/// Put each module's bytes in BootModule frames, aligned to align (the kernel's granule, so it can map modules on their own)
+/// write(paddr, size, bytes) copies bytes to paddr and zeroes the rest of size, which is whole pages
+pub fn load_modules(
+    modules: &[(BootModule, impl AsRef<[u8]>)],
+    frames: &mut FrameAllocator,
+    direct_map: &DirectMap,
+    align: u64,
+    mut write: impl FnMut(u64, u64, &[u8]),
+) -> Result<Vec<LoadedModule>, ModuleLoadError> {
+    let mut loaded = Vec::with_capacity(modules.len());
+
+    for (module, bytes) in modules {
+        let bytes = bytes.as_ref();
+        let size = bytes.len() as u64;
+        // even an empty module gets a page, so it has an address of its own
+        let n_frames = size.div_ceil(align).max(1) * align / PAGE_SIZE;
+        let paddr = frames
+            .allocate_as(n_frames, align, MemoryRegionType::BootModule)
+            .ok_or_else(|| ModuleLoadError {
+                path: module.path.clone(),
+                size,
+            })?;
+
+        write(paddr, n_frames * PAGE_SIZE, bytes);
+        loaded.push(LoadedModule {
+            range: (paddr, paddr + size),
+            vaddr: direct_map.hhdm_offset + paddr,
+            name: module.path.clone(),
+            args: module.args.clone(),
+        });
+    }
+
+    Ok(loaded)
+}
+
+// --------------
+// TEST
+// --------------
+
+/// Maps segments into a buffer that stands in for physical memory starting at `base`. Keeps the vaddr of every segment it was told is loaded
+#[cfg(test)]
+struct TestMemory {
+    base: u64,
+    ram: Vec<u8>,
+    loaded: Vec<u64>,
+}
+
+#[cfg(test)]
+impl SegmentMapper for TestMemory {
+    fn map_segment(&mut self, segment: &KernelSegment) -> &mut [u8] {
+        let start = (segment.paddr - self.base) as usize;
+        &mut self.ram[start..start + segment.mem_size as usize]
+    }
+
+    fn segment_loaded(&mut self, segment: &KernelSegment) {
+        self.loaded.push(segment.vaddr);
+    }
+}
+
+/// Builds little endian ELF64 imgs for the loader tests
+#[cfg(test)]
+struct TestElf {
+    e_type: u16,
+    machine: u16,
+    entry: u64,
+    /// Program headers and the bytes they point at. p_offset and p_filesz are filled in by build()
+    segments: Vec<(ProgramHeader, Vec<u8>)>,
+}
+
+#[cfg(test)]
+impl TestElf {
+    fn new(entry: u64) -> Self {
+        Self {
+            e_type: header::ET_EXEC,
+            machine: header::EM_AARCH64,
+            entry,
+            segments: Vec::new(),
+        }
+    }
+
+    fn load(self, vaddr: u64, paddr: u64, bytes: &[u8], mem_size: u64, flags: u32) -> Self {
+        let header = ProgramHeader {
+            p_type: program_header::PT_LOAD,
+            p_flags: flags,
+            p_vaddr: vaddr,
+            p_paddr: paddr,
+            p_memsz: mem_size,
+            p_align: 1,
+            ..Default::default()
+        };
+        self.segment(header, bytes)
+    }
+
+    /// Add a PT_NOTE holding an arcboot note
+    fn note(self, requirements: KernelRequirements) -> Self {
+        let note = note::ArcbootNote::new(requirements);
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                &note as *const _ as *const u8,
+                core::mem::size_of::<note::ArcbootNote>(),
+            )
+        };
+        let header = ProgramHeader {
+            p_type: program_header::PT_NOTE,
+            p_flags: program_header::PF_R,
+            p_align: 4,
+            ..Default::default()
+        };
+        self.segment(header, bytes)
+    }
 
-    // set a0 = *mut arcservices
-    // unsafe { asm!("mov a0 {p}", p = inout(reg) point_arc) }
+    fn segment(mut self, header: ProgramHeader, bytes: &[u8]) -> Self {
+        self.segments.push((header, bytes.to_vec()));
+        self
+    }
 
-    let arc_entry = elf.header.e_entry as *const ();
-    // transmute that addr to a function pointer
-    let arc_entry_function = unsafe { transmute::<*const (), fn(ArcServices)>(arc_entry) };
+    fn build(&self) -> Vec<u8> {
+        let phoff = ELF64_HDR_SIZE as u64;
 
-    // call (dont return, unless mapped bootloader's code section to kernel's TTBR1)
-    arc_entry_function(arcservices);
+        let mut img = Vec::new();
+        img.extend_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        img.extend_from_slice(&self.e_type.to_le_bytes());
+        img.extend_from_slice(&self.machine.to_le_bytes());
+        img.extend_from_slice(&1u32.to_le_bytes());
+        img.extend_from_slice(&self.entry.to_le_bytes());
+        img.extend_from_slice(&phoff.to_le_bytes());
+        img.extend_from_slice(&0u64.to_le_bytes());
+        img.extend_from_slice(&0u32.to_le_bytes());
+        img.extend_from_slice(&(ELF64_HDR_SIZE as u16).to_le_bytes());
+        img.extend_from_slice(&(ELF64_PHDR_SIZE as u16).to_le_bytes());
+        img.extend_from_slice(&(self.segments.len() as u16).to_le_bytes());
+        img.extend_from_slice(&[0; 6]);
 
-    // jump to header.entry
-    // would be "j" on riscv/x86
-    // * prob use an instruction barrier here?
-    // unsafe { asm!("br {arc_entry}", arc_entry = inout(reg) elf.header.e_entry) }
+        // segment bytes go after the program headers, at an offset congruent to vaddr modulo p_align
+        let mut data = Vec::new();
+        let mut headers = Vec::new();
+        let data_start = (ELF64_HDR_SIZE + ELF64_PHDR_SIZE * self.segments.len()) as u64;
+        for (header, bytes) in &self.segments {
+            let align = header.p_align.max(1);
+            while (data_start + data.len() as u64) % align != header.p_vaddr % align {
+                data.push(0);
+            }
+
+            let mut header = header.clone();
+            header.p_offset = data_start + data.len() as u64;
+            header.p_filesz = bytes.len() as u64;
+            headers.push(header);
+            data.extend_from_slice(bytes);
+        }
+
+        for header in headers {
+            img.extend_from_slice(&header.p_type.to_le_bytes());
+            img.extend_from_slice(&header.p_flags.to_le_bytes());
+            img.extend_from_slice(&header.p_offset.to_le_bytes());
+            img.extend_from_slice(&header.p_vaddr.to_le_bytes());
+            img.extend_from_slice(&header.p_paddr.to_le_bytes());
+            img.extend_from_slice(&header.p_filesz.to_le_bytes());
+            img.extend_from_slice(&header.p_memsz.to_le_bytes());
+            img.extend_from_slice(&header.p_align.to_le_bytes());
+        }
+        img.extend_from_slice(&data);
+
+        img
+    }
+}
 
-    loop {}
+/// 1 GiB of free RAM at 0x4000_0000
+#[cfg(test)]
+fn test_ram() -> MemoryMap {
+    use arcboot_api::MemoryRegion;
+
+    MemoryMap::new(vec![MemoryRegion::new(
+        MemoryRegionType::Standard,
+        (0x4000_0000, 0x8000_0000),
+    )])
+}
+
+#[cfg(test)]
+fn aarch64_options() -> LoadOptions {
+    LoadOptions::new(header::EM_AARCH64, vec![KERNEL_VA_WINDOW])
 }
 
+#[cfg(test)]
+const RX: u32 = program_header::PF_R | program_header::PF_X;
+#[cfg(test)]
+const RW: u32 = program_header::PF_R | program_header::PF_W;
+
 #[test]
 fn test_load_kernel() {
-    // Load an actual file into a vector of bytes
-    let kernel_img = [0 as u8; 64];
-    load_kernel(&kernel_img)
+    let text = [0xAA; 16];
+    let data = [0xBB; 8];
+    let kernel_img = TestElf::new(0xFFFF_0000_0000_0000)
+        .load(0xFFFF_0000_0000_0000, 0x4008_0000, &text, 16, RX)
+        .load(0xFFFF_0000_0000_1000, 0x4008_1000, &data, 32, RW)
+        .build();
+
+    let mut memory = TestMemory {
+        base: 0x4008_0000,
+        ram: vec![0xCC; 0x2000],
+        loaded: Vec::new(),
+    };
+    let mut frames = FrameAllocator::new(&test_ram());
+    let kernel = load_kernel(&kernel_img, &aarch64_options(), &mut frames, &mut memory).unwrap();
+
+    assert_eq!(kernel.entry, 0xFFFF_0000_0000_0000);
+    assert_eq!(kernel.segments.len(), 2);
+    assert_eq!(kernel.segments[1].n_pages(PAGE_SIZE), 1);
+    assert_eq!(
+        memory.loaded,
+        [0xFFFF_0000_0000_0000, 0xFFFF_0000_0000_1000]
+    );
+
+    assert_eq!(memory.ram[..16], text);
+    assert_eq!(memory.ram[0x1000..0x1008], data);
+    // .bss zeroed, the rest of the frame untouched
+    assert_eq!(memory.ram[0x1008..0x1020], [0; 24]);
+    assert_eq!(memory.ram[0x1020], 0xCC);
+}
+
+#[test]
+fn test_reject_bad_header() {
+    let options = aarch64_options();
+    let kernel_img = TestElf::new(0xFFFF_0000_0000_0000)
+        .load(0xFFFF_0000_0000_0000, 0x4008_0000, &[0; 4], 4, RX)
+        .build();
+
+    assert_eq!(
+        KernelImage::parse(&[0; 64], &options).unwrap_err(),
+        KernelLoadError::BadMagic
+    );
+    assert_eq!(
+        KernelImage::parse(&kernel_img[..32], &options).unwrap_err(),
+        KernelLoadError::TruncatedHeader
+    );
+
+    let mut big_endian = kernel_img.clone();
+    big_endian[header::EI_DATA] = header::ELFDATA2MSB;
+    assert_eq!(
+        KernelImage::parse(&big_endian, &options).unwrap_err(),
+        KernelLoadError::UnsupportedFormat
+    );
+
+    let x86_options = LoadOptions::new(header::EM_X86_64, vec![KERNEL_VA_WINDOW]);
+    assert_eq!(
+        KernelImage::parse(&kernel_img, &x86_options).unwrap_err(),
+        KernelLoadError::WrongMachine {
+            expected: header::EM_X86_64,
+            found: header::EM_AARCH64
+        }
+    );
+}
+
+#[test]
+fn test_reject_truncated() {
+    let options = aarch64_options();
+    let kernel_img = TestElf::new(0xFFFF_0000_0000_0000)
+        .load(0xFFFF_0000_0000_0000, 0x4008_0000, &[0; 4], 4, RX)
+        .build();
+
+    // cut off halfway through the program header table
+    assert_eq!(
+        KernelImage::parse(&kernel_img[..ELF64_HDR_SIZE + 20], &options).unwrap_err(),
+        KernelLoadError::TruncatedProgramHeaders
+    );
+    // cut off in the segment's bytes
+    assert_eq!(
+        KernelImage::parse(&kernel_img[..kernel_img.len() - 1], &options).unwrap_err(),
+        KernelLoadError::TruncatedSegment {
+            vaddr: 0xFFFF_0000_0000_0000
+        }
+    );
+
+    let no_segments = TestElf::new(0xFFFF_0000_0000_0000).build();
+    assert_eq!(
+        KernelImage::parse(&no_segments, &options).unwrap_err(),
+        KernelLoadError::NoLoadableSegments
+    );
+}
+
+#[test]
+fn test_reject_bad_segments() {
+    let options = aarch64_options();
+
+    let overlapping = TestElf::new(0xFFFF_0000_0000_0000)
+        .load(0xFFFF_0000_0000_0000, 0x4008_0000, &[0; 16], 0x2000, RX)
+        .load(0xFFFF_0000_0000_1000, 0x4008_1000, &[0; 16], 16, RW)
+        .build();
+    assert_eq!(
+        KernelImage::parse(&overlapping, &options).unwrap_err(),
+        KernelLoadError::OverlappingSegments {
+            first: 0xFFFF_0000_0000_0000,
+            second: 0xFFFF_0000_0000_1000
+        }
+    );
+
+    let lower_half = TestElf::new(0x4008_0000)
+        .load(0x4008_0000, 0x4008_0000, &[0; 16], 16, RX)
+        .build();
+    assert_eq!(
+        KernelImage::parse(&lower_half, &options).unwrap_err(),
+        KernelLoadError::SegmentOutsideWindow { vaddr: 0x4008_0000 }
+    );
+
+    let unaligned_entry = TestElf::new(0xFFFF_0000_0000_0002)
+        .load(0xFFFF_0000_0000_0000, 0x4008_0000, &[0; 16], 16, RX)
+        .build();
+    assert_eq!(
+        KernelImage::parse(&unaligned_entry, &options).unwrap_err(),
+        KernelLoadError::UnalignedEntry(0xFFFF_0000_0000_0002)
+    );
+
+    let misaligned_paddr = ProgramHeader {
+        p_type: program_header::PT_LOAD,
+        p_flags: RX,
+        p_vaddr: 0xFFFF_0000_0000_0000,
+        p_paddr: 0x4008_0800,
+        p_memsz: 16,
+        p_align: 0x1000,
+        ..Default::default()
+    };
+    let unaligned_segment = TestElf::new(0xFFFF_0000_0000_0000)
+        .segment(misaligned_paddr, &[0; 16])
+        .build();
+    assert_eq!(
+        KernelImage::parse(&unaligned_segment, &options).unwrap_err(),
+        KernelLoadError::UnalignedSegment {
+            vaddr: 0xFFFF_0000_0000_0000
+        }
+    );
+
+    let wrapping = TestElf::new(0xFFFF_FFFF_FFFF_F000)
+        .load(0xFFFF_FFFF_FFFF_F000, 0x4008_0000, &[0; 16], 0x2000, RX)
+        .build();
+    assert_eq!(
+        KernelImage::parse(&wrapping, &options).unwrap_err(),
+        KernelLoadError::SegmentOutsideWindow {
+            vaddr: 0xFFFF_FFFF_FFFF_F000
+        }
+    );
+
+    // ends on the last byte of the address space
+    let top = KernelSegment {
+        vaddr: 0xFFFF_FFFF_FFFF_E800,
+        paddr: 0x4008_0800,
+        offset: 0,
+        file_size: 0,
+        mem_size: 0x1800,
+        align: 1,
+        flags: RW,
+    };
+    assert_eq!(top.n_pages(PAGE_SIZE), 2);
+    assert_eq!(top.last_page(PAGE_SIZE), 0xFFFF_FFFF_FFFF_F000);
+}
+
+#[test]
+fn test_load_pie_kernel() {
+    let mut image = vec![0u8; 0x100];
+    let mut put = |at: usize, value: u64| image[at..at + 8].copy_from_slice(&value.to_le_bytes());
+
+    // .rela.dyn at 0x40: two pointers at 0x0 and 0x8 that point back into the kernel
+    let r_info = reloc::R_AARCH64_RELATIVE as u64;
+    put(0x40, 0x0);
+    put(0x48, r_info);
+    put(0x50, 0x20);
+    put(0x58, 0x8);
+    put(0x60, r_info);
+    put(0x68, 0x30);
+
+    // .dynamic at 0x80
+    let dynamic_table = [
+        (dynamic::DT_RELA, 0x40),
+        (dynamic::DT_RELASZ, 48),
+        (dynamic::DT_RELAENT, 24),
+        (dynamic::DT_NULL, 0),
+    ];
+    for (i, (tag, value)) in dynamic_table.iter().enumerate() {
+        put(0x80 + i * 16, *tag);
+        put(0x88 + i * 16, *value);
+    }
+
+    let dynamic_header = ProgramHeader {
+        p_type: program_header::PT_DYNAMIC,
+        p_flags: RW,
+        p_vaddr: 0x80,
+        p_paddr: 0x80,
+        p_memsz: 0x40,
+        ..Default::default()
+    };
+    let mut elf = TestElf::new(0x10)
+        .load(0, 0, &image, 0x100, RX | program_header::PF_W)
+        .segment(dynamic_header.clone(), &image[0x80..0xC0]);
+    elf.e_type = header::ET_DYN;
+    let kernel_img = elf.build();
+
+    // one RWX segment for the whole test kernel
+    let mut options = aarch64_options();
+    options.allow_wx = true;
+    options.pie_vaddr = 0xFFFF_0000_0020_0000;
+
+    // the first free frame goes to the kernel
+    let mut frames = FrameAllocator::new(&test_ram());
+    frames.allocate_contiguous(0x200, PAGE_SIZE);
+    let mut memory = TestMemory {
+        base: 0x4020_0000,
+        ram: vec![0; 0x100],
+        loaded: Vec::new(),
+    };
+    let kernel = load_kernel(&kernel_img, &options, &mut frames, &mut memory).unwrap();
+
+    assert_eq!(kernel.entry, 0xFFFF_0000_0020_0010);
+    assert_eq!(kernel.load_bias, 0xFFFF_0000_0020_0000);
+    assert_eq!(kernel.segments[0].paddr, 0x4020_0000);
+    assert_eq!(memory.ram[..8], 0xFFFF_0000_0020_0020u64.to_le_bytes());
+    assert_eq!(memory.ram[8..16], 0xFFFF_0000_0020_0030u64.to_le_bytes());
+
+    // x86 relocations in an aarch64 kernel
+    let mut x86_reloc = image.clone();
+    x86_reloc[0x48..0x50].copy_from_slice(&(reloc::R_X86_64_RELATIVE as u64).to_le_bytes());
+    let mut elf = TestElf::new(0x10)
+        .load(0, 0, &x86_reloc, 0x100, RX | program_header::PF_W)
+        .segment(dynamic_header, &image[0x80..0xC0]);
+    elf.e_type = header::ET_DYN;
+    assert_eq!(
+        KernelImage::parse(&elf.build(), &options).unwrap_err(),
+        KernelLoadError::UnsupportedRelocation(reloc::R_X86_64_RELATIVE)
+    );
+}
+
+#[test]
+fn test_reserve_kernel_segments() {
+    use crate::memory::map::{
+        normalise, FirmwareDescriptor, EFI_CONVENTIONAL_MEMORY, EFI_MEMORY_WB,
+    };
+
+    // 256 MiB of RAM, with arcboot's 64 MiB heap at the start carved out like uefi.rs does
+    let heap = (0x4000_0000, 0x4400_0000);
+    let memory_map = normalise(
+        [FirmwareDescriptor::new(
+            EFI_CONVENTIONAL_MEMORY,
+            0x4000_0000,
+            0x1_0000,
+            EFI_MEMORY_WB,
+        )],
+        &[heap],
+        PAGE_SIZE,
+    );
+
+    // relocatable, with .data sharing .text's last 64K page
+    let mut elf = TestElf::new(0x10)
+        .load(0, 0, &[0; 0x20], 0x1_1000, RX)
+        .load(0x1_8000, 0x1_8000, &[0; 0x20], 0x1000, RW);
+    elf.segments[0].0.p_align = 0x1_0000;
+    elf.e_type = header::ET_DYN;
+    let mut options = aarch64_options();
+    options.allow_wx = true;
+    options.page_size = 0x1_0000;
+    let mut kernel = KernelImage::parse(&elf.build(), &options).unwrap();
+
+    let mut frames = FrameAllocator::new(&memory_map);
+    reserve_kernel_segments(&mut frames, &mut kernel, options.page_size).unwrap();
+    assert_eq!(kernel.segments[0].paddr, heap.1);
+    assert_eq!(kernel.segments[1].paddr, heap.1 + 0x1_8000);
+    assert_eq!(frames.free_ranges()[0], (heap.1 + 0x2_0000, 0x5000_0000));
+
+    // linked into the heap
+    let exec = TestElf::new(0xFFFF_0000_0000_0000)
+        .load(0xFFFF_0000_0000_0000, 0x4100_0000, &[0; 16], 16, RX)
+        .build();
+    let mut kernel = KernelImage::parse(&exec, &aarch64_options()).unwrap();
+    let mut frames = FrameAllocator::new(&memory_map);
+    assert_eq!(
+        reserve_kernel_segments(&mut frames, &mut kernel, PAGE_SIZE).unwrap_err(),
+        KernelLoadError::FramesNotFree((0x4100_0000, 0x4100_1000))
+    );
+
+    // no room left
+    kernel.pie_align = Some(PAGE_SIZE);
+    kernel.segments[0].mem_size = 0x1000_0000;
+    assert_eq!(
+        reserve_kernel_segments(&mut frames, &mut kernel, PAGE_SIZE).unwrap_err(),
+        KernelLoadError::NoFramesForKernel { size: 0x1000_0000 }
+    );
+}
+
+#[test]
+fn test_kaslr() {
+    // kernel needs 3 MiB, so the last 2 MiB slot is out
+    let n_bases = (KASLR_WINDOW.end() - KASLR_WINDOW.start() + 1) / KASLR_ALIGN - 1;
+    assert_eq!(
+        kaslr_base(0, 0x30_0000, 0x1000),
+        Some(*KASLR_WINDOW.start())
+    );
+    assert_eq!(
+        kaslr_base(u64::MAX, 0x30_0000, 0x1000),
+        Some(KASLR_WINDOW.start() + (n_bases - 1) * KASLR_ALIGN)
+    );
+    assert_eq!(
+        kaslr_base(1 << 63, 0x30_0000, 0x1000),
+        Some(KASLR_WINDOW.start() + n_bases / 2 * KASLR_ALIGN)
+    );
+    // bigger alignments are kept
+    let base = kaslr_base(1 << 63, 0x1000, 0x4000_0000).unwrap();
+    assert!(base > *KASLR_WINDOW.start());
+    assert_eq!((base - KASLR_WINDOW.start()) % 0x4000_0000, 0);
+    assert_eq!(kaslr_base(1, u64::MAX, 0x1000), None);
+
+    let mut elf = TestElf::new(0x10).load(0, 0, &[0; 0x20], 0x20, RX);
+    elf.e_type = header::ET_DYN;
+    let kernel_img = elf.build();
+
+    let mut options = aarch64_options();
+    options.kaslr_seed = Some(0x1234_5678_9ABC);
+    let kernel = KernelImage::parse(&kernel_img, &options).unwrap();
+
+    assert!(KASLR_WINDOW.contains(&kernel.load_bias));
+    assert_eq!(kernel.load_bias % KASLR_ALIGN, 0);
+    assert_eq!(kernel.entry, kernel.load_bias + 0x10);
+    // frames are picked by reserve_kernel_segments, not here
+    assert_eq!(kernel.segments[0].paddr, 0);
+    assert_eq!(kernel.pie_align, Some(1));
+
+    let mut huge = TestElf::new(0x10).load(0, 0, &[0; 0x20], 1 << 62, RX);
+    huge.e_type = header::ET_DYN;
+    assert_eq!(
+        KernelImage::parse(&huge.build(), &options).unwrap_err(),
+        KernelLoadError::KernelTooLargeForKaslr { span: 1 << 62 }
+    );
+}
+
+#[test]
+fn test_write_xor_execute() {
+    let rwx = TestElf::new(0xFFFF_0000_0000_0000)
+        .load(0xFFFF_0000_0000_0000, 0x4008_0000, &[0; 16], 16, RX | RW)
+        .build();
+    // .text and .data sharing a page
+    let shared_page = TestElf::new(0xFFFF_0000_0000_0000)
+        .load(0xFFFF_0000_0000_0000, 0x4008_0000, &[0; 16], 16, RX)
+        .load(0xFFFF_0000_0000_0010, 0x4008_0010, &[0; 16], 16, RW)
+        .build();
+
+    let mut options = aarch64_options();
+    for kernel_img in [&rwx, &shared_page] {
+        assert!(matches!(
+            KernelImage::parse(kernel_img, &options),
+            Err(KernelLoadError::WritableAndExecutable { .. })
+        ));
+    }
+
+    options.allow_wx = true;
+    let kernel = KernelImage::parse(&shared_page, &options).unwrap();
+    assert_eq!(kernel.segments[0].page_flags(), PageFlags::KERNEL_CODE);
+    assert_eq!(kernel.segments[1].page_flags(), PageFlags::KERNEL_DATA);
+}
+
+#[test]
+fn test_granule_alignment() {
+    use arcboot_api::note::DEFAULT_BOOT_HEAP_START;
+
+    let base = 0xFFFF_0000_0000_0000;
+    let mut options = aarch64_options();
+    options.page_size = 0x1_0000;
+
+    // 4K aligned, but not 64K aligned
+    let kernel = TestElf::new(base)
+        .load(base + 0x1000, 0x4100_1000, &[1, 2, 3, 4], 0x1000, RX)
+        .build();
+    let image = KernelImage::parse(&kernel, &options).unwrap();
+    assert_eq!(image.segments[0].page_start(options.page_size), base);
+    assert_eq!(image.segments[0].n_pages(options.page_size), 1);
+
+    // vaddr and paddr at different offsets into a 64K page
+    let skewed = TestElf::new(base)
+        .load(base + 0x1000, 0x4100_2000, &[1, 2, 3, 4], 0x1000, RX)
+        .build();
+    assert_eq!(
+        KernelImage::parse(&skewed, &options).unwrap_err(),
+        KernelLoadError::UnalignedSegment {
+            vaddr: base + 0x1000
+        }
+    );
+
+    // .text and .data sharing a 64K page have to share its frame too
+    let split_page = TestElf::new(base)
+        .load(base, 0x4100_0000, &[1, 2, 3, 4], 0x1000, RX)
+        .load(base + 0x8000, 0x4200_8000, &[1, 2, 3, 4], 0x1000, RW)
+        .build();
+    options.allow_wx = true;
+    assert!(matches!(
+        KernelImage::parse(&split_page, &options),
+        Err(KernelLoadError::OverlappingSegments { .. })
+    ));
+    options.allow_wx = false;
+
+    // a 4K aligned stack top or heap
+    let with_note = |requirements| {
+        TestElf::new(base)
+            .load(base, 0x4100_0000, &[1, 2, 3, 4], 0x1000, RX)
+            .note(requirements)
+            .build()
+    };
+    let stack_top = 0xFFFF_0000_1000_1000;
+    assert_eq!(
+        KernelImage::parse(
+            &with_note(KernelRequirements::new(0, 0, 0, stack_top, 0, 0, 0)),
+            &options
+        )
+        .unwrap_err(),
+        KernelLoadError::BadBootLayout {
+            vaddr: stack_top - 0x1_0000
+        }
+    );
+    let heap_start = DEFAULT_BOOT_HEAP_START + 0x1000;
+    assert_eq!(
+        KernelImage::parse(
+            &with_note(KernelRequirements::new(0, 0, 0, 0, heap_start, 0, 0)),
+            &options
+        )
+        .unwrap_err(),
+        KernelLoadError::BadBootLayout { vaddr: heap_start }
+    );
+
+    // the defaults are fine with every granule
+    let image = KernelImage::parse(&kernel, &options).unwrap();
+    assert_eq!(image.requirements, KernelRequirements::default());
+}
+
+#[test]
+fn test_map_shared_pages() {
+    use crate::memory::mmu::{
+        arm64::{Granule, PageTable, TranslationMode, VaRange},
+        TestRam,
+    };
+    use arcboot_api::{MemoryMap, MemoryRegion, MemoryRegionType};
+
+    let page_size = 0x1_0000;
+    let ram = (0x4000_0000, 0x4000_0000 + 16 * page_size);
+    let mut frames = FrameAllocator::new(&MemoryMap::new(vec![MemoryRegion::new(
+        MemoryRegionType::Standard,
+        ram,
+    )]));
+    let mode = TranslationMode::new(Granule::K64, 48).unwrap();
+    let mut tables = PageTable::new(
+        TestRam::new(ram.0, (ram.1 - ram.0) as usize),
+        VaRange::Upper,
+        mode,
+        &mut frames,
+    )
+    .unwrap();
+
+    // .text runs into the page .data starts in
+    let base = 0xFFFF_0000_0000_0000;
+    let kernel = TestElf::new(base)
+        .load(base, 0x4100_0000, &[1, 2, 3, 4], 0x1_1000, RX)
+        .load(base + 0x1_8000, 0x4101_8000, &[1, 2, 3, 4], 0x1_0000, RW)
+        .build();
+    let mut options = aarch64_options();
+    options.page_size = page_size;
+    options.allow_wx = true;
+    let image = KernelImage::parse(&kernel, &options).unwrap();
+
+    for segment in &image.segments {
+        map_segment_pages(&mut tables, segment, &mut frames).unwrap();
+    }
+
+    assert_eq!(
+        tables.translate(base),
+        Some((0x4100_0000, PageFlags::KERNEL_CODE))
+    );
+    assert_eq!(
+        tables.translate(base + 0x1_0000),
+        Some((
+            0x4101_0000,
+            PageFlags::KERNEL_CODE.union(PageFlags::KERNEL_DATA)
+        ))
+    );
+    assert_eq!(
+        tables.translate(base + 0x2_0000),
+        Some((0x4102_0000, PageFlags::KERNEL_DATA))
+    );
+    assert_eq!(tables.translate(base + 0x3_0000), None);
+}
+
+#[test]
+fn test_kernel_requirements() {
+    use arcboot_api::note::{CPU_FEATURE_FP, CPU_FEATURE_SVE, DEFAULT_BOOT_HEAP_START};
+
+    let base = 0xFFFF_0000_0000_0000;
+    let kernel = |requirements| {
+        TestElf::new(base)
+            .load(base, 0x4100_0000, &[1, 2, 3, 4], 0x1000, RX)
+            .note(requirements)
+            .build()
+    };
+    let mut options = aarch64_options();
+    options.cpu_features = CPU_FEATURE_FP;
+
+    // no note, arcboot's defaults
+    let no_note = TestElf::new(base)
+        .load(base, 0x4100_0000, &[1, 2, 3, 4], 0x1000, RX)
+        .build();
+    let image = KernelImage::parse(&no_note, &options).unwrap();
+    assert_eq!(image.requirements, KernelRequirements::default());
+
+    // stack size and layout come from the note, the rest is defaulted
+    let requirements =
+        KernelRequirements::new(1, 0x8000, 0, 0xFFFF_0000_1000_0000, 0, 0, CPU_FEATURE_FP);
+    let image = KernelImage::parse(&kernel(requirements), &options).unwrap();
+    assert_eq!(image.requirements.stack_size, 0x8000);
+    assert_eq!(image.requirements.stack_top, 0xFFFF_0000_1000_0000);
+    assert_eq!(image.requirements.heap_start, DEFAULT_BOOT_HEAP_START);
+    assert_eq!(image.requirements.entry_el, 1);
+
+    let reject = |requirements, error| {
+        assert_eq!(
+            KernelImage::parse(&kernel(requirements), &options).unwrap_err(),
+            error
+        );
+    };
+
+    reject(
+        KernelRequirements::new(ARC_SERVICES_VERSION + 1, 0, 0, 0, 0, 0, 0),
+        KernelLoadError::ServicesTooOld {
+            required: ARC_SERVICES_VERSION + 1,
+            provided: ARC_SERVICES_VERSION,
+        },
+    );
+    reject(
+        KernelRequirements::new(0, 0, 0, 0, 0, 2, 0),
+        KernelLoadError::UnsupportedEntryLevel(2),
+    );
+    reject(
+        KernelRequirements::new(0, 0, 0, 0, 0, 0, CPU_FEATURE_FP | CPU_FEATURE_SVE),
+        KernelLoadError::MissingCpuFeatures(CPU_FEATURE_SVE),
+    );
+    reject(
+        KernelRequirements::new(0, DEFAULT_BOOT_MEMORY_LIMIT, 0x1000, 0, 0, 0, 0),
+        KernelLoadError::BootMemoryTooLarge {
+            requested: DEFAULT_BOOT_MEMORY_LIMIT + 0x1000,
+            limit: DEFAULT_BOOT_MEMORY_LIMIT,
+        },
+    );
+
+    // heap on top of the kernel, stack in the lower half, unaligned heap
+    reject(
+        KernelRequirements::new(0, 0, 0, 0, base, 0, 0),
+        KernelLoadError::BadBootLayout { vaddr: base },
+    );
+    reject(
+        KernelRequirements::new(0, 0x1000, 0, 0x1000_0000, 0, 0, 0),
+        KernelLoadError::BadBootLayout { vaddr: 0xFFF_F000 },
+    );
+    reject(
+        KernelRequirements::new(0, 0, 0, 0, base + 0x10_0010, 0, 0),
+        KernelLoadError::BadBootLayout {
+            vaddr: base + 0x10_0010,
+        },
+    );
+
+    // notes from a newer arcboot
+    let mut newer = requirements;
+    newer.note_version += 1;
+    reject(
+        newer,
+        KernelLoadError::BadRequirementsNote(NoteError::UnsupportedVersion(newer.note_version)),
+    );
+}
+
+/// Records what enter_kernel asked for, then panics with it instead of jumping
+#[cfg(test)]
+#[derive(Debug, Default)]
+struct TestSwitch {
+    vectors: Option<(u64, u64)>,
+    ttbr1: u64,
+    identity: Vec<AddressRange>,
+    entry: u64,
+    stack_top: u64,
+    arg: u64,
+}
+
+#[cfg(test)]
+impl KernelSwitch for TestSwitch {
+    fn vector_table(&self) -> Option<(u64, u64)> {
+        self.vectors
+    }
+
+    fn kernel_tables(&self) -> u64 {
+        self.ttbr1
+    }
+
+    fn identity_map(&mut self, range: AddressRange) {
+        self.identity.push(range);
+    }
+
+    unsafe fn switch(mut self, entry: u64, stack_top: u64, arg: u64) -> ! {
+        self.entry = entry;
+        self.stack_top = stack_top;
+        self.arg = arg;
+        std::panic::panic_any(self)
+    }
+}
+
+#[test]
+fn test_enter_kernel() {
+    use arcboot_api::{ArcServices, MemoryRegion};
+
+    let kernel_img = TestElf::new(0xFFFF_0000_0000_0000)
+        .load(0xFFFF_0000_0000_0000, 0x4008_0000, &[0xAA; 16], 16, RX)
+        .load(0xFFFF_0000_0000_1000, 0x4008_1000, &[0xBB; 8], 32, RW)
+        .build();
+    let kernel = KernelImage::parse(&kernel_img, &aarch64_options()).unwrap();
+    let memory_map = MemoryMap::new(vec![MemoryRegion::new(
+        MemoryRegionType::Standard,
+        (0x4000_0000, 0x8000_0000),
+    )]);
+    let direct_map = DirectMap::default();
+    let switch = TestSwitch {
+        vectors: Some((0xFFFF_C000_0000_0000, direct_map.hhdm_offset + 0x4000_0000)),
+        ttbr1: 0x4010_0000,
+        ..TestSwitch::default()
+    };
+
+    let mut boot_info = BootInfoWriter::new();
+    boot_info.command_line("console=ttyAMA0 init=\"/bin/sh -x\"");
+    let modules = [LoadedModule {
+        range: (0x4800_0000, 0x4800_0010),
+        vaddr: direct_map.hhdm_offset + 0x4800_0000,
+        name: "/boot/initrd.img".into(),
+        args: "initrd".into(),
+    }];
+
+    let switched = std::panic::catch_unwind(|| {
+        enter_kernel(
+            &kernel,
+            memory_map,
+            &direct_map,
+            boot_info,
+            &modules,
+            switch,
+        )
+    })
+    .unwrap_err()
+    .downcast::<TestSwitch>()
+    .unwrap();
+
+    assert_eq!(switched.entry, kernel.entry);
+    assert_eq!(switched.stack_top, kernel.requirements.stack_top);
+
+    // the whole of ArcServices is identity mapped
+    assert_eq!(switched.identity.len(), 1);
+    let (start, end) = switched.identity[0];
+    assert_eq!(start, switched.arg);
+    assert!(end - start >= core::mem::size_of::<ArcServices>() as u64);
+
+    let arcservices = unsafe { &*(switched.arg as *const ArcServices) };
+    assert_eq!(arcservices.hhdm_offset(), direct_map.hhdm_offset);
+    assert_eq!(arcservices.paging().phys_addr_base(), 0x4010_0000);
+    assert_eq!(arcservices.memory_regions().len(), 1);
+    assert_eq!(arcservices.vector_table(), 0xFFFF_C000_0000_0000);
+    // the command line is in the identity mapped block too
+    let cmdline = arcservices.command_line().unwrap();
+    let cmdline_addr = cmdline.as_str().as_ptr() as u64;
+    assert!(cmdline_addr > start && cmdline_addr < end);
+    assert_eq!(cmdline.value("init"), Some("/bin/sh -x"));
+
+    // modules are in ArcServices, and the tags
+    let module = &arcservices.modules()[0];
+    assert_eq!(module.address_range(), modules[0].range);
+    assert_eq!(module.vaddr(), modules[0].vaddr);
+    assert_eq!(
+        (module.name(), module.args()),
+        ("/boot/initrd.img", "initrd")
+    );
+    let tagged: Vec<_> = arcservices.boot_info().unwrap().modules().collect();
+    assert_eq!(tagged[0].name, "/boot/initrd.img");
+}
+
+#[test]
+fn test_load_modules() {
+    use arcboot_api::MemoryRegion;
+
+    let memory_map = MemoryMap::new(vec![MemoryRegion::new(
+        MemoryRegionType::Standard,
+        (0x4000_0000, 0x4010_0000),
+    )]);
+    let mut frames = FrameAllocator::new(&memory_map);
+    frames.allocate();
+    let direct_map = DirectMap::default();
+    let module = |path: &str, args: &str| BootModule {
+        path: path.into(),
+        args: args.into(),
+    };
+    let modules = [
+        (module("/boot/initrd.img", "initrd"), vec![0xAA; 0x1801]),
+        (module("/empty", ""), Vec::new()),
+    ];
+
+    let mut written = Vec::new();
+    let loaded = load_modules(
+        &modules,
+        &mut frames,
+        &direct_map,
+        0x4000,
+        |paddr, size, bytes| written.push((paddr, size, bytes.len())),
+    )
+    .unwrap();
+
+    // 16K aligned, and whole pages of it
+    assert_eq!(
+        written,
+        [(0x4000_4000, 0x4000, 0x1801), (0x4000_8000, 0x4000, 0)]
+    );
+    assert_eq!(loaded[0].range, (0x4000_4000, 0x4000_5801));
+    assert_eq!(loaded[0].vaddr, direct_map.hhdm_offset + 0x4000_4000);
+    assert_eq!(loaded[0].name, "/boot/initrd.img");
+    assert_eq!(loaded[1].range, (0x4000_8000, 0x4000_8000));
+
+    let types: Vec<_> = frames
+        .memory_map(&memory_map)
+        .regions()
+        .iter()
+        .map(|r| (r.address_range(), r.region_type().unwrap()))
+        .collect();
+    // one region per module
+    assert_eq!(
+        types[1..],
+        [
+            ((0x4000_1000, 0x4000_4000), MemoryRegionType::Standard),
+            ((0x4000_4000, 0x4000_8000), MemoryRegionType::BootModule),
+            ((0x4000_8000, 0x4000_C000), MemoryRegionType::BootModule),
+            ((0x4000_C000, 0x4010_0000), MemoryRegionType::Standard),
+        ]
+    );
+
+    let huge = [(module("/huge", ""), vec![0; 0x10_0000])];
+    assert_eq!(
+        load_modules(&huge, &mut frames, &direct_map, 0x1000, |_, _, _| {}),
+        Err(ModuleLoadError {
+            path: "/huge".into(),
+            size: 0x10_0000
+        })
+    );
 }
@@ -0,0 +1,626 @@
+// ---------------
+// BOOT CONFIG
+// ---------------
+
+// arcboot.conf, read from the boot volume. One `key value` per line, # starts a comment line:
+//
+//   default neutron
+//   timeout 5
+//
+//   entry neutron
+//       title Neutron
+//       kernel /neutron.elf
+//       cmdline console=ttyAMA0 quiet
+//       module /boot/initrd.img initrd
+//       devicetree /boot/virt.dtb
+//       flags no_kaslr
+//
+// default and timeout have to come before the first entry. Every other key belongs to the entry above it
+
+use alloc::{
+    borrow::ToOwned,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt;
+
+use crate::boot::LoadOptions;
+
+/// Where arcboot looks for its config, from the root of the volume it was loaded from
+pub const CONFIG_PATH: &str = "/boot/arcboot.conf";
+
+/// Seconds before the default entry boots, if arcboot.conf doesnt say
+pub const DEFAULT_TIMEOUT: u32 = 5;
+
+/// What gets booted when there is no arcboot.conf
+pub const DEFAULT_KERNEL_PATH: &str = "/neutron.elf";
+
+/// Every key, globals first
+const KEYS: [&str; 9] = [
+    "default",
+    "timeout",
+    "entry",
+    "title",
+    "kernel",
+    "cmdline",
+    "module",
+    "devicetree",
+    "flags",
+];
+
+// ---------------
+// ERRORS
+// ---------------
+
+/// A problem with arcboot.conf, and the line it is on (from 1)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub line: usize,
+    pub kind: ConfigErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigErrorKind {
+    /// The file isnt UTF-8. The line is where the first bad byte is
+    NotUtf8,
+    UnknownKey(String),
+    /// Key with nothing after it
+    MissingValue(&'static str),
+    /// More than one word after a key that takes one
+    ExtraValue(&'static str),
+    /// Set twice in the same place
+    DuplicateKey(&'static str),
+    /// An entry's key before the first entry
+    OutsideEntry(&'static str),
+    /// default or timeout after the first entry
+    InsideEntry(&'static str),
+    BadTimeout(String),
+    UnknownFlag(String),
+    /// Two entries with the same id
+    DuplicateEntry(String),
+    /// Entry with no kernel line. On the entry's line
+    NoKernel(String),
+    /// default names an entry that doesnt exist
+    UnknownDefault(String),
+    NoEntries,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            ConfigErrorKind::NotUtf8 => write!(f, "not valid UTF-8"),
+            ConfigErrorKind::UnknownKey(key) => {
+                write!(f, "unknown key `{key}`, expected one of {}", KEYS[0])?;
+                for key in &KEYS[1..] {
+                    write!(f, ", {key}")?;
+                }
+                Ok(())
+            }
+            ConfigErrorKind::MissingValue(key) => write!(f, "`{key}` needs a value"),
+            ConfigErrorKind::ExtraValue(key) => write!(f, "`{key}` takes a single word"),
+            ConfigErrorKind::DuplicateKey(key) => write!(f, "`{key}` is already set"),
+            ConfigErrorKind::OutsideEntry(key) => {
+                write!(
+                    f,
+                    "`{key}` has to be inside an entry, start one with `entry <id>`"
+                )
+            }
+            ConfigErrorKind::InsideEntry(key) => {
+                write!(f, "`{key}` has to come before the first entry")
+            }
+            ConfigErrorKind::BadTimeout(value) => {
+                write!(f, "timeout `{value}` is not a number of seconds")
+            }
+            ConfigErrorKind::UnknownFlag(flag) => {
+                write!(f, "unknown flag `{flag}`, expected no_kaslr or allow_wx")
+            }
+            ConfigErrorKind::DuplicateEntry(id) => write!(f, "there is already an entry `{id}`"),
+            ConfigErrorKind::NoKernel(id) => write!(f, "entry `{id}` has no kernel"),
+            ConfigErrorKind::UnknownDefault(id) => write!(f, "default `{id}` is not an entry"),
+            ConfigErrorKind::NoEntries => write!(f, "no entries"),
+        }
+    }
+}
+
+// ---------------
+// ENTRIES
+// ---------------
+
+/// A file to load alongside the kernel, and the string it gets passed with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootModule {
+    pub path: String,
+    pub args: String,
+}
+
+/// Per entry switches, from its flags line
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EntryFlags {
+    /// Load relocatable kernels at their default base
+    pub no_kaslr: bool,
+    /// Accept writable and executable segments, see LoadOptions::allow_wx
+    pub allow_wx: bool,
+}
+
+/// One way to boot, from an `entry` block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootEntry {
+    /// The word after `entry`. What default refers to
+    pub id: String,
+    /// What the menu shows. The id if there is no title line
+    pub title: String,
+    pub kernel: String,
+    pub cmdline: String,
+    pub modules: Vec<BootModule>,
+    /// Passed to the kernel as a TAG_DTB
+    pub device_tree: Option<String>,
+    pub flags: EntryFlags,
+}
+
+impl BootEntry {
+    pub fn new(id: &str, kernel: &str) -> Self {
+        Self {
+            id: id.to_owned(),
+            title: id.to_owned(),
+            kernel: kernel.to_owned(),
+            cmdline: String::new(),
+            modules: Vec::new(),
+            device_tree: None,
+            flags: EntryFlags::default(),
+        }
+    }
+
+    /// Loader settings this entry changes
+    pub fn apply(&self, options: &mut LoadOptions) {
+        options.allow_wx = self.flags.allow_wx;
+        if self.flags.no_kaslr {
+            options.kaslr_seed = None;
+        }
+    }
+}
+
+/// A whole arcboot.conf
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootConfig {
+    pub entries: Vec<BootEntry>,
+    /// Index into entries
+    pub default: usize,
+    /// Seconds to wait before booting the default entry. 0 boots it straight away
+    pub timeout: u32,
+}
+
+/// One entry for DEFAULT_KERNEL_PATH, booted straight away
+impl Default for BootConfig {
+    fn default() -> Self {
+        Self {
+            entries: alloc::vec![BootEntry::new("default", DEFAULT_KERNEL_PATH)],
+            default: 0,
+            timeout: 0,
+        }
+    }
+}
+
+/// Entry being parsed, with the line it started on and what was set
+struct PartialEntry {
+    entry: BootEntry,
+    line: usize,
+    has_kernel: bool,
+    has_title: bool,
+    has_cmdline: bool,
+}
+
+impl BootConfig {
+    /// Parse the raw file, which has to be UTF-8
+    pub fn parse_bytes(bytes: &[u8]) -> Result<Self, ConfigError> {
+        let text = core::str::from_utf8(bytes).map_err(|err| {
+            let before = &bytes[..err.valid_up_to()];
+            ConfigError {
+                line: before.iter().filter(|b| **b == b'\n').count() + 1,
+                kind: ConfigErrorKind::NotUtf8,
+            }
+        })?;
+
+        Self::parse(text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let mut entries: Vec<BootEntry> = Vec::new();
+        let mut current: Option<PartialEntry> = None;
+        let mut default: Option<(String, usize)> = None;
+        let mut timeout: Option<u32> = None;
+        let mut n_lines = 0;
+
+        for (i, line) in text.lines().enumerate() {
+            let line_no = i + 1;
+            n_lines = line_no;
+            let err = |kind| ConfigError {
+                line: line_no,
+                kind,
+            };
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.split_once(char::is_whitespace) {
+                Some((key, value)) => (key, value.trim()),
+                None => (line, ""),
+            };
+
+            let key = KEYS
+                .iter()
+                .find(|k| **k == key)
+                .copied()
+                .ok_or_else(|| err(ConfigErrorKind::UnknownKey(key.to_owned())))?;
+            match key {
+                "default" | "timeout" => {
+                    if current.is_some() {
+                        return Err(err(ConfigErrorKind::InsideEntry(key)));
+                    }
+                    let value = single_word(key, value).map_err(err)?;
+                    if key == "default" {
+                        if default.is_some() {
+                            return Err(err(ConfigErrorKind::DuplicateKey(key)));
+                        }
+                        default = Some((value.to_owned(), line_no));
+                    } else {
+                        if timeout.is_some() {
+                            return Err(err(ConfigErrorKind::DuplicateKey(key)));
+                        }
+                        let seconds = value
+                            .parse()
+                            .map_err(|_| err(ConfigErrorKind::BadTimeout(value.to_owned())))?;
+                        timeout = Some(seconds);
+                    }
+                }
+                "entry" => {
+                    let id = single_word("entry", value).map_err(err)?;
+                    if let Some(done) = current.take() {
+                        entries.push(finish_entry(done)?);
+                    }
+                    if entries.iter().any(|e| e.id == id) {
+                        return Err(err(ConfigErrorKind::DuplicateEntry(id.to_owned())));
+                    }
+                    current = Some(PartialEntry {
+                        entry: BootEntry::new(id, ""),
+                        line: line_no,
+                        has_kernel: false,
+                        has_title: false,
+                        has_cmdline: false,
+                    });
+                }
+                _ => {
+                    let partial = current
+                        .as_mut()
+                        .ok_or_else(|| err(ConfigErrorKind::OutsideEntry(key)))?;
+                    // an empty command line is fine, anything else needs a value
+                    if value.is_empty() && key != "cmdline" {
+                        return Err(err(ConfigErrorKind::MissingValue(key)));
+                    }
+                    set_entry_key(partial, key, value).map_err(err)?;
+                }
+            }
+        }
+
+        if let Some(done) = current.take() {
+            entries.push(finish_entry(done)?);
+        }
+        if entries.is_empty() {
+            return Err(ConfigError {
+                line: n_lines,
+                kind: ConfigErrorKind::NoEntries,
+            });
+        }
+
+        let default = match default {
+            Some((id, line)) => entries.iter().position(|e| e.id == id).ok_or(ConfigError {
+                line,
+                kind: ConfigErrorKind::UnknownDefault(id),
+            })?,
+            None => 0,
+        };
+
+        Ok(Self {
+            entries,
+            default,
+            timeout: timeout.unwrap_or(DEFAULT_TIMEOUT),
+        })
+    }
+
+    pub fn default_entry(&self) -> &BootEntry {
+        &self.entries[self.default]
+    }
+
+    /// Take out the entry with this id, e.g. because its kernel wouldnt load. If it was the default, the entry after it is
+    pub fn remove_entry(&mut self, id: &str) {
+        if let Some(index) = self.entries.iter().position(|entry| entry.id == id) {
+            self.entries.remove(index);
+            if index < self.default {
+                self.default -= 1;
+            }
+            self.default = self.default.min(self.entries.len().saturating_sub(1));
+        }
+    }
+}
+
+// ---------------
+// LOAD OPTIONS
+// ---------------
+
+/// The command line arcboot itself was started with, from its LoadedImage load options. UCS-2, up to the first NUL
+/// The UEFI shell puts arcboot's own path first, which gets dropped. None if there is nothing usable, like the binary data boot managers pass
+pub fn load_options_cmdline(options: &[u8]) -> Option<String> {
+    let units = options
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0);
+    let text: String = char::decode_utf16(units).collect::<Result<_, _>>().ok()?;
+    if text.chars().any(|c| c.is_control() && !c.is_whitespace()) {
+        return None;
+    }
+
+    let text = text.trim();
+    let (first, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let cmdline = match first.to_ascii_lowercase().ends_with(".efi") {
+        true => rest.trim(),
+        false => text,
+    };
+
+    (!cmdline.is_empty()).then(|| cmdline.to_owned())
+}
+
+fn single_word<'a>(key: &'static str, value: &'a str) -> Result<&'a str, ConfigErrorKind> {
+    if value.is_empty() {
+        Err(ConfigErrorKind::MissingValue(key))
+    } else if value.contains(char::is_whitespace) {
+        Err(ConfigErrorKind::ExtraValue(key))
+    } else {
+        Ok(value)
+    }
+}
+
+fn set_entry_key(
+    partial: &mut PartialEntry,
+    key: &'static str,
+    value: &str,
+) -> Result<(), ConfigErrorKind> {
+    let entry = &mut partial.entry;
+    let seen = match key {
+        "title" => core::mem::replace(&mut partial.has_title, true),
+        "kernel" => core::mem::replace(&mut partial.has_kernel, true),
+        "cmdline" => core::mem::replace(&mut partial.has_cmdline, true),
+        "devicetree" => entry.device_tree.is_some(),
+        _ => false,
+    };
+    if seen {
+        return Err(ConfigErrorKind::DuplicateKey(key));
+    }
+
+    match key {
+        "title" => entry.title = value.to_owned(),
+        // paths can have spaces, so the whole rest of the line
+        "kernel" => entry.kernel = value.to_owned(),
+        "cmdline" => entry.cmdline = value.to_owned(),
+        "devicetree" => entry.device_tree = Some(value.to_owned()),
+        "module" => {
+            let (path, args) = value.split_once(char::is_whitespace).unwrap_or((value, ""));
+            entry.modules.push(BootModule {
+                path: path.to_owned(),
+                args: args.trim().to_string(),
+            });
+        }
+        _ => {
+            for flag in value.split_whitespace() {
+                match flag {
+                    "no_kaslr" => entry.flags.no_kaslr = true,
+                    "allow_wx" => entry.flags.allow_wx = true,
+                    _ => return Err(ConfigErrorKind::UnknownFlag(flag.to_owned())),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn finish_entry(partial: PartialEntry) -> Result<BootEntry, ConfigError> {
+    if !partial.has_kernel {
+        return Err(ConfigError {
+            line: partial.line,
+            kind: ConfigErrorKind::NoKernel(partial.entry.id),
+        });
+    }
+
+    Ok(partial.entry)
+}
+
+// --------------
+// TEST
+// --------------
+
+#[cfg(test)]
+const TEST_CONFIG: &str = "
+# two kernels on the same ESP
+default debug
+timeout 3
+
+entry release
+    title Neutron
+    kernel /neutron.elf
+    cmdline console=ttyAMA0 quiet
+    module /boot/initrd.img initrd
+    module /boot/fw/gpu.bin
+    devicetree /boot/virt.dtb
+
+entry debug
+    kernel /boot/debug/neutron kernel.elf
+    cmdline
+    flags no_kaslr allow_wx
+";
+
+#[test]
+fn test_parse_config() {
+    let config = BootConfig::parse(TEST_CONFIG).unwrap();
+    assert_eq!(config.timeout, 3);
+    assert_eq!(config.entries.len(), 2);
+    assert_eq!(config.default_entry().id, "debug");
+
+    let release = &config.entries[0];
+    assert_eq!(release.title, "Neutron");
+    assert_eq!(release.kernel, "/neutron.elf");
+    assert_eq!(release.cmdline, "console=ttyAMA0 quiet");
+    assert_eq!(
+        release.modules,
+        [
+            BootModule {
+                path: "/boot/initrd.img".into(),
+                args: "initrd".into(),
+            },
+            BootModule {
+                path: "/boot/fw/gpu.bin".into(),
+                args: "".into(),
+            },
+        ]
+    );
+    assert_eq!(release.device_tree.as_deref(), Some("/boot/virt.dtb"));
+    assert_eq!(release.flags, EntryFlags::default());
+
+    let debug = &config.entries[1];
+    assert_eq!(debug.title, "debug");
+    assert_eq!(debug.kernel, "/boot/debug/neutron kernel.elf");
+    assert_eq!(debug.cmdline, "");
+    assert_eq!(
+        debug.flags,
+        EntryFlags {
+            no_kaslr: true,
+            allow_wx: true,
+        }
+    );
+
+    let mut options = LoadOptions::new(0, Vec::new());
+    options.kaslr_seed = Some(42);
+    debug.apply(&mut options);
+    assert!(options.allow_wx);
+    assert_eq!(options.kaslr_seed, None);
+
+    // a broken default hands over to the entry after it
+    let mut config =
+        BootConfig::parse(&(TEST_CONFIG.to_owned() + "entry c\nkernel /c.elf")).unwrap();
+    config.remove_entry("debug");
+    assert_eq!(config.default_entry().id, "c");
+    config.remove_entry("release");
+    assert_eq!(config.default_entry().id, "c");
+    config.remove_entry("c");
+    assert!(config.entries.is_empty());
+
+    // first entry and the default timeout if neither is given
+    let config = BootConfig::parse("entry a\nkernel /a.elf").unwrap();
+    assert_eq!(config.default, 0);
+    assert_eq!(config.timeout, DEFAULT_TIMEOUT);
+}
+
+#[test]
+fn test_config_errors() {
+    let error = |text: &str| BootConfig::parse(text).unwrap_err();
+    let entry = "entry a\nkernel /a.elf\n";
+
+    assert_eq!(
+        error("entry a\n  kernal /a.elf"),
+        ConfigError {
+            line: 2,
+            kind: ConfigErrorKind::UnknownKey("kernal".into()),
+        }
+    );
+    assert_eq!(
+        error("kernel /a.elf").kind,
+        ConfigErrorKind::OutsideEntry("kernel")
+    );
+    assert_eq!(
+        error(&(entry.to_owned() + "timeout 3")),
+        ConfigError {
+            line: 3,
+            kind: ConfigErrorKind::InsideEntry("timeout"),
+        }
+    );
+    assert_eq!(
+        error(&("timeout soon\n".to_owned() + entry)).kind,
+        ConfigErrorKind::BadTimeout("soon".into())
+    );
+    assert_eq!(
+        error(&("default a b\n".to_owned() + entry)).kind,
+        ConfigErrorKind::ExtraValue("default")
+    );
+    assert_eq!(
+        error(&("default b\n".to_owned() + entry)),
+        ConfigError {
+            line: 1,
+            kind: ConfigErrorKind::UnknownDefault("b".into()),
+        }
+    );
+    assert_eq!(
+        error("entry a\n\nentry b\nkernel /b.elf"),
+        ConfigError {
+            line: 1,
+            kind: ConfigErrorKind::NoKernel("a".into()),
+        }
+    );
+    assert_eq!(
+        error(&(entry.to_owned() + entry)).kind,
+        ConfigErrorKind::DuplicateEntry("a".into())
+    );
+    assert_eq!(
+        error(&(entry.to_owned() + "kernel /b.elf")).kind,
+        ConfigErrorKind::DuplicateKey("kernel")
+    );
+    assert_eq!(
+        error(&(entry.to_owned() + "flags no_kaslr fast")).kind,
+        ConfigErrorKind::UnknownFlag("fast".into())
+    );
+    assert_eq!(
+        error(&(entry.to_owned() + "module")).kind,
+        ConfigErrorKind::MissingValue("module")
+    );
+    assert_eq!(
+        error("# nothing\n\n"),
+        ConfigError {
+            line: 2,
+            kind: ConfigErrorKind::NoEntries,
+        }
+    );
+    assert_eq!(
+        BootConfig::parse_bytes(b"entry a\nkernel /\xFF.elf").unwrap_err(),
+        ConfigError {
+            line: 2,
+            kind: ConfigErrorKind::NotUtf8,
+        }
+    );
+
+    assert_eq!(
+        error("entry a\n  kernal /a.elf").to_string(),
+        "line 2: unknown key `kernal`, expected one of default, timeout, entry, title, kernel, cmdline, module, devicetree, flags"
+    );
+}
+
+#[test]
+fn test_load_options_cmdline() {
+    let ucs2 = |s: &str| -> Vec<u8> { s.encode_utf16().flat_map(u16::to_le_bytes).collect() };
+
+    assert_eq!(
+        load_options_cmdline(&ucs2(
+            "\\EFI\\BOOT\\ARCBOOT.EFI console=ttyAMA0  quiet\0junk"
+        )),
+        Some("console=ttyAMA0  quiet".to_string())
+    );
+    assert_eq!(
+        load_options_cmdline(&ucs2(" init=/bin/sh ")),
+        Some("init=/bin/sh".to_string())
+    );
+    // nothing after arcboot's path
+    assert_eq!(load_options_cmdline(&ucs2("arcboot.efi")), None);
+    assert_eq!(load_options_cmdline(&[]), None);
+    // a boot manager's binary blob
+    assert_eq!(load_options_cmdline(&[0x01, 0x00, 0x41, 0x00]), None);
+    // a lone surrogate
+    assert_eq!(load_options_cmdline(&[0x00, 0xD8, 0x41, 0x00]), None);
+}
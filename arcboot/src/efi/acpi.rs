@@ -57,6 +57,15 @@ impl AcpiHandler for AcpiHandle {
     }
 }
 
+/// Paddr of the ACPI RSDP, for the kernel. The ACPI 2.0 one if the firmware has both
+pub fn rsdp(config_table: &[ConfigTableEntry]) -> Option<u64> {
+    let find = |guid| config_table.iter().find(|entry| entry.guid == guid);
+
+    find(cfg::ACPI2_GUID)
+        .or_else(|| find(cfg::ACPI_GUID))
+        .map(|entry| entry.address as u64)
+}
+
 pub fn get_acpi_tables(rt: &RuntimeServices, config_table: &[ConfigTableEntry]) {
     let res = rt.variable_keys().unwrap();
 
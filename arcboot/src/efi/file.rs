@@ -0,0 +1,167 @@
+// ---------------
+// BOOT VOLUME FILES
+// ---------------
+
+// Files on the volume arcboot was loaded from. Paths are written with / like in arcboot.conf, and turned into UEFI's \ here
+
+use alloc::string::String;
+use arcboot_api::AddressRange;
+use core::{fmt, ops::Deref};
+use uefi::{
+    prelude::*,
+    proto::media::{
+        file::{Directory, File, FileAttribute, FileInfo, FileMode, RegularFile},
+        fs::SimpleFileSystem,
+    },
+    table::boot::{AllocateType, MemoryType, ScopedProtocol},
+    CString16,
+};
+
+use crate::config::{BootConfig, ConfigError, CONFIG_PATH};
+use crate::memory::PAGE_SIZE;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileError {
+    /// Has characters UCS-2 cant hold
+    BadPath(String),
+    /// Opening failed, usually because it isnt there
+    NotFound(String),
+    /// A directory, not a file
+    NotAFile(String),
+    /// Not enough free pages to read it into. Has its size
+    TooLarge(String, u64),
+    Read(String, Status),
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadPath(path) => write!(f, "{path} is not a valid UEFI path"),
+            Self::NotFound(path) => write!(f, "{path} not found"),
+            Self::NotAFile(path) => write!(f, "{path} is a directory"),
+            Self::TooLarge(path, size) => {
+                write!(f, "{path} ({size} bytes) does not fit in free memory")
+            }
+            Self::Read(path, status) => write!(f, "could not read {path}: {status:?}"),
+        }
+    }
+}
+
+/// A file read into LOADER_DATA pages of its own, so it outlives boot services and the heap
+/// Nothing frees them once boot services are gone, so address_range() has to be carved out of the memory map while it's used
+pub struct FileBuffer {
+    paddr: u64,
+    len: usize,
+    n_pages: usize,
+}
+
+impl FileBuffer {
+    /// The pages it was read into
+    pub fn address_range(&self) -> AddressRange {
+        (self.paddr, self.paddr + (self.n_pages as u64) * PAGE_SIZE)
+    }
+}
+
+impl Deref for FileBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.paddr as *const u8, self.len) }
+    }
+}
+
+impl AsRef<[u8]> for FileBuffer {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+/// The volume arcboot was loaded from. Only usable while boot services are, so everything the boot entry needs is read before they exit
+pub struct BootVolume<'a> {
+    bt: &'a BootServices,
+    // keeps the file system protocol open under root
+    _fs: ScopedProtocol<'a, SimpleFileSystem>,
+    root: Directory,
+}
+
+impl<'a> BootVolume<'a> {
+    pub fn open(bt: &'a BootServices, image: Handle) -> uefi::Result<Self> {
+        let fs = bt.get_image_file_system(image)?;
+        let root = unsafe { &mut *fs.interface.get() }.open_volume()?;
+
+        Ok(Self { bt, _fs: fs, root })
+    }
+
+    /// The whole file at path, from the root of the volume. Read into pages sized from its FileInfo, not the heap
+    pub fn read_file(&mut self, path: &str) -> Result<FileBuffer, FileError> {
+        let uefi_path: String = path.trim_start_matches('/').replace('/', "\\");
+        let uefi_path =
+            CString16::try_from(uefi_path.as_str()).map_err(|_| FileError::BadPath(path.into()))?;
+
+        let mut file = self
+            .root
+            .open(&uefi_path, FileMode::Read, FileAttribute::empty())
+            .map_err(|_| FileError::NotFound(path.into()))?
+            .into_regular_file()
+            .ok_or_else(|| FileError::NotAFile(path.into()))?;
+        let size = file
+            .get_boxed_info::<FileInfo>()
+            .map_err(|err| FileError::Read(path.into(), err.status()))?
+            .file_size();
+
+        // even an empty file gets a page, so the buffer has an address
+        let n_pages = usize::try_from(size.div_ceil(PAGE_SIZE).max(1))
+            .map_err(|_| FileError::TooLarge(path.into(), size))?;
+        let paddr = self
+            .bt
+            .allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, n_pages)
+            .map_err(|_| FileError::TooLarge(path.into(), size))?;
+        let data = unsafe { core::slice::from_raw_parts_mut(paddr as *mut u8, size as usize) };
+
+        match read_all(&mut file, data) {
+            Ok(len) => Ok(FileBuffer {
+                paddr,
+                len,
+                n_pages,
+            }),
+            Err(status) => {
+                let _ = self.bt.free_pages(paddr, n_pages);
+                Err(FileError::Read(path.into(), status))
+            }
+        }
+    }
+
+    /// Give back a file's pages. Only while boot services are up, and nothing can still point into it
+    pub fn free_file(&self, file: FileBuffer) {
+        let _ = self.bt.free_pages(file.paddr, file.n_pages);
+    }
+
+    /// arcboot.conf from CONFIG_PATH. If there isnt one, the default config boots DEFAULT_KERNEL_PATH
+    pub fn load_config(&mut self) -> Result<BootConfig, ConfigError> {
+        match self.read_file(CONFIG_PATH) {
+            Ok(file) => {
+                let config = BootConfig::parse_bytes(&file);
+                self.free_file(file);
+                config
+            }
+            Err(err) => {
+                warn!("No boot config ({err}), using the defaults");
+                Ok(BootConfig::default())
+            }
+        }
+    }
+}
+
+/// Read file into data until it ends or data is full. Returns how much was read
+fn read_all(file: &mut RegularFile, data: &mut [u8]) -> Result<usize, Status> {
+    let mut read = 0;
+    while read < data.len() {
+        let n = file.read(&mut data[read..]).map_err(|err| err.status())?;
+        if n == 0 {
+            break;
+        }
+        read += n;
+    }
+
+    Ok(read)
+}
@@ -0,0 +1,203 @@
+// ---------------
+// BOOT MENU
+// ---------------
+
+// The boot menu on the firmware's text console. Keys come from Simple Text Input, the countdown from a periodic timer event
+// See crate::menu for what the keys do. Pressing `e` swaps the menu for an edit screen on the selected entry
+
+use core::fmt::{self, Write};
+use uefi::{
+    prelude::*,
+    proto::console::text::{Color, Input, Key, Output, ScanCode},
+    table::boot::{EventType, TimerTrigger, Tpl},
+    Event,
+};
+
+use crate::config::{BootConfig, BootEntry};
+use crate::menu::{EditAction, EntryEditor, Menu, MenuAction, MenuKey};
+
+/// One second, in the 100ns units timers count in
+const TICK: u64 = 10_000_000;
+
+/// Columns taken by the edit screen's labels
+const LABEL_WIDTH: usize = 9;
+
+/// Show the menu and return the entry to boot, with any edits made to it. With a timeout of 0, the menu only comes up if a key is already pressed
+pub fn choose_entry(st: &mut SystemTable<Boot>, config: &BootConfig) -> BootEntry {
+    let mut menu = Menu::new(config);
+
+    if config.timeout == 0 {
+        match read_key(st.stdin()).map(|key| menu.key(key)) {
+            Some(MenuAction::Boot(index)) => return config.entries[index].clone(),
+            Some(_) => {}
+            None => return config.default_entry().clone(),
+        }
+    }
+
+    let bt = st.boot_services();
+    // the watchdog would reset the machine after 5 minutes sat at the menu
+    if bt.set_watchdog_timer(0, 0x10000, None).is_err() {
+        warn!("Could not turn off the watchdog, the menu might get reset");
+    }
+    let timer = unsafe { bt.create_event(EventType::TIMER, Tpl::APPLICATION, None, None) }
+        .expect("Failed to create the boot menu timer");
+    bt.set_timer(&timer, TimerTrigger::Periodic(TICK))
+        .expect("Failed to start the boot menu timer");
+    let key_event = unsafe { st.stdin().wait_for_key_event().unsafe_clone() };
+
+    redraw(st.stdout(), config, &menu);
+    let choice = loop {
+        let mut events = unsafe { [key_event.unsafe_clone(), timer.unsafe_clone()] };
+        let woken_by = st
+            .boot_services()
+            .wait_for_event(&mut events)
+            .expect("Failed to wait for a key or the timer");
+
+        let action = match woken_by {
+            0 => read_key(st.stdin()).map_or(MenuAction::None, |key| menu.key(key)),
+            _ => menu.tick(),
+        };
+        match action {
+            MenuAction::None => {}
+            MenuAction::Redraw => redraw(st.stdout(), config, &menu),
+            MenuAction::Boot(index) => break config.entries[index].clone(),
+            MenuAction::Edit(index) => match edit_entry(st, &key_event, &config.entries[index]) {
+                Some(entry) => break entry,
+                None => redraw(st.stdout(), config, &menu),
+            },
+        }
+    };
+
+    let bt = st.boot_services();
+    if bt.set_timer(&timer, TimerTrigger::Cancel).is_err() || bt.close_event(timer).is_err() {
+        warn!("Could not stop the boot menu timer");
+    }
+    st.stdout().clear().ok();
+
+    choice
+}
+
+/// The edit screen for entry. None if it was left with Escape
+fn edit_entry(
+    st: &mut SystemTable<Boot>,
+    key_event: &Event,
+    entry: &BootEntry,
+) -> Option<BootEntry> {
+    let mut editor = EntryEditor::new(entry);
+
+    redraw_editor(st.stdout(), entry, &editor);
+    let action = loop {
+        let mut events = unsafe { [key_event.unsafe_clone()] };
+        st.boot_services()
+            .wait_for_event(&mut events)
+            .expect("Failed to wait for a key");
+
+        let action = read_key(st.stdin()).map_or(EditAction::None, |key| editor.key(key));
+        match action {
+            EditAction::None => {}
+            EditAction::Redraw => redraw_editor(st.stdout(), entry, &editor),
+            EditAction::Boot | EditAction::Cancel => break action,
+        }
+    };
+    st.stdout().enable_cursor(false).ok();
+
+    (action == EditAction::Boot).then(|| editor.entry())
+}
+
+/// The next key, if there is one waiting
+fn read_key(stdin: &mut Input) -> Option<MenuKey> {
+    let key = stdin.read_key().ok().flatten()?;
+
+    Some(match key {
+        Key::Special(ScanCode::UP) => MenuKey::Up,
+        Key::Special(ScanCode::DOWN) => MenuKey::Down,
+        Key::Special(ScanCode::LEFT) => MenuKey::Left,
+        Key::Special(ScanCode::RIGHT) => MenuKey::Right,
+        Key::Special(ScanCode::HOME) => MenuKey::Home,
+        Key::Special(ScanCode::END) => MenuKey::End,
+        Key::Special(ScanCode::ESCAPE) => MenuKey::Escape,
+        Key::Special(ScanCode::DELETE) => MenuKey::Delete,
+        Key::Special(_) => MenuKey::Other,
+        Key::Printable(c) => match char::from(c) {
+            '\r' | '\n' => MenuKey::Enter,
+            '\u{8}' => MenuKey::Backspace,
+            c => MenuKey::Char(c),
+        },
+    })
+}
+
+fn redraw(stdout: &mut Output, config: &BootConfig, menu: &Menu) {
+    if draw(stdout, config, menu).is_err() {
+        warn!("Could not draw the boot menu");
+    }
+}
+
+fn draw(stdout: &mut Output, config: &BootConfig, menu: &Menu) -> fmt::Result {
+    let colors = |stdout: &mut Output, fg, bg| stdout.set_color(fg, bg).map_err(|_| fmt::Error);
+
+    stdout.clear().map_err(|_| fmt::Error)?;
+    stdout.enable_cursor(false).ok();
+    writeln!(stdout, "arcboot\n")?;
+
+    for (i, entry) in config.entries.iter().enumerate() {
+        if i == menu.selected() {
+            colors(stdout, Color::Black, Color::LightGray)?;
+        }
+        write!(stdout, " {}. {} ", i + 1, entry.title)?;
+        colors(stdout, Color::LightGray, Color::Black)?;
+        writeln!(stdout)?;
+    }
+
+    writeln!(stdout)?;
+    match menu.countdown() {
+        Some(seconds) => writeln!(
+            stdout,
+            "Booting {} in {seconds}s, press any key to stop",
+            config.entries[menu.selected()].title
+        ),
+        None => writeln!(
+            stdout,
+            "Up/Down or 1-9 to pick an entry, Enter to boot it, e to edit it"
+        ),
+    }
+}
+
+fn redraw_editor(stdout: &mut Output, entry: &BootEntry, editor: &EntryEditor) {
+    if draw_editor(stdout, entry, editor).is_err() {
+        warn!("Could not draw the entry editor");
+    }
+}
+
+/// One row per field, long lines scroll sideways. The cursor is left on the current field
+fn draw_editor(stdout: &mut Output, entry: &BootEntry, editor: &EntryEditor) -> fmt::Result {
+    let columns = match stdout.current_mode() {
+        Ok(Some(mode)) => mode.columns(),
+        _ => 80,
+    };
+    // the last column would wrap
+    let width = columns.saturating_sub(LABEL_WIDTH + 1);
+
+    stdout.clear().map_err(|_| fmt::Error)?;
+    writeln!(stdout, "Editing {}, for this boot only\n", entry.title)?;
+
+    let first_row = 2;
+    let mut cursor = (0, 0);
+    for (i, (label, line)) in editor.fields().iter().enumerate() {
+        let (text, column) = line.view(width);
+        writeln!(stdout, "{label:LABEL_WIDTH$}{text}")?;
+        if i == editor.current() {
+            cursor = (LABEL_WIDTH + column, first_row + i);
+        }
+    }
+
+    writeln!(
+        stdout,
+        "\nUp/Down to pick a line, Enter to boot, Escape to go back"
+    )?;
+    stdout
+        .set_cursor_position(cursor.0, cursor.1)
+        .map_err(|_| fmt::Error)?;
+    stdout.enable_cursor(true).ok();
+
+    Ok(())
+}
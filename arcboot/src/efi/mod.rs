@@ -1,16 +1,27 @@
-use alloc::vec;
-use alloc::vec::Vec;
-use arcboot_api::{address_range_4k, MemoryMap, MemoryRegion, MemoryRegionType};
-use uefi::table::boot::{MemoryDescriptor, MemoryType};
+use alloc::{string::String, vec::Vec};
+use arcboot_api::{AddressRange, MemoryMap};
+use uefi::table::boot::MemoryDescriptor;
+
+use crate::memory::{
+    map::{boot_frames, descriptor_containing, normalise, FirmwareDescriptor},
+    PAGE_SIZE,
+};
 use uefi::{
     prelude::BootServices,
-    table::{runtime::ResetType, Runtime, SystemTable},
-    Status,
+    proto::loaded_image::LoadedImage,
+    table::{
+        boot::{OpenProtocolAttributes, OpenProtocolParams},
+        runtime::ResetType,
+        Runtime, SystemTable,
+    },
+    Handle, Status,
 };
 
 // Contains the startup boot code (and tests)
 pub mod acpi;
 pub mod boot;
+pub mod file;
+pub mod menu;
 pub mod proto;
 pub mod runtime;
 
@@ -36,6 +47,43 @@ pub fn shutdown(mut st: SystemTable<Runtime>) -> ! {
     rt.reset(ResetType::Shutdown, Status::SUCCESS, None);
 }
 
+/// Random number to seed KASLR with. EFI_RNG_PROTOCOL first, then RNDR on arm64. None if neither is there
+pub fn kaslr_seed(bt: &BootServices) -> Option<u64> {
+    let seed = proto::rng::random_u64(bt);
+
+    #[cfg(target_arch = "aarch64")]
+    let seed = seed.or_else(crate::arm64::rng::rndr);
+
+    seed
+}
+
+/// Bytes for the kernel to seed its RNG with, from the same sources as kaslr_seed. Drawn separately, so they say nothing about the KASLR base
+pub fn rng_seed(bt: &BootServices) -> Option<[u8; 32]> {
+    let mut seed = [0; 32];
+    for chunk in seed.chunks_exact_mut(8) {
+        chunk.copy_from_slice(&kaslr_seed(bt)?.to_le_bytes());
+    }
+
+    Some(seed)
+}
+
+/// The command line arcboot was started with, if it was given one. See config::load_options_cmdline
+pub fn load_options_cmdline(bt: &BootServices, image: Handle) -> Option<String> {
+    let loaded_image = bt
+        .open_protocol::<LoadedImage>(
+            OpenProtocolParams {
+                handle: image,
+                agent: image,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+        .ok()?;
+    let loaded_image = unsafe { &*loaded_image.interface.get() };
+
+    crate::config::load_options_cmdline(loaded_image.load_options_as_bytes()?)
+}
+
 // -----------------
 // MEMORY MAP
 // -----------------
@@ -74,56 +122,43 @@ pub fn get_mem_map(bt: &BootServices) -> MemoryMapEFI {
     m
 }
 
-/// Physical RAM Regions that can be directly mapped as Standard Pages
-pub const EFI_FREE_MEMORY_REGIONS: &[MemoryType] = &[
-    MemoryType::LOADER_CODE,
-    MemoryType::LOADER_DATA,
-    MemoryType::BOOT_SERVICES_CODE,
-    MemoryType::BOOT_SERVICES_DATA,
-    MemoryType::CONVENTIONAL,
-    MemoryType::PERSISTENT_MEMORY,
-];
-
-/// Usable as Standard Pages after retrieving ACPI Tables and all of its data
-pub const EFI_RECLAIMABLE_MEMORY_REGIONS: &[MemoryType] =
-    &[MemoryType::ACPI_RECLAIM, MemoryType::ACPI_NON_VOLATILE];
-
-/// Memory Regions that shouldn't be used directly, but mapped by AllocateMemory() I think
-pub const EFI_ACPI_S_STATE_REGIONS: &[MemoryType] = &[
-    MemoryType::RUNTIME_SERVICES_CODE,
-    MemoryType::RUNTIME_SERVICES_DATA,
-];
-
-/// RAM Ranges that are hooked onto by the MMIO Controller, and need to be marked as pass-through, non-cacheable, with strong ordering, no buffering
-pub const EFI_MMIO_MEMORY_REGIONS: &[MemoryType] = &[MemoryType::MMIO_PORT_SPACE, MemoryType::MMIO];
-
-/// Export ArcMemory. NOTE: you should enable ACPI and retrieve the tables, and esp the MMIO APIs before using the regions
-pub fn create_arc_memory_from_uefi(mem_map: &MemoryMapEFI) -> MemoryMap {
-    let mut arc_memory_map = MemoryMap::new(vec![]);
-
-    for mem_region in mem_map {
-        // based on the type, make the MemoryRegion and add it to the map
-        let res = if EFI_FREE_MEMORY_REGIONS.contains(&mem_region.ty)
-            || EFI_FREE_MEMORY_REGIONS.contains(&mem_region.ty)
-        {
-            MemoryRegion::new(
-                MemoryRegionType::Standard,
-                address_range_4k(mem_region.phys_start, mem_region.page_count),
-            )
-        } else if EFI_ACPI_S_STATE_REGIONS.contains(&mem_region.ty) {
-            MemoryRegion::new(
-                MemoryRegionType::ACPI,
-                address_range_4k(mem_region.phys_start, mem_region.page_count),
-            )
-        } else {
-            MemoryRegion::new(
-                MemoryRegionType::Standard,
-                address_range_4k(mem_region.phys_start, mem_region.page_count),
-            )
-        };
-
-        arc_memory_map.push(res);
-    }
+/// In the firmware neutral form memory::map works on
+pub fn firmware_descriptor(descriptor: &MemoryDescriptor) -> FirmwareDescriptor {
+    FirmwareDescriptor::new(
+        descriptor.ty.0,
+        descriptor.phys_start,
+        descriptor.page_count,
+        descriptor.att.bits(),
+    )
+}
+
+/// Export ArcMemory. `arcboot_allocations` are the ranges arcboot allocated itself (heap, boot frames, kernel...), they get marked as Bootloader
+/// NOTE: you should enable ACPI and retrieve the tables, and esp the MMIO APIs before using the regions
+/// Boot services memory becomes Standard, the kernel can have it. Allocate from create_boot_frames_from_uefi's map instead
+pub fn create_arc_memory_from_uefi(
+    mem_map: &[MemoryDescriptor],
+    arcboot_allocations: &[AddressRange],
+) -> MemoryMap {
+    normalise(
+        mem_map.iter().map(firmware_descriptor),
+        arcboot_allocations,
+        PAGE_SIZE,
+    )
+}
+
+/// What a FrameAllocator can use while arcboot still runs on the firmware's stack and TTBR0 tables. Like create_arc_memory_from_uefi, but boot services memory isnt free
+pub fn create_boot_frames_from_uefi(
+    mem_map: &[MemoryDescriptor],
+    arcboot_allocations: &[AddressRange],
+) -> MemoryMap {
+    boot_frames(
+        mem_map.iter().map(firmware_descriptor),
+        arcboot_allocations,
+        PAGE_SIZE,
+    )
+}
 
-    arc_memory_map
+/// The whole descriptor paddr is in, e.g. the one holding SP
+pub fn uefi_region_containing(mem_map: &[MemoryDescriptor], paddr: u64) -> Option<AddressRange> {
+    descriptor_containing(mem_map.iter().map(firmware_descriptor), paddr)
 }
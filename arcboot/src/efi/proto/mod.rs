@@ -63,7 +63,7 @@ mod loaded_image;
 mod media;
 mod network;
 mod pi;
-mod rng;
+pub mod rng;
 #[cfg(any(
     target_arch = "i386",
     target_arch = "x86_64",
@@ -2,6 +2,17 @@ use uefi::prelude::*;
 use uefi::proto::rng::{Rng, RngAlgorithmType};
 use uefi::table::boot::{BootServices, OpenProtocolAttributes, OpenProtocolParams};
 
+/// Get a random u64 from EFI_RNG_PROTOCOL using the firmware's default algorithm. None if there is no RNG or it failed
+pub fn random_u64(bt: &BootServices) -> Option<u64> {
+    let rng = bt.locate_protocol::<Rng>().ok()?;
+    let rng = unsafe { &mut *rng.get() };
+
+    let mut buf = [0u8; 8];
+    rng.get_rng(None, &mut buf).ok()?;
+
+    Some(u64::from_le_bytes(buf))
+}
+
 pub fn test(image: Handle, bt: &BootServices) {
     info!("Running rng protocol test");
 
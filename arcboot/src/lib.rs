@@ -27,6 +27,8 @@ pub mod logger;
 pub mod qemu;
 pub mod sync;
 pub mod boot;
+pub mod config;
+pub mod menu;
 pub mod memory;
 
 // ---------------
@@ -0,0 +1,373 @@
+// ---------------
+// FRAME ALLOCATOR
+// ---------------
+
+// Physical frames for page tables, kernel segments, the boot stack and heap
+// Keeps a sorted list of free ranges, built from the Standard regions of the memory map
+// What it handed out goes back to the kernel as BootAllocated regions, or whatever type it was allocated as (see allocate_as)
+
+use alloc::vec::Vec;
+use arcboot_api::{AddressRange, MemoryMap, MemoryRegion, MemoryRegionType};
+
+use super::PAGE_SIZE;
+
+/// Free list of physical frames
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameAllocator {
+    /// Sorted, non overlapping, non adjacent, page aligned. End exclusive
+    free: Vec<AddressRange>,
+    /// Allocations that arent BootAllocated, sorted
+    typed: Vec<(AddressRange, MemoryRegionType)>,
+    /// The free list new() started with. Frames outside it were never the allocator's to hand out
+    seeded: Vec<AddressRange>,
+}
+
+impl FrameAllocator {
+    /// Every Standard region of the memory map, shrunk to whole frames. Except frame 0, a paddr of 0 looks like a null pointer
+    /// Anything arcboot still uses, like the stack it runs on or the firmware's page tables, can't be Standard in memory_map
+    pub fn new(memory_map: &MemoryMap) -> Self {
+        let mut allocator = Self {
+            free: Vec::new(),
+            typed: Vec::new(),
+            seeded: Vec::new(),
+        };
+
+        for region in memory_map.regions() {
+            if !region.region_type().is_some_and(|t| t.is_usable()) {
+                continue;
+            }
+
+            let (start, end) = region.address_range();
+            let start = align_up(start, PAGE_SIZE).max(PAGE_SIZE);
+            let end = end & !(PAGE_SIZE - 1);
+            if start < end {
+                allocator.free(start, (end - start) / PAGE_SIZE);
+            }
+        }
+        allocator.seeded = allocator.free.clone();
+
+        allocator
+    }
+
+    /// Take a range out of the free list, e.g. the kernel image. False if some of it wasnt free
+    pub fn reserve(&mut self, range: AddressRange) -> bool {
+        let start = range.0 & !(PAGE_SIZE - 1);
+        let end = align_up(range.1, PAGE_SIZE);
+        if start >= end {
+            return true;
+        }
+
+        let fully_free = self.free.iter().any(|&(s, e)| s <= start && end <= e);
+        self.remove(start, end);
+
+        fully_free
+    }
+
+    /// One frame, not zeroed. None if out of memory
+    pub fn allocate(&mut self) -> Option<u64> {
+        self.allocate_contiguous(1, PAGE_SIZE)
+    }
+
+    /// `n_frames` physically contiguous frames starting at a multiple of `align` (a power of 2, at least PAGE_SIZE)
+    pub fn allocate_contiguous(&mut self, n_frames: u64, align: u64) -> Option<u64> {
+        let align = align.max(PAGE_SIZE);
+        let size = n_frames.checked_mul(PAGE_SIZE)?;
+
+        let start = self.free.iter().find_map(|&(s, e)| {
+            let start = align_up(s, align);
+            (start.checked_add(size)? <= e).then_some(start)
+        })?;
+        self.remove(start, start + size);
+
+        Some(start)
+    }
+
+    /// Like allocate_contiguous, but the frames show up as region_type in memory_map(), e.g. BootModule
+    pub fn allocate_as(
+        &mut self,
+        n_frames: u64,
+        align: u64,
+        region_type: MemoryRegionType,
+    ) -> Option<u64> {
+        let start = self.allocate_contiguous(n_frames, align)?;
+        let range = (start, start + n_frames * PAGE_SIZE);
+        let at = self.typed.partition_point(|&((s, _), _)| s < start);
+        self.typed.insert(at, (range, region_type));
+
+        Some(start)
+    }
+
+    /// Give frames back. Merges with neighbouring free ranges
+    pub fn free(&mut self, addr: u64, n_frames: u64) {
+        let start = addr & !(PAGE_SIZE - 1);
+        let end = start.saturating_add(n_frames.saturating_mul(PAGE_SIZE));
+        if start >= end {
+            return;
+        }
+
+        // first range that ends at or after start, anything before it stays untouched
+        let first = self.free.partition_point(|&(_, e)| e < start);
+        let mut merged = (start, end);
+        let mut last = first;
+        while last < self.free.len() && self.free[last].0 <= merged.1 {
+            merged = (
+                merged.0.min(self.free[last].0),
+                merged.1.max(self.free[last].1),
+            );
+            last += 1;
+        }
+
+        self.free.splice(first..last, [merged]);
+    }
+
+    /// Number of free frames left
+    pub fn free_frames(&self) -> u64 {
+        self.free.iter().map(|(s, e)| (e - s) / PAGE_SIZE).sum()
+    }
+
+    /// The free ranges, sorted
+    pub fn free_ranges(&self) -> &[AddressRange] {
+        &self.free
+    }
+
+    /// Hand the allocator's state to the kernel. `memory_map` with every Standard frame that was handed out marked as BootAllocated
+    /// Standard frames the allocator was never given, like frame 0 or the firmware's boot services memory, stay Standard
+    pub fn memory_map(&self, memory_map: &MemoryMap) -> MemoryMap {
+        let mut res = MemoryMap::default();
+        let free = self.given_back();
+
+        for region in memory_map.regions() {
+            if !region.region_type().is_some_and(|t| t.is_usable()) {
+                res.push(*region);
+                continue;
+            }
+
+            // walk the region, alternating between allocated gaps and free ranges
+            let (start, end) = region.address_range();
+            let mut cursor = start;
+            for &(free_start, free_end) in &free {
+                let free_start = free_start.max(start);
+                let free_end = free_end.min(end);
+                if free_start >= free_end {
+                    continue;
+                }
+
+                if cursor < free_start {
+                    self.push_allocated(&mut res, cursor, free_start);
+                }
+                res.push(MemoryRegion::new(
+                    MemoryRegionType::Standard,
+                    (free_start, free_end),
+                ));
+                cursor = free_end;
+            }
+            if cursor < end {
+                self.push_allocated(&mut res, cursor, end);
+            }
+        }
+
+        res
+    }
+
+    /// The free list, plus everything outside what new() started with. Sorted and merged
+    fn given_back(&self) -> Vec<AddressRange> {
+        let mut ranges = self.free.clone();
+        let mut cursor = 0;
+        for &(start, end) in &self.seeded {
+            if cursor < start {
+                ranges.push((cursor, start));
+            }
+            cursor = end;
+        }
+        ranges.push((cursor, u64::MAX));
+        ranges.sort_unstable();
+
+        let mut res: Vec<AddressRange> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match res.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => res.push((start, end)),
+            }
+        }
+
+        res
+    }
+
+    /// start..end was handed out. BootAllocated, apart from what allocate_as typed
+    fn push_allocated(&self, memory_map: &mut MemoryMap, start: u64, end: u64) {
+        let mut cursor = start;
+        for &((typed_start, typed_end), region_type) in &self.typed {
+            let typed_start = typed_start.max(cursor);
+            let typed_end = typed_end.min(end);
+            if typed_start >= typed_end {
+                continue;
+            }
+
+            if cursor < typed_start {
+                memory_map.push(MemoryRegion::new(
+                    MemoryRegionType::BootAllocated,
+                    (cursor, typed_start),
+                ));
+            }
+            memory_map.push(MemoryRegion::new(region_type, (typed_start, typed_end)));
+            cursor = typed_end;
+        }
+        if cursor < end {
+            memory_map.push(MemoryRegion::new(
+                MemoryRegionType::BootAllocated,
+                (cursor, end),
+            ));
+        }
+    }
+
+    /// Remove start..end from the free list, splitting ranges it cuts through
+    fn remove(&mut self, start: u64, end: u64) {
+        let mut res = Vec::with_capacity(self.free.len() + 1);
+        for &(s, e) in &self.free {
+            if e <= start || end <= s {
+                res.push((s, e));
+                continue;
+            }
+            if s < start {
+                res.push((s, start));
+            }
+            if end < e {
+                res.push((end, e));
+            }
+        }
+
+        self.free = res;
+    }
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    addr.saturating_add(align - 1) & !(align - 1)
+}
+
+// ---------------
+// TEST
+// ---------------
+
+#[cfg(test)]
+fn test_memory_map() -> MemoryMap {
+    MemoryMap::new(alloc::vec![
+        MemoryRegion::new(MemoryRegionType::Standard, (0x0, 0x4000)),
+        MemoryRegion::new(MemoryRegionType::MMIO, (0x0900_0000, 0x0900_1000)),
+        MemoryRegion::new(MemoryRegionType::Bootloader, (0x4000_0000, 0x4010_0000)),
+        MemoryRegion::new(MemoryRegionType::Standard, (0x4010_0000, 0x4040_0000)),
+    ])
+}
+
+#[test]
+fn test_frame_allocator() {
+    let mut frames = FrameAllocator::new(&test_memory_map());
+
+    // Bootloader and MMIO arent free, and neither is frame 0
+    assert_eq!(
+        frames.free_ranges(),
+        [(0x1000, 0x4000), (0x4010_0000, 0x4040_0000)]
+    );
+    assert_eq!(frames.free_frames(), 3 + 0x300);
+
+    assert_eq!(frames.allocate(), Some(0x1000));
+
+    // 2 MiB aligned, skips the small range and the start of the big one
+    assert_eq!(
+        frames.allocate_contiguous(0x200, 0x20_0000),
+        Some(0x4020_0000)
+    );
+    assert_eq!(frames.allocate_contiguous(0x200, 0x20_0000), None);
+    assert_eq!(
+        frames.free_ranges(),
+        [(0x2000, 0x4000), (0x4010_0000, 0x4020_0000)]
+    );
+
+    // kernel image, partially allocated already
+    assert!(frames.reserve((0x4010_0000, 0x4010_1800)));
+    assert!(!frames.reserve((0x401F_F000, 0x4020_1000)));
+    assert_eq!(
+        frames.free_ranges(),
+        [(0x2000, 0x4000), (0x4010_2000, 0x401F_F000)]
+    );
+
+    // freeing merges back into one range
+    frames.free(0x401F_F000, 0x201);
+    frames.free(0x4010_0000, 2);
+    assert_eq!(
+        frames.free_ranges(),
+        [(0x2000, 0x4000), (0x4010_0000, 0x4040_0000)]
+    );
+}
+
+#[test]
+fn test_frame_allocator_handoff() {
+    let memory_map = test_memory_map();
+    let mut frames = FrameAllocator::new(&memory_map);
+    frames.allocate();
+    frames.allocate_contiguous(0x10, 0x10_0000);
+    // a module right after the 16 frames, and one on its own
+    assert_eq!(
+        frames.allocate_as(2, 0x1_0000, MemoryRegionType::BootModule),
+        Some(0x4011_0000)
+    );
+    assert_eq!(
+        frames.allocate_as(1, 0x20_0000, MemoryRegionType::BootModule),
+        Some(0x4020_0000)
+    );
+
+    let handoff = frames.memory_map(&memory_map);
+    let regions: Vec<_> = handoff
+        .regions()
+        .iter()
+        .map(|r| (r.address_range(), r.region_type().unwrap()))
+        .collect();
+
+    use MemoryRegionType::*;
+    assert_eq!(
+        regions,
+        [
+            ((0x0, 0x1000), Standard),
+            ((0x1000, 0x2000), BootAllocated),
+            ((0x2000, 0x4000), Standard),
+            ((0x0900_0000, 0x0900_1000), MMIO),
+            ((0x4000_0000, 0x4010_0000), Bootloader),
+            ((0x4010_0000, 0x4011_0000), BootAllocated),
+            ((0x4011_0000, 0x4011_2000), BootModule),
+            ((0x4011_2000, 0x4020_0000), Standard),
+            ((0x4020_0000, 0x4020_1000), BootModule),
+            ((0x4020_1000, 0x4040_0000), Standard),
+        ]
+    );
+}
+
+#[test]
+fn test_frames_never_seeded() {
+    use MemoryRegionType::*;
+
+    // the kernel gets 0x4000_0000..0x4040_0000 as Standard, but only the top half was free while arcboot ran
+    let kernel_map = MemoryMap::new(alloc::vec![MemoryRegion::new(
+        Standard,
+        (0x4000_0000, 0x4040_0000)
+    )]);
+    let boot_map = MemoryMap::new(alloc::vec![
+        MemoryRegion::new(Bootloader, (0x4000_0000, 0x4020_0000)),
+        MemoryRegion::new(Standard, (0x4020_0000, 0x4040_0000)),
+    ]);
+    let mut frames = FrameAllocator::new(&boot_map);
+    assert_eq!(frames.allocate(), Some(0x4020_0000));
+
+    let regions: Vec<_> = frames
+        .memory_map(&kernel_map)
+        .regions()
+        .iter()
+        .map(|r| (r.address_range(), r.region_type().unwrap()))
+        .collect();
+    assert_eq!(
+        regions,
+        [
+            ((0x4000_0000, 0x4020_0000), Standard),
+            ((0x4020_0000, 0x4020_1000), BootAllocated),
+            ((0x4020_1000, 0x4040_0000), Standard),
+        ]
+    );
+}
@@ -0,0 +1,484 @@
+// ---------------
+// FIRMWARE MEMORY MAP -> ARC MEMORY MAP
+// ---------------
+
+// Turns the firmware's memory map into the MemoryMap handed to the kernel
+// Every region gets a type, overlaps are resolved (the more restrictive type wins), arcboot's own allocations are carved out,
+// then the result is sorted and merged. Arch and firmware neutral so it can be tested on the host
+
+use alloc::vec::Vec;
+use arcboot_api::{AddressRange, MemoryMap, MemoryRegion, MemoryRegionType};
+
+/// UEFI pages are always 4K, whatever granule the kernel ends up using
+pub const EFI_PAGE_SIZE: u64 = 4096;
+
+// EFI_MEMORY_TYPE
+
+pub const EFI_RESERVED_MEMORY_TYPE: u32 = 0;
+pub const EFI_LOADER_CODE: u32 = 1;
+pub const EFI_LOADER_DATA: u32 = 2;
+pub const EFI_BOOT_SERVICES_CODE: u32 = 3;
+pub const EFI_BOOT_SERVICES_DATA: u32 = 4;
+pub const EFI_RUNTIME_SERVICES_CODE: u32 = 5;
+pub const EFI_RUNTIME_SERVICES_DATA: u32 = 6;
+pub const EFI_CONVENTIONAL_MEMORY: u32 = 7;
+pub const EFI_UNUSABLE_MEMORY: u32 = 8;
+pub const EFI_ACPI_RECLAIM_MEMORY: u32 = 9;
+pub const EFI_ACPI_MEMORY_NVS: u32 = 10;
+pub const EFI_MEMORY_MAPPED_IO: u32 = 11;
+pub const EFI_MEMORY_MAPPED_IO_PORT_SPACE: u32 = 12;
+pub const EFI_PAL_CODE: u32 = 13;
+pub const EFI_PERSISTENT_MEMORY: u32 = 14;
+
+// EFI memory attributes we care about
+
+/// Write back cacheable. RAM without it cant be used as normal memory
+pub const EFI_MEMORY_WB: u64 = 0x8;
+/// Specific purpose memory (e.g. HBM). Left for drivers, not general allocation
+pub const EFI_MEMORY_SP: u64 = 0x40000;
+/// Needed by runtime services, has to stay mapped after ExitBootServices
+pub const EFI_MEMORY_RUNTIME: u64 = 0x8000_0000_0000_0000;
+
+/// One entry of the firmware's memory map, as UEFI describes it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirmwareDescriptor {
+    /// EFI_MEMORY_TYPE
+    pub ty: u32,
+    pub phys_start: u64,
+    /// In EFI_PAGE_SIZE pages
+    pub page_count: u64,
+    /// EFI_MEMORY_* attribute bits
+    pub attribute: u64,
+}
+
+impl FirmwareDescriptor {
+    pub const fn new(ty: u32, phys_start: u64, page_count: u64, attribute: u64) -> Self {
+        Self {
+            ty,
+            phys_start,
+            page_count,
+            attribute,
+        }
+    }
+
+    /// Exclusive end. Saturates instead of wrapping for broken descriptors
+    pub fn end(&self) -> u64 {
+        self.phys_start
+            .saturating_add(self.page_count.saturating_mul(EFI_PAGE_SIZE))
+    }
+
+    /// What the kernel should think of this memory
+    pub fn region_type(&self) -> MemoryRegionType {
+        use MemoryRegionType::*;
+
+        let region_type = match self.ty {
+            EFI_LOADER_CODE | EFI_LOADER_DATA => Bootloader,
+            EFI_BOOT_SERVICES_CODE | EFI_BOOT_SERVICES_DATA | EFI_CONVENTIONAL_MEMORY => Standard,
+            EFI_RUNTIME_SERVICES_CODE | EFI_RUNTIME_SERVICES_DATA => Runtime,
+            EFI_ACPI_RECLAIM_MEMORY => AcpiReclaimable,
+            EFI_ACPI_MEMORY_NVS => ACPI,
+            EFI_MEMORY_MAPPED_IO | EFI_MEMORY_MAPPED_IO_PORT_SPACE => MMIO,
+            EFI_PERSISTENT_MEMORY => Persistent,
+            // reserved, unusable, PAL code, unaccepted, OEM and OS defined types
+            _ => Reserved,
+        };
+
+        match region_type {
+            // RAM the firmware still needs at runtime, whatever its type says
+            Standard | Bootloader | AcpiReclaimable if self.attribute & EFI_MEMORY_RUNTIME != 0 => {
+                Runtime
+            }
+            Standard if self.attribute & (EFI_MEMORY_WB | EFI_MEMORY_SP) != EFI_MEMORY_WB => {
+                Reserved
+            }
+            region_type => region_type,
+        }
+    }
+
+    /// What arcboot can allocate from while it still runs on the firmware. Boot services memory holds the stack and the page tables TTBR0 points at, so it isnt free yet
+    pub fn boot_region_type(&self) -> MemoryRegionType {
+        match self.ty {
+            EFI_BOOT_SERVICES_CODE | EFI_BOOT_SERVICES_DATA => MemoryRegionType::Bootloader,
+            _ => self.region_type(),
+        }
+    }
+}
+
+/// When regions overlap, the higher one wins. Carve outs are Bootloader, so they take over free RAM but never firmware memory
+fn priority(region_type: MemoryRegionType) -> u8 {
+    use MemoryRegionType::*;
+
+    match region_type {
+        Standard => 0,
+        Persistent => 1,
+        Bootloader => 2,
+        BootAllocated | BootModule => 3,
+        AcpiReclaimable => 4,
+        ACPI => 5,
+        Runtime => 6,
+        MMIO => 7,
+        Reserved => 8,
+    }
+}
+
+/// Build the kernel's memory map
+/// `carve_outs` are arcboot's own allocations (heap, boot frames, kernel image...) and become Bootloader regions
+/// `page_size` is the kernel's granule, Standard regions get trimmed to it so the kernel never gets a partial page
+pub fn normalise(
+    descriptors: impl IntoIterator<Item = FirmwareDescriptor>,
+    carve_outs: &[AddressRange],
+    page_size: u64,
+) -> MemoryMap {
+    normalise_by(
+        descriptors,
+        carve_outs,
+        page_size,
+        FirmwareDescriptor::region_type,
+    )
+}
+
+/// Like normalise, but for the FrameAllocator arcboot uses before the kernel runs. Boot services memory is Bootloader, see boot_region_type
+/// The kernel still gets it as Standard, FrameAllocator::memory_map only marks what was handed out
+pub fn boot_frames(
+    descriptors: impl IntoIterator<Item = FirmwareDescriptor>,
+    carve_outs: &[AddressRange],
+    page_size: u64,
+) -> MemoryMap {
+    normalise_by(
+        descriptors,
+        carve_outs,
+        page_size,
+        FirmwareDescriptor::boot_region_type,
+    )
+}
+
+fn normalise_by(
+    descriptors: impl IntoIterator<Item = FirmwareDescriptor>,
+    carve_outs: &[AddressRange],
+    page_size: u64,
+    region_type: impl Fn(&FirmwareDescriptor) -> MemoryRegionType,
+) -> MemoryMap {
+    let mut pieces: Vec<(u64, u64, MemoryRegionType)> = descriptors
+        .into_iter()
+        .map(|d| (d.phys_start, d.end(), region_type(&d)))
+        .collect();
+    pieces.extend(carve_outs.iter().map(|&(start, end)| {
+        (
+            start & !(EFI_PAGE_SIZE - 1),
+            end.saturating_add(EFI_PAGE_SIZE - 1) & !(EFI_PAGE_SIZE - 1),
+            MemoryRegionType::Bootloader,
+        )
+    }));
+    pieces.retain(|(start, end, _)| start < end);
+
+    // every address where some region starts or ends. Between two of them, the same regions apply throughout
+    let mut boundaries: Vec<u64> = pieces.iter().flat_map(|&(s, e, _)| [s, e]).collect();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    let mut regions: Vec<(u64, u64, MemoryRegionType)> = Vec::new();
+    for window in boundaries.windows(2) {
+        let (start, end) = (window[0], window[1]);
+
+        // a hole in the map if nothing covers it
+        let region_type = match pieces
+            .iter()
+            .filter(|(s, e, _)| *s <= start && end <= *e)
+            .map(|(_, _, t)| *t)
+            .max_by_key(|t| priority(*t))
+        {
+            Some(t) => t,
+            None => continue,
+        };
+
+        match regions.last_mut() {
+            Some(last) if last.1 == start && last.2 == region_type => last.1 = end,
+            _ => regions.push((start, end, region_type)),
+        }
+    }
+
+    let mut memory_map = MemoryMap::default();
+    for (start, end, region_type) in regions {
+        let (start, end) = if region_type.is_usable() {
+            (
+                start.saturating_add(page_size - 1) & !(page_size - 1),
+                end & !(page_size - 1),
+            )
+        } else {
+            (start, end)
+        };
+
+        if start < end {
+            memory_map.push(MemoryRegion::new(region_type, (start, end)));
+        }
+    }
+
+    memory_map
+}
+
+/// The descriptor paddr is in, e.g. the stack arcboot runs on. That's usually BOOT_SERVICES_DATA, which becomes Standard unless it's carved out
+pub fn descriptor_containing(
+    descriptors: impl IntoIterator<Item = FirmwareDescriptor>,
+    paddr: u64,
+) -> Option<AddressRange> {
+    descriptors
+        .into_iter()
+        .find(|d| d.phys_start <= paddr && paddr < d.end())
+        .map(|d| (d.phys_start, d.end()))
+}
+
+/// The regions `include` picks, rounded out to whole pages then sorted and merged. What the direct map and MMIO window get built from
+pub fn page_ranges(
+    memory_map: &MemoryMap,
+    include: impl Fn(MemoryRegionType) -> bool,
+    page_size: u64,
+) -> Vec<AddressRange> {
+    let mut ranges: Vec<AddressRange> = memory_map
+        .regions()
+        .iter()
+        .filter(|region| region.region_type().is_some_and(&include))
+        .map(|region| {
+            let (start, end) = region.address_range();
+            (
+                start & !(page_size - 1),
+                end.saturating_add(page_size - 1) & !(page_size - 1),
+            )
+        })
+        .filter(|(start, end)| start < end)
+        .collect();
+    ranges.sort_unstable();
+
+    // rounding out can make neighbours overlap
+    let mut res: Vec<AddressRange> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match res.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => res.push((start, end)),
+        }
+    }
+
+    res
+}
+
+// ---------------
+// TEST
+// ---------------
+
+#[cfg(test)]
+const RAM: u64 = 0xF | EFI_MEMORY_WB;
+#[cfg(test)]
+const UC_RUNTIME: u64 = 0x1 | EFI_MEMORY_RUNTIME;
+
+/// OVMF (ArmVirtQemu) on QEMU virt, 256 MiB of RAM at 0x4000_0000, arcboot's heap already allocated at the start of RAM
+#[cfg(test)]
+const OVMF_AARCH64_MAP: &[FirmwareDescriptor] = &[
+    FirmwareDescriptor::new(EFI_MEMORY_MAPPED_IO, 0x0400_0000, 0x4000, UC_RUNTIME),
+    FirmwareDescriptor::new(EFI_MEMORY_MAPPED_IO, 0x0901_0000, 0x1, UC_RUNTIME),
+    FirmwareDescriptor::new(EFI_LOADER_DATA, 0x4000_0000, 0x4000, RAM),
+    FirmwareDescriptor::new(EFI_CONVENTIONAL_MEMORY, 0x4400_0000, 0x8000, RAM),
+    FirmwareDescriptor::new(EFI_BOOT_SERVICES_DATA, 0x4C00_0000, 0x2000, RAM),
+    FirmwareDescriptor::new(EFI_ACPI_RECLAIM_MEMORY, 0x4E00_0000, 0x10, RAM),
+    FirmwareDescriptor::new(
+        EFI_RUNTIME_SERVICES_DATA,
+        0x4E01_0000,
+        0x100,
+        RAM | EFI_MEMORY_RUNTIME,
+    ),
+    FirmwareDescriptor::new(
+        EFI_RUNTIME_SERVICES_CODE,
+        0x4E11_0000,
+        0x50,
+        RAM | EFI_MEMORY_RUNTIME,
+    ),
+    FirmwareDescriptor::new(EFI_LOADER_CODE, 0x4E16_0000, 0x40, RAM),
+    FirmwareDescriptor::new(EFI_BOOT_SERVICES_CODE, 0x4E1A_0000, 0x1000, RAM),
+    FirmwareDescriptor::new(EFI_CONVENTIONAL_MEMORY, 0x4F1A_0000, 0xE00, RAM),
+    FirmwareDescriptor::new(EFI_BOOT_SERVICES_DATA, 0x4FFA_0000, 0x60, RAM),
+];
+
+/// OVMF on QEMU q35 (x86_64), 128 MiB. Unsorted, with the legacy holes below 1 MiB
+#[cfg(test)]
+const OVMF_X86_64_MAP: &[FirmwareDescriptor] = &[
+    FirmwareDescriptor::new(EFI_BOOT_SERVICES_CODE, 0x0000_0000, 0x1, RAM),
+    FirmwareDescriptor::new(EFI_CONVENTIONAL_MEMORY, 0x0000_1000, 0x9F, RAM),
+    FirmwareDescriptor::new(EFI_CONVENTIONAL_MEMORY, 0x0010_0000, 0x700, RAM),
+    FirmwareDescriptor::new(EFI_ACPI_MEMORY_NVS, 0x0080_0000, 0x8, RAM),
+    FirmwareDescriptor::new(EFI_CONVENTIONAL_MEMORY, 0x0080_8000, 0x8, RAM),
+    FirmwareDescriptor::new(EFI_ACPI_MEMORY_NVS, 0x0081_0000, 0xF0, RAM),
+    FirmwareDescriptor::new(EFI_BOOT_SERVICES_DATA, 0x0090_0000, 0x7000, RAM),
+    FirmwareDescriptor::new(EFI_CONVENTIONAL_MEMORY, 0x0790_0000, 0x200, RAM),
+    FirmwareDescriptor::new(EFI_RESERVED_MEMORY_TYPE, 0x07B0_0000, 0x300, RAM),
+    FirmwareDescriptor::new(EFI_ACPI_RECLAIM_MEMORY, 0x07E0_0000, 0x10, RAM),
+    FirmwareDescriptor::new(
+        EFI_RUNTIME_SERVICES_DATA,
+        0x07E1_0000,
+        0x1F0,
+        RAM | EFI_MEMORY_RUNTIME,
+    ),
+    FirmwareDescriptor::new(EFI_MEMORY_MAPPED_IO, 0xFFC0_0000, 0x400, UC_RUNTIME),
+    FirmwareDescriptor::new(EFI_RESERVED_MEMORY_TYPE, 0xB000_0000, 0x10000, 0),
+];
+
+#[cfg(test)]
+fn region_list(memory_map: &MemoryMap) -> Vec<(u64, u64, MemoryRegionType)> {
+    memory_map
+        .regions()
+        .iter()
+        .map(|r| {
+            let (start, end) = r.address_range();
+            (start, end, r.region_type().unwrap())
+        })
+        .collect()
+}
+
+#[test]
+fn test_ovmf_aarch64_map() {
+    use MemoryRegionType::*;
+
+    let memory_map = normalise(OVMF_AARCH64_MAP.iter().copied(), &[], 0x1000);
+
+    assert_eq!(
+        region_list(&memory_map),
+        [
+            (0x0400_0000, 0x0800_0000, MMIO),
+            (0x0901_0000, 0x0901_1000, MMIO),
+            (0x4000_0000, 0x4400_0000, Bootloader),
+            (0x4400_0000, 0x4E00_0000, Standard),
+            (0x4E00_0000, 0x4E01_0000, AcpiReclaimable),
+            (0x4E01_0000, 0x4E16_0000, Runtime),
+            (0x4E16_0000, 0x4E1A_0000, Bootloader),
+            (0x4E1A_0000, 0x5000_0000, Standard),
+        ]
+    );
+}
+
+#[test]
+fn test_ovmf_x86_64_map() {
+    use MemoryRegionType::*;
+
+    let memory_map = normalise(OVMF_X86_64_MAP.iter().copied(), &[], 0x1000);
+
+    assert_eq!(
+        region_list(&memory_map),
+        [
+            (0x0000_0000, 0x000A_0000, Standard),
+            (0x0010_0000, 0x0080_0000, Standard),
+            (0x0080_0000, 0x0080_8000, ACPI),
+            (0x0080_8000, 0x0081_0000, Standard),
+            (0x0081_0000, 0x0090_0000, ACPI),
+            (0x0090_0000, 0x07B0_0000, Standard),
+            (0x07B0_0000, 0x07E0_0000, Reserved),
+            (0x07E0_0000, 0x07E1_0000, AcpiReclaimable),
+            (0x07E1_0000, 0x0800_0000, Runtime),
+            (0xB000_0000, 0xC000_0000, Reserved),
+            (0xFFC0_0000, 0x1_0000_0000, MMIO),
+        ]
+    );
+}
+
+#[test]
+fn test_carve_outs_and_overlaps() {
+    use MemoryRegionType::*;
+
+    // kernel frames in the middle of free RAM, and a carve out that runs into runtime memory
+    let carve_outs = [(0x4800_0000, 0x4800_2800), (0x4E00_F000, 0x4E01_1000)];
+    let memory_map = normalise(OVMF_AARCH64_MAP.iter().copied(), &carve_outs, 0x1000);
+    let regions = region_list(&memory_map);
+
+    assert!(regions.contains(&(0x4400_0000, 0x4800_0000, Standard)));
+    assert!(regions.contains(&(0x4800_0000, 0x4800_3000, Bootloader)));
+    assert!(regions.contains(&(0x4800_3000, 0x4E00_0000, Standard)));
+    // firmware memory wins over the carve out
+    assert!(regions.contains(&(0x4E00_0000, 0x4E01_0000, AcpiReclaimable)));
+    assert!(regions.contains(&(0x4E01_0000, 0x4E16_0000, Runtime)));
+
+    // overlapping firmware regions, an unknown type, RAM without WB, and a 64K granule
+    let broken = [
+        FirmwareDescriptor::new(EFI_CONVENTIONAL_MEMORY, 0x4000_0000, 0x100, RAM),
+        FirmwareDescriptor::new(EFI_RESERVED_MEMORY_TYPE, 0x4008_0000, 0x10, 0),
+        FirmwareDescriptor::new(0x7000_0001, 0x4010_0000, 0x10, RAM),
+        FirmwareDescriptor::new(EFI_CONVENTIONAL_MEMORY, 0x4020_0000, 0x10, 0x1),
+        FirmwareDescriptor::new(EFI_CONVENTIONAL_MEMORY, 0x4030_1000, 0x20, RAM),
+        FirmwareDescriptor::new(EFI_CONVENTIONAL_MEMORY, 0x4040_0000, 0, RAM),
+    ];
+    let memory_map = normalise(broken.iter().copied(), &[], 0x10000);
+
+    assert_eq!(
+        region_list(&memory_map),
+        [
+            (0x4000_0000, 0x4008_0000, Standard),
+            (0x4008_0000, 0x4009_0000, Reserved),
+            (0x4009_0000, 0x4010_0000, Standard),
+            (0x4010_0000, 0x4011_0000, Reserved),
+            (0x4020_0000, 0x4021_0000, Reserved),
+            // 0x4030_1000..0x4032_1000, trimmed to 64K pages
+            (0x4031_0000, 0x4032_0000, Standard),
+        ]
+    );
+}
+
+#[test]
+fn test_stack_carve_out() {
+    use MemoryRegionType::*;
+
+    // the stack is at the top of the last BOOT_SERVICES_DATA
+    let sp = 0x4FFF_FE40;
+    let stack = descriptor_containing(OVMF_AARCH64_MAP.iter().copied(), sp).unwrap();
+    assert_eq!(stack, (0x4FFA_0000, 0x5000_0000));
+    assert_eq!(
+        descriptor_containing(OVMF_AARCH64_MAP.iter().copied(), 0x5000_0000),
+        None
+    );
+
+    let memory_map = normalise(OVMF_AARCH64_MAP.iter().copied(), &[stack], 0x1000);
+    let regions = region_list(&memory_map);
+    assert!(regions.contains(&(0x4FFA_0000, 0x5000_0000, Bootloader)));
+    // the rest of the firmware's memory is still free
+    assert!(regions.contains(&(0x4E1A_0000, 0x4FFA_0000, Standard)));
+}
+
+#[test]
+fn test_boot_frames() {
+    use super::frame::FrameAllocator;
+    use MemoryRegionType::*;
+
+    let boot = boot_frames(OVMF_AARCH64_MAP.iter().copied(), &[], 0x1000);
+    let regions = region_list(&boot);
+    // the firmware's memory isnt free while arcboot runs on it
+    assert!(regions.contains(&(0x4C00_0000, 0x4E00_0000, Bootloader)));
+    assert!(regions.contains(&(0x4E16_0000, 0x4F1A_0000, Bootloader)));
+    assert!(regions.contains(&(0x4F1A_0000, 0x4FFA_0000, Standard)));
+    assert!(regions.contains(&(0x4FFA_0000, 0x5000_0000, Bootloader)));
+
+    // but the kernel gets it as Standard
+    let kernel = normalise(OVMF_AARCH64_MAP.iter().copied(), &[], 0x1000);
+    let mut frames = FrameAllocator::new(&boot);
+    frames.allocate();
+    let regions = region_list(&frames.memory_map(&kernel));
+    assert!(regions.contains(&(0x4400_0000, 0x4400_1000, BootAllocated)));
+    assert!(regions.contains(&(0x4400_1000, 0x4E00_0000, Standard)));
+    assert!(regions.contains(&(0x4E1A_0000, 0x5000_0000, Standard)));
+}
+
+#[test]
+fn test_page_ranges() {
+    use MemoryRegionType::*;
+
+    let memory_map = MemoryMap::new(alloc::vec![
+        MemoryRegion::new(Standard, (0x4000_0000, 0x4800_0000)),
+        MemoryRegion::new(Runtime, (0x4800_0000, 0x4800_0800)),
+        MemoryRegion::new(ACPI, (0x4800_0800, 0x4800_2000)),
+        MemoryRegion::new(MMIO, (0x0900_0000, 0x0900_0100)),
+        MemoryRegion::new(Reserved, (0x4900_0000, 0x4A00_0000)),
+        MemoryRegion::new(Persistent, (0x6000_0000, 0x6010_0000)),
+    ]);
+
+    assert_eq!(
+        page_ranges(&memory_map, |t| t.is_ram(), 0x1000),
+        [(0x4000_0000, 0x4800_2000), (0x6000_0000, 0x6010_0000)]
+    );
+    assert_eq!(
+        page_ranges(&memory_map, |t| t == MMIO, 0x1000),
+        [(0x0900_0000, 0x0900_1000)]
+    );
+}
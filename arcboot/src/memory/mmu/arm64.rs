@@ -0,0 +1,1033 @@
+// ---------------
+// AARCH64 PAGE TABLES
+// ---------------
+
+// 4K, 16K and 64K granules with 48 or 52 bit VAs, picked at runtime. Not behind target_arch so the walk can be tested on the host
+//
+// granule  VA  levels   blocks   contiguous run
+// 4K       48  L0-L3    1G, 2M   16 pages
+// 4K       52  L-1-L3   1G, 2M   16 pages   LPA2
+// 16K      48  L0-L3    32M      128 pages
+// 16K      52  L0-L3    32M      128 pages  LPA2
+// 64K      48  L1-L3    512M     32 pages
+// 64K      52  L1-L3    512M     32 pages   LVA, plus LPA for 52 bit PAs
+//
+// Levels are counted from the root as depths here, since the root can be L-1, L0 or L1
+
+pub use arcboot_api::mmu::Granule;
+use arcboot_api::mmu::{ATTR_INDEX_DEVICE, ATTR_INDEX_NORMAL};
+use bitfield::bitfield;
+
+use super::{MapError, PageMapper, PhysMemory};
+use crate::memory::{frame::FrameAllocator, PageFlags, PAGE_SIZE};
+
+// ------------------
+// DESCRIPTORS
+// ------------------
+
+// NOTE: set each field to a repr(C) enum
+// The attribute bits are the same for every granule. Only where the address goes changes, see TranslationMode::encode_addr
+// The address fields here are the 4K, 48 bit ones
+
+// 4K => starts with 1's
+bitfield! {
+    pub struct TableDescriptor4K(u64);
+    impl Debug;
+    u64;
+    pub ns_table, set_ns_table: 63;
+    pub ap_table, set_ap_table: 62, 61;
+    pub xn_table, set_xn_table: 60;
+    pub pxn_table, set_pxn_table: 59;
+    pub next_lvl_table_addr, set_next_lvl_table_addr: 47, 12;
+    pub id_one, set_one: 1;
+    pub valid, set_valid :0;
+}
+
+// 4K => starts with 0's
+bitfield! {
+    pub struct BlockDescriptor4K(u64);
+    impl Debug;
+    u64;
+    pub custom, set_custom: 58, 55;
+    pub uxn, set_uxn: 54;
+    pub pxn, set_pxn: 53;
+    pub contig, set_contig: 52;
+    pub output_addr, set_output_addr: 47, 12;
+    pub zeroes, set_zeroes: 1, 0;
+    pub non_gathering, set_non_gathering: 11;
+    pub access_flag, set_access_flag: 10;
+    pub shared, set_shared: 9, 8;
+    pub access_permissions, set_access_permissions: 7, 6;
+    pub non_secure, set_non_secure: 5;
+    pub index_into_mair, set_index_into_mair: 4, 2;
+    pub id_zero, set_zero: 1;
+    pub valid, set_valid: 0;
+}
+
+pub fn default_unmapped_table_descriptor() -> TableDescriptor4K {
+    let mut res = TableDescriptor4K(0);
+    // just in case, it should be 0 anway
+    res.set_valid(false);
+
+    res
+}
+
+pub fn default_unmapped_block_descriptor() -> BlockDescriptor4K {
+    let mut res = BlockDescriptor4K(0);
+    res.set_valid(false);
+
+    res
+}
+
+/// MAIR_EL1 attribute indices, see arcboot_api::mmu::ARCBOOT_ATTRIBUTES
+pub const MAIR_DEVICE_INDEX: u64 = ATTR_INDEX_DEVICE as u64;
+pub const MAIR_NORMAL_INDEX: u64 = ATTR_INDEX_NORMAL as u64;
+
+/// AP[2:1]. EL0 never gets access to kernel pages
+pub const AP_EL1_RW: u64 = 0b00;
+pub const AP_EL1_RO: u64 = 0b10;
+
+/// SH[1:0] for normal memory. Device memory is always outer shareable
+pub const SH_INNER_SHAREABLE: u64 = 0b11;
+
+/// The PageFlags a page or block descriptor was made with
+pub fn descriptor_flags(desc: &BlockDescriptor4K) -> PageFlags {
+    PageFlags::new(
+        desc.access_permissions() == AP_EL1_RW,
+        !desc.pxn(),
+        desc.index_into_mair() == MAIR_DEVICE_INDEX,
+    )
+}
+
+/// Block and contiguous run sizes with the 4K granule
+pub const L1_BLOCK_SIZE: u64 = 1 << 30;
+pub const L2_BLOCK_SIZE: u64 = 1 << 21;
+pub const CONTIGUOUS_PAGES: u64 = 16;
+
+/// Longest contiguous run of any granule, 16K's
+const MAX_CONTIGUOUS_ENTRIES: usize = 128;
+
+/// Bits hi..=lo set
+const fn bits(hi: u32, lo: u32) -> u64 {
+    (u64::MAX >> (63 - hi)) & !((1 << lo) - 1)
+}
+
+// ------------------
+// TRANSLATION MODES
+// ------------------
+
+/// Granule and VA size of a set of tables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TranslationMode {
+    granule: Granule,
+    va_bits: u32,
+}
+
+impl TranslationMode {
+    /// 4K, 48 bit. Every ARMv8 CPU arcboot has met does this
+    pub const DEFAULT: Self = Self {
+        granule: Granule::K4,
+        va_bits: 48,
+    };
+
+    pub fn new(granule: Granule, va_bits: u32) -> Result<Self, &'static str> {
+        if va_bits != 48 && va_bits != 52 {
+            return Err("Only 48 and 52 bit VAs are supported");
+        }
+
+        Ok(Self { granule, va_bits })
+    }
+
+    pub fn granule(&self) -> Granule {
+        self.granule
+    }
+
+    pub fn va_bits(&self) -> u32 {
+        self.va_bits
+    }
+
+    pub fn page_size(&self) -> u64 {
+        self.granule.size()
+    }
+
+    /// 52 bits with 4K or 16K. Moves OA[51:50] into bits[9:8] of descriptors, and shareability into TCR
+    pub fn lpa2(&self) -> bool {
+        self.va_bits == 52 && self.granule != Granule::K64
+    }
+
+    /// Levels of tables, root included
+    pub fn levels(&self) -> usize {
+        let shift = self.granule.shift();
+        (self.va_bits - shift).div_ceil(self.granule.bits_per_level()) as usize
+    }
+
+    /// Lowest VA bit the entries of a table at depth resolve. 0 is the root
+    pub fn level_shift(&self, depth: usize) -> u32 {
+        let below = (self.levels() - 1 - depth) as u32;
+        self.granule.shift() + below * self.granule.bits_per_level()
+    }
+
+    /// Bytes one entry of a table at depth maps
+    pub fn level_size(&self, depth: usize) -> u64 {
+        1 << self.level_shift(depth)
+    }
+
+    /// Index of vaddr in its table at depth. The root only resolves what is left of the VA
+    pub fn index(&self, vaddr: u64, depth: usize) -> u64 {
+        let shift = self.level_shift(depth);
+        let n_bits = if depth == 0 {
+            self.va_bits - shift
+        } else {
+            self.granule.bits_per_level()
+        };
+
+        (vaddr >> shift) & ((1 << n_bits) - 1)
+    }
+
+    /// Whether entries at depth can be blocks. Just the level above pages, and L1 with 4K
+    pub fn is_block_depth(&self, depth: usize) -> bool {
+        let leaf = self.levels() - 1;
+        depth + 1 == leaf || (self.granule == Granule::K4 && depth + 2 == leaf)
+    }
+
+    /// An output or next table address, as descriptor bits
+    pub fn encode_addr(&self, addr: u64) -> u64 {
+        let shift = self.granule.shift();
+        if self.lpa2() {
+            (addr & bits(49, shift)) | ((addr >> 50) & 0b11) << 8
+        } else if self.granule == Granule::K64 {
+            (addr & bits(47, 16)) | ((addr >> 48) & 0xF) << 12
+        } else {
+            addr & bits(47, shift)
+        }
+    }
+
+    /// The address in a descriptor
+    pub fn decode_addr(&self, desc: u64) -> u64 {
+        let shift = self.granule.shift();
+        if self.lpa2() {
+            (desc & bits(49, shift)) | ((desc >> 8) & 0b11) << 50
+        } else if self.granule == Granule::K64 {
+            (desc & bits(47, 16)) | ((desc >> 12) & 0xF) << 48
+        } else {
+            desc & bits(47, shift)
+        }
+    }
+
+    /// A table descriptor pointing at the next level's table
+    pub fn table_descriptor(&self, next_lvl_table_addr: u64) -> u64 {
+        let mut res = default_unmapped_table_descriptor();
+        // bits[1:0] = 0b11 means table. 0b01 would be a block
+        res.set_one(true);
+        res.set_valid(true);
+
+        res.0 | self.encode_addr(next_lvl_table_addr)
+    }
+
+    /// A valid page descriptor for output_addr, with AP, UXN/PXN and the MAIR index set from flags
+    pub fn page_descriptor(&self, output_addr: u64, flags: PageFlags) -> u64 {
+        let mut res = default_unmapped_block_descriptor();
+
+        res.set_access_permissions(if flags.writable { AP_EL1_RW } else { AP_EL1_RO });
+        // kernel pages are never executable from EL0, and only executable from EL1 if asked
+        res.set_uxn(true);
+        res.set_pxn(!flags.executable);
+
+        if flags.device {
+            res.set_index_into_mair(MAIR_DEVICE_INDEX);
+        } else {
+            res.set_index_into_mair(MAIR_NORMAL_INDEX);
+            // with LPA2 these bits are OA[51:50] and TCR_EL1.SH1 applies
+            if !self.lpa2() {
+                res.set_shared(SH_INNER_SHAREABLE);
+            }
+        }
+
+        // without AF, the first access would fault
+        res.set_access_flag(true);
+        // at the last level, bits[1:0] = 0b11 means page. 0b01 would be reserved
+        res.set_zero(true);
+        res.set_valid(true);
+
+        res.0 | self.encode_addr(output_addr)
+    }
+
+    /// A valid block descriptor. Same as a page, but bits[1:0] = 0b01
+    pub fn block_descriptor(&self, output_addr: u64, flags: PageFlags) -> u64 {
+        self.page_descriptor(output_addr, flags) & !0b10
+    }
+
+    /// Whether the CPU can use this mode, from ID_AA64MMFR0_EL1 and ID_AA64MMFR2_EL1
+    pub fn is_supported(&self, mmfr0: u64, mmfr2: u64) -> bool {
+        let field = |reg: u64, shift: u32| (reg >> shift) & 0xF;
+
+        match self.granule {
+            // TGran4: 0 = yes, 1 = yes with LPA2, 0xF = no
+            Granule::K4 => {
+                let tgran4 = field(mmfr0, 28);
+                tgran4 != 0xF && (!self.lpa2() || tgran4 == 1)
+            }
+            // TGran16: 0 = no, 1 = yes, 2 = yes with LPA2
+            Granule::K16 => {
+                let tgran16 = field(mmfr0, 20);
+                tgran16 >= if self.lpa2() { 2 } else { 1 }
+            }
+            // TGran64: 0 = yes, 0xF = no. 52 bit VAs need VARange = 1 (LVA)
+            Granule::K64 => field(mmfr0, 24) == 0 && (self.va_bits == 48 || field(mmfr2, 16) == 1),
+        }
+    }
+}
+
+impl Default for TranslationMode {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+// ------------------
+// MAPPER
+// ------------------
+
+/// Which half of the address space a set of tables translates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VaRange {
+    /// TTBR0, the bits above the VA all 0
+    Lower,
+    /// TTBR1, the bits above the VA all 1
+    Upper,
+}
+
+/// A set of tables in any TranslationMode, starting from the root table at root
+pub struct PageTable<M: PhysMemory> {
+    mem: M,
+    root: u64,
+    range: VaRange,
+    mode: TranslationMode,
+}
+
+/// A zeroed table, one granule big, from frames
+fn new_table(
+    mem: &mut impl PhysMemory,
+    mode: &TranslationMode,
+    frames: &mut FrameAllocator,
+) -> Result<u64, MapError> {
+    let size = mode.page_size();
+    let table = frames
+        .allocate_contiguous(size / PAGE_SIZE, size)
+        .ok_or(MapError::OutOfFrames)?;
+    mem.zero(table, size);
+
+    Ok(table)
+}
+
+impl<M: PhysMemory> PageTable<M> {
+    /// Empty tables, with a zeroed root table from frames
+    pub fn new(
+        mut mem: M,
+        range: VaRange,
+        mode: TranslationMode,
+        frames: &mut FrameAllocator,
+    ) -> Result<Self, MapError> {
+        let root = new_table(&mut mem, &mode, frames)?;
+
+        Ok(Self::from_root(mem, root, range, mode))
+    }
+
+    /// Tables that already exist, like the ones TTBR1 points to
+    pub fn from_root(mem: M, root: u64, range: VaRange, mode: TranslationMode) -> Self {
+        Self {
+            mem,
+            root,
+            range,
+            mode,
+        }
+    }
+
+    /// Physical address of the root table, what goes in TTBRn
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    pub fn mode(&self) -> &TranslationMode {
+        &self.mode
+    }
+
+    /// Fails if vaddr isnt page aligned or is in the wrong half
+    fn check_vaddr(&self, vaddr: u64) -> Result<u64, MapError> {
+        let sign = vaddr >> self.mode.va_bits;
+        let expected = match self.range {
+            VaRange::Lower => 0,
+            VaRange::Upper => u64::MAX >> self.mode.va_bits,
+        };
+        if sign != expected {
+            return Err(MapError::OutOfRange(vaddr));
+        }
+        if vaddr & (self.mode.page_size() - 1) != 0 {
+            return Err(MapError::Unaligned(vaddr));
+        }
+
+        Ok(vaddr)
+    }
+
+    /// The valid leaf entry (block or page) covering vaddr, and its depth
+    fn find_entry(&self, vaddr: u64) -> Option<(u64, usize)> {
+        let leaf = self.mode.levels() - 1;
+
+        let mut table = self.root;
+        for depth in 0..=leaf {
+            let entry = table + self.mode.index(vaddr, depth) * 8;
+            let desc = TableDescriptor4K(self.mem.read_u64(entry));
+            if !desc.valid() {
+                return None;
+            }
+            if depth == leaf || !desc.id_one() {
+                // a block where there cant be one is reserved
+                return (depth == leaf || self.mode.is_block_depth(depth))
+                    .then_some((entry, depth));
+            }
+            table = self.mode.decode_addr(desc.0);
+        }
+
+        None
+    }
+
+    /// Address of the entry for vaddr in its table at depth, making any missing tables on the way from frames
+    fn find_or_create_entry(
+        &mut self,
+        vaddr: u64,
+        depth: usize,
+        frames: &mut FrameAllocator,
+    ) -> Result<u64, MapError> {
+        let mut table = self.root;
+        for d in 0..depth {
+            let entry = table + self.mode.index(vaddr, d) * 8;
+            let desc = TableDescriptor4K(self.mem.read_u64(entry));
+
+            table = if !desc.valid() {
+                let next = new_table(&mut self.mem, &self.mode, frames)?;
+                self.mem.write_u64(entry, self.mode.table_descriptor(next));
+                next
+            } else if desc.id_one() {
+                self.mode.decode_addr(desc.0)
+            } else {
+                // a block already covers vaddr
+                return Err(MapError::AlreadyMapped(vaddr));
+            };
+        }
+
+        Ok(table + self.mode.index(vaddr, depth) * 8)
+    }
+
+    /// The leaf entry for vaddr, its depth and descriptor. vaddr has to be the start of the page or block
+    fn mapped_entry(&self, vaddr: u64) -> Result<(u64, usize, BlockDescriptor4K), MapError> {
+        let vaddr = self.check_vaddr(vaddr)?;
+        let (entry, depth) = self.find_entry(vaddr).ok_or(MapError::NotMapped(vaddr))?;
+        if vaddr & (self.mode.level_size(depth) - 1) != 0 {
+            return Err(MapError::PartialBlock(vaddr));
+        }
+
+        Ok((entry, depth, BlockDescriptor4K(self.mem.read_u64(entry))))
+    }
+
+    /// Map one block at depth
+    fn map_block(
+        &mut self,
+        vaddr: u64,
+        paddr: u64,
+        depth: usize,
+        flags: PageFlags,
+        frames: &mut FrameAllocator,
+    ) -> Result<(), MapError> {
+        let vaddr = self.check_vaddr(vaddr)?;
+        let entry = self.find_or_create_entry(vaddr, depth, frames)?;
+        // an existing table counts as mapped, even if its empty
+        if TableDescriptor4K(self.mem.read_u64(entry)).valid() {
+            return Err(MapError::AlreadyMapped(vaddr));
+        }
+        self.mem
+            .write_u64(entry, self.mode.block_descriptor(paddr, flags));
+        self.mem.tables_written();
+
+        Ok(())
+    }
+
+    /// Map a run of contiguous_entries pages with the contiguous hint. vaddr and paddr have to be aligned to the whole run
+    fn map_contiguous(
+        &mut self,
+        vaddr: u64,
+        paddr: u64,
+        flags: PageFlags,
+        frames: &mut FrameAllocator,
+    ) -> Result<(), MapError> {
+        let vaddr = self.check_vaddr(vaddr)?;
+        let leaf = self.mode.levels() - 1;
+        let n_pages = self.mode.granule.contiguous_entries();
+        let page_size = self.mode.page_size();
+        // an aligned run never crosses into another table
+        let first = self.find_or_create_entry(vaddr, leaf, frames)?;
+
+        // all or nothing, a run with a gap in it cant have the hint
+        for page in 0..n_pages {
+            if BlockDescriptor4K(self.mem.read_u64(first + page * 8)).valid() {
+                return Err(MapError::AlreadyMapped(vaddr + page * page_size));
+            }
+        }
+        for page in 0..n_pages {
+            let mut desc =
+                BlockDescriptor4K(self.mode.page_descriptor(paddr + page * page_size, flags));
+            desc.set_contig(true);
+            self.mem.write_u64(first + page * 8, desc.0);
+        }
+        self.mem.tables_written();
+
+        Ok(())
+    }
+
+    /// Clear the contiguous hint of the whole run vaddr (at entry) is in, before one page of it changes
+    /// Break before make, the TLB cant hold the old and new entries at once. So the run is unmapped and invalidated first
+    fn break_contiguous(&mut self, entry: u64, vaddr: u64) {
+        let n_pages = self.mode.granule.contiguous_entries();
+        let page_size = self.mode.page_size();
+        let first = entry & !(n_pages * 8 - 1);
+        let first_vaddr = vaddr & !(n_pages * page_size - 1);
+
+        let mut run = [0; MAX_CONTIGUOUS_ENTRIES];
+        for (page, desc) in run.iter_mut().enumerate().take(n_pages as usize) {
+            let page = page as u64;
+            *desc = self.mem.read_u64(first + page * 8);
+            self.mem
+                .write_u64(first + page * 8, default_unmapped_block_descriptor().0);
+            self.mem.invalidate(first_vaddr + page * page_size);
+        }
+        for (page, &desc) in run.iter().enumerate().take(n_pages as usize) {
+            let mut desc = BlockDescriptor4K(desc);
+            desc.set_contig(false);
+            self.mem.write_u64(first + page as u64 * 8, desc.0);
+        }
+        self.mem.tables_written();
+    }
+}
+
+impl<M: PhysMemory> PageMapper for PageTable<M> {
+    fn page_size(&self) -> u64 {
+        self.mode.page_size()
+    }
+
+    fn map(
+        &mut self,
+        vaddr: u64,
+        paddr: u64,
+        flags: PageFlags,
+        frames: &mut FrameAllocator,
+    ) -> Result<(), MapError> {
+        let vaddr = self.check_vaddr(vaddr)?;
+        if paddr & (self.mode.page_size() - 1) != 0 {
+            return Err(MapError::Unaligned(paddr));
+        }
+
+        let entry = self.find_or_create_entry(vaddr, self.mode.levels() - 1, frames)?;
+        if BlockDescriptor4K(self.mem.read_u64(entry)).valid() {
+            return Err(MapError::AlreadyMapped(vaddr));
+        }
+        self.mem
+            .write_u64(entry, self.mode.page_descriptor(paddr, flags));
+        self.mem.tables_written();
+
+        Ok(())
+    }
+
+    fn unmap(&mut self, vaddr: u64) -> Result<u64, MapError> {
+        let (entry, depth, desc) = self.mapped_entry(vaddr)?;
+        if depth == self.mode.levels() - 1 && desc.contig() {
+            self.break_contiguous(entry, vaddr);
+        }
+        self.mem
+            .write_u64(entry, default_unmapped_block_descriptor().0);
+        self.mem.invalidate(vaddr);
+
+        Ok(self.mode.decode_addr(desc.0))
+    }
+
+    fn translate(&self, vaddr: u64) -> Option<(u64, PageFlags)> {
+        let page = self
+            .check_vaddr(vaddr & !(self.mode.page_size() - 1))
+            .ok()?;
+        let (entry, depth) = self.find_entry(page)?;
+        let desc = BlockDescriptor4K(self.mem.read_u64(entry));
+        let offset = vaddr & (self.mode.level_size(depth) - 1);
+
+        Some((
+            self.mode.decode_addr(desc.0) + offset,
+            descriptor_flags(&desc),
+        ))
+    }
+
+    fn protect(&mut self, vaddr: u64, flags: PageFlags) -> Result<(), MapError> {
+        let (entry, depth, desc) = self.mapped_entry(vaddr)?;
+        let output_addr = self.mode.decode_addr(desc.0);
+        let desc = if depth == self.mode.levels() - 1 {
+            if desc.contig() {
+                self.break_contiguous(entry, vaddr);
+            }
+            self.mode.page_descriptor(output_addr, flags)
+        } else {
+            self.mode.block_descriptor(output_addr, flags)
+        };
+        self.mem.write_u64(entry, desc);
+        self.mem.invalidate(vaddr);
+
+        Ok(())
+    }
+
+    /// Uses blocks wherever vaddr, paddr and what is left of size line up with one, and the contiguous hint for aligned runs of pages
+    fn map_range(
+        &mut self,
+        vaddr: u64,
+        paddr: u64,
+        size: u64,
+        flags: PageFlags,
+        frames: &mut FrameAllocator,
+    ) -> Result<(), MapError> {
+        let page_size = self.mode.page_size();
+        let size = size.div_ceil(page_size) * page_size;
+        let leaf = self.mode.levels() - 1;
+        let run_size = self.mode.granule.contiguous_entries() * page_size;
+
+        let mut offset = 0;
+        while offset < size {
+            let (vaddr, paddr, left) = (vaddr + offset, paddr + offset, size - offset);
+            let fits = |chunk: u64| (vaddr | paddr) & (chunk - 1) == 0 && left >= chunk;
+
+            // biggest block first
+            let block_depth = (0..leaf)
+                .filter(|&depth| self.mode.is_block_depth(depth))
+                .find(|&depth| fits(self.mode.level_size(depth)));
+
+            offset += if let Some(depth) = block_depth {
+                self.map_block(vaddr, paddr, depth, flags, frames)?;
+                self.mode.level_size(depth)
+            } else if fits(run_size) {
+                self.map_contiguous(vaddr, paddr, flags, frames)?;
+                run_size
+            } else {
+                self.map(vaddr, paddr, flags, frames)?;
+                page_size
+            };
+        }
+
+        Ok(())
+    }
+}
+
+// ------------------
+// TEST
+// ------------------
+
+#[cfg(test)]
+use super::TestRam;
+
+/// frames of RAM at 0x4000_0000, all of it free
+#[cfg(test)]
+fn test_tables_in(mode: TranslationMode, n_frames: u64) -> (PageTable<TestRam>, FrameAllocator) {
+    use arcboot_api::{MemoryMap, MemoryRegion, MemoryRegionType};
+
+    let ram = TestRam::new(0x4000_0000, (n_frames * PAGE_SIZE) as usize);
+    let mut frames = FrameAllocator::new(&MemoryMap::new(alloc::vec![MemoryRegion::new(
+        MemoryRegionType::Standard,
+        (0x4000_0000, 0x4000_0000 + n_frames * PAGE_SIZE),
+    )]));
+    let tables = PageTable::new(ram, VaRange::Upper, mode, &mut frames).unwrap();
+
+    (tables, frames)
+}
+
+/// 4K, 48 bit tables with 64 frames
+#[cfg(test)]
+fn test_tables() -> (PageTable<TestRam>, FrameAllocator) {
+    test_tables_in(TranslationMode::DEFAULT, 64)
+}
+
+#[test]
+fn test_page_table_4k() {
+    let (mut tables, mut frames) = test_tables();
+    assert_eq!(tables.root(), 0x4000_0000);
+
+    let code = 0xFFFF_0000_0000_0000;
+    let stack = 0xFFFF_FFFF_FFFF_E000;
+    tables
+        .map(code, 0x4100_0000, PageFlags::KERNEL_CODE, &mut frames)
+        .unwrap();
+    tables
+        .map(stack, 0x4100_1000, PageFlags::KERNEL_DATA, &mut frames)
+        .unwrap();
+    // L1-L3 for each, they share no tables
+    assert_eq!(frames.free_frames(), 64 - 7);
+
+    assert_eq!(
+        tables.translate(code + 0x123),
+        Some((0x4100_0123, PageFlags::KERNEL_CODE))
+    );
+    assert_eq!(
+        tables.translate(stack + 0xFF8),
+        Some((0x4100_1FF8, PageFlags::KERNEL_DATA))
+    );
+    assert_eq!(tables.translate(code + PAGE_SIZE), None);
+
+    // each L0 entry lands at its own index and points at the right table
+    let l0_first = TableDescriptor4K(tables.mem.read_u64(tables.root()));
+    let l0_last = TableDescriptor4K(tables.mem.read_u64(tables.root() + 511 * 8));
+    assert_eq!(
+        l0_first.0,
+        TranslationMode::DEFAULT.table_descriptor(0x4000_1000)
+    );
+    assert_eq!(
+        l0_last.0,
+        TranslationMode::DEFAULT.table_descriptor(0x4000_4000)
+    );
+    assert_eq!(l0_first.0 & 0b11, 0b11);
+
+    // the page itself
+    let l3 = 0x4000_3000;
+    assert_eq!(
+        tables.mem.read_u64(l3),
+        TranslationMode::DEFAULT.page_descriptor(0x4100_0000, PageFlags::KERNEL_CODE)
+    );
+
+    assert_eq!(
+        tables.map(code, 0x4200_0000, PageFlags::KERNEL_DATA, &mut frames),
+        Err(MapError::AlreadyMapped(code))
+    );
+
+    // new mappings need no TLB invalidate, changed ones do
+    assert!(tables.mem.invalidated.is_empty());
+    tables.protect(stack, PageFlags::KERNEL_RODATA).unwrap();
+    assert_eq!(
+        tables.translate(stack),
+        Some((0x4100_1000, PageFlags::KERNEL_RODATA))
+    );
+
+    assert_eq!(tables.unmap(code), Ok(0x4100_0000));
+    assert_eq!(tables.mem.invalidated, [stack, code]);
+    assert_eq!(tables.translate(code), None);
+    assert_eq!(tables.unmap(code), Err(MapError::NotMapped(code)));
+    assert_eq!(
+        tables.protect(code, PageFlags::KERNEL_DATA),
+        Err(MapError::NotMapped(code))
+    );
+
+    // reuses the tables from before
+    tables
+        .map_range(
+            code,
+            0x4100_0000,
+            2 * PAGE_SIZE,
+            PageFlags::MMIO,
+            &mut frames,
+        )
+        .unwrap();
+    assert_eq!(frames.free_frames(), 64 - 7);
+    assert_eq!(
+        tables.translate(code + PAGE_SIZE),
+        Some((0x4100_1000, PageFlags::MMIO))
+    );
+}
+
+#[test]
+fn test_page_table_4k_errors() {
+    let (mut tables, mut frames) = test_tables();
+
+    assert_eq!(
+        tables.map(
+            0x4000_0000,
+            0x4000_0000,
+            PageFlags::KERNEL_DATA,
+            &mut frames
+        ),
+        Err(MapError::OutOfRange(0x4000_0000))
+    );
+    assert_eq!(
+        tables.map(
+            0xFFFF_0000_0000_0800,
+            0x4100_0000,
+            PageFlags::KERNEL_DATA,
+            &mut frames
+        ),
+        Err(MapError::Unaligned(0xFFFF_0000_0000_0800))
+    );
+    assert_eq!(
+        tables.map(
+            0xFFFF_0000_0000_0000,
+            0x4100_0800,
+            PageFlags::KERNEL_DATA,
+            &mut frames
+        ),
+        Err(MapError::Unaligned(0x4100_0800))
+    );
+
+    // the tables for a mapping need 3 frames
+    frames.allocate_contiguous(frames.free_frames() - 2, PAGE_SIZE);
+    assert_eq!(
+        tables.map(
+            0xFFFF_0000_0000_0000,
+            0x4100_0000,
+            PageFlags::KERNEL_DATA,
+            &mut frames
+        ),
+        Err(MapError::OutOfFrames)
+    );
+}
+
+#[test]
+fn test_page_table_4k_blocks() {
+    let (mut tables, mut frames) = test_tables();
+
+    // 1G block, 2M block, a contiguous run and a page
+    let vaddr = 0xFFFF_0000_0000_0000;
+    let size = L1_BLOCK_SIZE + L2_BLOCK_SIZE + CONTIGUOUS_PAGES * PAGE_SIZE + PAGE_SIZE;
+    tables
+        .map_range(
+            vaddr,
+            0x8000_0000,
+            size,
+            PageFlags::KERNEL_DATA,
+            &mut frames,
+        )
+        .unwrap();
+    // one L1, L2 and L3 table
+    assert_eq!(frames.free_frames(), 64 - 4);
+
+    let l1 = 0x4000_1000;
+    let l2 = 0x4000_2000;
+    let l3 = 0x4000_3000;
+    assert_eq!(
+        tables.mem.read_u64(l1),
+        TranslationMode::DEFAULT.block_descriptor(0x8000_0000, PageFlags::KERNEL_DATA)
+    );
+    assert_eq!(tables.mem.read_u64(l1) & 0b11, 0b01);
+    assert_eq!(
+        tables.mem.read_u64(l2),
+        TranslationMode::DEFAULT.block_descriptor(0xC000_0000, PageFlags::KERNEL_DATA)
+    );
+    let contig = |tables: &PageTable<TestRam>, entry: u64| {
+        BlockDescriptor4K(tables.mem.read_u64(l3 + entry * 8)).contig()
+    };
+    assert!((0..16).all(|entry| contig(&tables, entry)));
+    assert!(!contig(&tables, 16));
+
+    assert_eq!(
+        tables.translate(vaddr + 0x1234_5678),
+        Some((0x9234_5678, PageFlags::KERNEL_DATA))
+    );
+    assert_eq!(
+        tables.translate(vaddr + L1_BLOCK_SIZE + 0x1_2345),
+        Some((0xC001_2345, PageFlags::KERNEL_DATA))
+    );
+    assert_eq!(
+        tables.translate(vaddr + size - 1),
+        Some((0x8000_0000 + size - 1, PageFlags::KERNEL_DATA))
+    );
+    assert_eq!(tables.translate(vaddr + size), None);
+
+    // blocks only change as a whole
+    assert_eq!(
+        tables.unmap(vaddr + PAGE_SIZE),
+        Err(MapError::PartialBlock(vaddr + PAGE_SIZE))
+    );
+    assert_eq!(
+        tables.map(
+            vaddr + PAGE_SIZE,
+            0x4100_0000,
+            PageFlags::KERNEL_DATA,
+            &mut frames
+        ),
+        Err(MapError::AlreadyMapped(vaddr + PAGE_SIZE))
+    );
+    tables
+        .protect(vaddr + L1_BLOCK_SIZE, PageFlags::KERNEL_RODATA)
+        .unwrap();
+    assert_eq!(
+        tables.translate(vaddr + L1_BLOCK_SIZE + 8),
+        Some((0xC000_0008, PageFlags::KERNEL_RODATA))
+    );
+    assert_eq!(tables.unmap(vaddr), Ok(0x8000_0000));
+    assert_eq!(tables.translate(vaddr), None);
+
+    // changing one page of the run drops the hint for all of it
+    let run = vaddr + L1_BLOCK_SIZE + L2_BLOCK_SIZE;
+    tables.mem.invalidated.clear();
+    tables
+        .protect(run + 3 * PAGE_SIZE, PageFlags::KERNEL_RODATA)
+        .unwrap();
+    assert!(!(0..16).any(|entry| contig(&tables, entry)));
+    // the whole run went invalid and back before the page changed
+    let mut expected: alloc::vec::Vec<_> = (0..16).map(|page| run + page * PAGE_SIZE).collect();
+    expected.push(run + 3 * PAGE_SIZE);
+    assert_eq!(tables.mem.invalidated, expected);
+    assert_eq!(
+        tables.translate(run + 2 * PAGE_SIZE),
+        Some((0xC020_2000, PageFlags::KERNEL_DATA))
+    );
+
+    // only aligned and big enough gets a block
+    tables
+        .map_range(
+            vaddr + PAGE_SIZE,
+            0x8000_1000,
+            L2_BLOCK_SIZE,
+            PageFlags::MMIO,
+            &mut frames,
+        )
+        .unwrap();
+    assert_eq!(
+        tables.translate(vaddr + L2_BLOCK_SIZE),
+        Some((0x8020_0000, PageFlags::MMIO))
+    );
+}
+
+#[test]
+fn test_translation_modes() {
+    use Granule::*;
+
+    let mode = |granule, va_bits| TranslationMode::new(granule, va_bits).unwrap();
+    assert!(TranslationMode::new(K4, 39).is_err());
+
+    // levels, root shift, root entries, block depths
+    let cases = [
+        (mode(K4, 48), 4, 39, 512, [false, true, true, false, false]),
+        (mode(K4, 52), 5, 48, 16, [false, false, true, true, false]),
+        (mode(K16, 48), 4, 47, 2, [false, false, true, false, false]),
+        (mode(K16, 52), 4, 47, 32, [false, false, true, false, false]),
+        (mode(K64, 48), 3, 42, 64, [false, true, false, false, false]),
+        (
+            mode(K64, 52),
+            3,
+            42,
+            1024,
+            [false, true, false, false, false],
+        ),
+    ];
+    for (mode, levels, root_shift, root_entries, blocks) in cases {
+        assert_eq!(mode.levels(), levels);
+        assert_eq!(mode.level_shift(0), root_shift);
+        assert_eq!(mode.level_shift(levels - 1), mode.granule().shift());
+        assert_eq!(mode.index(u64::MAX, 0), root_entries - 1);
+        assert_eq!(
+            mode.index(u64::MAX, levels - 1),
+            (1 << mode.granule().bits_per_level()) - 1
+        );
+        for (depth, &block) in blocks.iter().enumerate().take(levels) {
+            assert_eq!(mode.is_block_depth(depth), block);
+        }
+    }
+    assert_eq!(mode(K16, 48).level_size(2), 32 << 20);
+    assert_eq!(mode(K64, 48).level_size(1), 512 << 20);
+
+    // PA[51:48] go in bits[15:12] with 64K, and PA[51:50] in bits[9:8] with LPA2
+    let high = 0xF_0000_0000_0000;
+    assert_eq!(mode(K64, 52).encode_addr(high), 0xF000);
+    assert_eq!(mode(K4, 52).encode_addr(high), 0x3_0000_0000_0300);
+    assert_eq!(mode(K16, 52).encode_addr(high | 0x4000), 0x3_0000_0000_4300);
+    for mode in [mode(K4, 48), mode(K4, 52), mode(K16, 52), mode(K64, 52)] {
+        let addr = if mode.va_bits() == 52 { high } else { 0 } | 0x1234_0000;
+        assert_eq!(mode.decode_addr(mode.encode_addr(addr)), addr);
+        assert_eq!(mode.decode_addr(mode.table_descriptor(addr)), addr);
+        assert_eq!(
+            mode.decode_addr(mode.page_descriptor(addr, PageFlags::KERNEL_DATA)),
+            addr
+        );
+    }
+
+    // a Cortex-A53: 4K and 64K, no 16K, no 52 bit anything
+    let (mmfr0, mmfr2) = (0x0000_0000_0000_1122, 0);
+    assert!(TranslationMode::DEFAULT.is_supported(mmfr0, mmfr2));
+    assert!(mode(K64, 48).is_supported(mmfr0, mmfr2));
+    assert!(!mode(K16, 48).is_supported(mmfr0, mmfr2));
+    assert!(!mode(K4, 52).is_supported(mmfr0, mmfr2));
+    assert!(!mode(K64, 52).is_supported(mmfr0, mmfr2));
+    assert!(mode(K64, 52).is_supported(mmfr0, 1 << 16));
+
+    // LPA2 for 4K and 16K, no 64K
+    let mmfr0 = (1 << 28) | (0xF << 24) | (2 << 20);
+    assert!(mode(K4, 52).is_supported(mmfr0, 0));
+    assert!(mode(K16, 52).is_supported(mmfr0, 0));
+    assert!(!mode(K64, 48).is_supported(mmfr0, 0));
+    assert!(!mode(K16, 52).is_supported(1 << 20, 0));
+
+    // 16K only
+    assert!(!TranslationMode::DEFAULT.is_supported((0xF << 28) | (0xF << 24) | (1 << 20), 0));
+}
+
+#[test]
+fn test_page_table_granules() {
+    use Granule::*;
+
+    for (granule, va_bits, paddr) in [
+        (K16, 48, 0x8000_0000),
+        (K64, 52, 0xF_0000_0000_0000),
+        (K4, 52, 0xF_0000_0000_0000),
+    ] {
+        let mode = TranslationMode::new(granule, va_bits).unwrap();
+        let (mut tables, mut frames) = test_tables_in(mode, 256);
+        let page_size = mode.page_size();
+        let block_depth = (0..mode.levels())
+            .find(|&depth| mode.is_block_depth(depth))
+            .unwrap();
+        let block_size = mode.level_size(block_depth);
+        let run_size = granule.contiguous_entries() * page_size;
+
+        // the lowest address of the upper half
+        let vaddr = u64::MAX << va_bits;
+        let size = block_size + run_size + page_size;
+        tables
+            .map_range(vaddr, paddr, size, PageFlags::KERNEL_DATA, &mut frames)
+            .unwrap();
+        // one table per level, every one a granule big
+        assert_eq!(
+            frames.free_frames(),
+            256 - mode.levels() as u64 * page_size / PAGE_SIZE
+        );
+        assert_eq!(tables.root() & (page_size - 1), 0);
+
+        let (block, depth) = tables.find_entry(vaddr).unwrap();
+        assert_eq!(depth, block_depth);
+        assert_eq!(tables.mem.read_u64(block) & 0b11, 0b01);
+
+        let leaf = mode.levels() - 1;
+        let (first, depth) = tables.find_entry(vaddr + block_size).unwrap();
+        assert_eq!(depth, leaf);
+        let contig =
+            |entry: u64| BlockDescriptor4K(tables.mem.read_u64(first + entry * 8)).contig();
+        assert!((0..granule.contiguous_entries()).all(contig));
+        assert!(!contig(granule.contiguous_entries()));
+
+        assert_eq!(
+            tables.translate(vaddr + block_size / 2 + 8),
+            Some((paddr + block_size / 2 + 8, PageFlags::KERNEL_DATA))
+        );
+        assert_eq!(
+            tables.translate(vaddr + size - 8),
+            Some((paddr + size - 8, PageFlags::KERNEL_DATA))
+        );
+        assert_eq!(tables.translate(vaddr + size), None);
+
+        // va_bits decides what is in range
+        let below = (u64::MAX << va_bits) - page_size;
+        assert_eq!(
+            tables.map(below, paddr, PageFlags::KERNEL_DATA, &mut frames),
+            Err(MapError::OutOfRange(below))
+        );
+        assert_eq!(
+            tables.map(
+                vaddr + size + PAGE_SIZE,
+                paddr,
+                PageFlags::KERNEL_DATA,
+                &mut frames
+            ),
+            if page_size == PAGE_SIZE {
+                Ok(())
+            } else {
+                Err(MapError::Unaligned(vaddr + size + PAGE_SIZE))
+            }
+        );
+    }
+}
@@ -0,0 +1,263 @@
+// ---------------
+// PAGE MAPPER
+// ---------------
+
+// Arch neutral interface to a set of page tables. Tables are only ever touched through PhysMemory,
+// so the same walk runs on real frames at boot and on a plain buffer in tests
+
+pub mod arm64;
+
+use arcboot_api::{MemoryMap, MemoryRegionType};
+use core::fmt;
+
+use super::{frame::FrameAllocator, map::page_ranges, DirectMap, PageFlags, PAGE_SIZE};
+
+/// How page tables reach physical memory, and how the MMU hears about changes to them
+pub trait PhysMemory {
+    /// Read the u64 at paddr. 8 byte aligned
+    fn read_u64(&self, paddr: u64) -> u64;
+
+    /// Write the u64 at paddr. 8 byte aligned
+    fn write_u64(&mut self, paddr: u64, value: u64);
+
+    /// Zero size bytes from paddr, e.g. a new table. Both 8 byte aligned
+    fn zero(&mut self, paddr: u64, size: u64) {
+        for offset in (0..size).step_by(8) {
+            self.write_u64(paddr + offset, 0);
+        }
+    }
+
+    /// New entries were written. Make them visible to table walks
+    fn tables_written(&mut self) {}
+
+    /// A valid entry for the page or block at vaddr was changed or removed. Drop what the TLB has for it
+    fn invalidate(&mut self, _vaddr: u64) {}
+}
+
+/// Physical memory at its own address. For when the MMU is off or everything is identity mapped
+#[derive(Debug)]
+pub struct IdentityMapped {
+    _private: (),
+}
+
+impl IdentityMapped {
+    /// # Safety
+    /// Every paddr given to the mapper has to be accessible at the same vaddr, and not used by anything else
+    pub unsafe fn new() -> Self {
+        Self { _private: () }
+    }
+}
+
+impl PhysMemory for IdentityMapped {
+    fn read_u64(&self, paddr: u64) -> u64 {
+        unsafe { core::ptr::read_volatile(paddr as *const u64) }
+    }
+
+    fn write_u64(&mut self, paddr: u64, value: u64) {
+        unsafe { core::ptr::write_volatile(paddr as *mut u64, value) }
+    }
+
+    // harmless for tables that arent live yet, needed for the ones that are
+
+    fn tables_written(&mut self) {
+        #[cfg(target_arch = "aarch64")]
+        arcboot_api::cache::tables_written();
+    }
+
+    fn invalidate(&mut self, _vaddr: u64) {
+        #[cfg(target_arch = "aarch64")]
+        arcboot_api::cache::tlbi_va(_vaddr);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// vaddr or paddr isnt page aligned
+    Unaligned(u64),
+    /// vaddr is outside the half of the address space the tables translate
+    OutOfRange(u64),
+    /// vaddr already has a mapping
+    AlreadyMapped(u64),
+    /// vaddr has no mapping
+    NotMapped(u64),
+    /// vaddr is inside a block mapping, which can only be changed as a whole from its start
+    PartialBlock(u64),
+    /// No frame left for a new table
+    OutOfFrames,
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unaligned(addr) => write!(f, "{addr:#X} is not page aligned"),
+            Self::OutOfRange(vaddr) => write!(f, "{vaddr:#X} is not translated by these tables"),
+            Self::AlreadyMapped(vaddr) => write!(f, "{vaddr:#X} is already mapped"),
+            Self::NotMapped(vaddr) => write!(f, "{vaddr:#X} is not mapped"),
+            Self::PartialBlock(vaddr) => write!(f, "{vaddr:#X} is inside a block mapping"),
+            Self::OutOfFrames => write!(f, "no free frames for a page table"),
+        }
+    }
+}
+
+/// A set of page tables. Invalidating the TLB after a change is left to PhysMemory, so mappers call it for every entry that was valid before
+pub trait PageMapper {
+    /// Smallest unit the tables map. At least PAGE_SIZE
+    fn page_size(&self) -> u64 {
+        PAGE_SIZE
+    }
+
+    /// Map the page at vaddr to the frame at paddr. Missing tables are allocated from frames
+    fn map(
+        &mut self,
+        vaddr: u64,
+        paddr: u64,
+        flags: PageFlags,
+        frames: &mut FrameAllocator,
+    ) -> Result<(), MapError>;
+
+    /// Remove the mapping of the page at vaddr. Returns the frame it pointed to. Tables stay, even if they end up empty
+    /// If vaddr starts a block mapping, the whole block goes
+    fn unmap(&mut self, vaddr: u64) -> Result<u64, MapError>;
+
+    /// Physical address vaddr maps to (with its offset in the page), and the flags of its page
+    fn translate(&self, vaddr: u64) -> Option<(u64, PageFlags)>;
+
+    /// Change the flags of the page (or block) at vaddr, keeping the frame it points to
+    fn protect(&mut self, vaddr: u64, flags: PageFlags) -> Result<(), MapError>;
+
+    /// Map size bytes (rounded up to pages) from vaddr to paddr. Stops at the first error
+    /// Mappers may use bigger pages than page_size() where things line up
+    fn map_range(
+        &mut self,
+        vaddr: u64,
+        paddr: u64,
+        size: u64,
+        flags: PageFlags,
+        frames: &mut FrameAllocator,
+    ) -> Result<(), MapError> {
+        let page_size = self.page_size();
+        for page in 0..size.div_ceil(page_size) {
+            let offset = page * page_size;
+            self.map(vaddr + offset, paddr + offset, flags, frames)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Map every RAM region at hhdm_offset + paddr, and every MMIO region at mmio_offset + paddr as device memory
+/// Regions are rounded out to the tables' page size
+pub fn map_direct(
+    tables: &mut impl PageMapper,
+    memory_map: &MemoryMap,
+    direct_map: &DirectMap,
+    frames: &mut FrameAllocator,
+) -> Result<(), MapError> {
+    let page_size = tables.page_size();
+    for (start, end) in page_ranges(memory_map, |t| t.is_ram(), page_size) {
+        tables.map_range(
+            direct_map.hhdm_offset + start,
+            start,
+            end - start,
+            PageFlags::KERNEL_DATA,
+            frames,
+        )?;
+    }
+
+    for (start, end) in page_ranges(memory_map, |t| t == MemoryRegionType::MMIO, page_size) {
+        tables.map_range(
+            direct_map.mmio_offset + start,
+            start,
+            end - start,
+            PageFlags::MMIO,
+            frames,
+        )?;
+    }
+
+    Ok(())
+}
+
+// ---------------
+// TEST
+// ---------------
+
+/// A buffer that stands in for physical memory starting at `base`. Keeps every vaddr the mapper invalidated
+#[cfg(test)]
+pub struct TestRam {
+    pub base: u64,
+    pub ram: alloc::vec::Vec<u8>,
+    pub invalidated: alloc::vec::Vec<u64>,
+}
+
+#[cfg(test)]
+impl TestRam {
+    pub fn new(base: u64, size: usize) -> Self {
+        Self {
+            base,
+            ram: alloc::vec![0xAA; size],
+            invalidated: alloc::vec::Vec::new(),
+        }
+    }
+
+    fn offset(&self, paddr: u64) -> usize {
+        (paddr - self.base) as usize
+    }
+}
+
+#[cfg(test)]
+impl PhysMemory for TestRam {
+    fn read_u64(&self, paddr: u64) -> u64 {
+        let offset = self.offset(paddr);
+        u64::from_le_bytes(self.ram[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn write_u64(&mut self, paddr: u64, value: u64) {
+        let offset = self.offset(paddr);
+        self.ram[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn invalidate(&mut self, vaddr: u64) {
+        self.invalidated.push(vaddr);
+    }
+}
+
+#[test]
+fn test_map_direct() {
+    use arcboot_api::MemoryRegion;
+    use arm64::{PageTable, TranslationMode, VaRange, L2_BLOCK_SIZE};
+    use MemoryRegionType::*;
+
+    let memory_map = MemoryMap::new(alloc::vec![
+        MemoryRegion::new(MMIO, (0x0900_0000, 0x0900_1000)),
+        MemoryRegion::new(Bootloader, (0x4000_0000, 0x4010_0000)),
+        MemoryRegion::new(Standard, (0x4010_0000, 0x8000_0000)),
+        MemoryRegion::new(Reserved, (0x8000_0000, 0x8010_0000)),
+    ]);
+    let ram = TestRam::new(0x4000_0000, 0x40_0000);
+    let mut frames = FrameAllocator::new(&MemoryMap::new(alloc::vec![MemoryRegion::new(
+        Standard,
+        (0x4000_0000, 0x4040_0000),
+    )]));
+    let mut tables =
+        PageTable::new(ram, VaRange::Upper, TranslationMode::DEFAULT, &mut frames).unwrap();
+    let direct_map = DirectMap::default();
+
+    map_direct(&mut tables, &memory_map, &direct_map, &mut frames).unwrap();
+    // 1G of RAM is one L1 block, the MMIO page needs its own L1, L2 and L3 table
+    assert_eq!(frames.free_frames(), 0x400 - 1 - 1 - 3);
+
+    assert_eq!(
+        tables.translate(direct_map.hhdm_offset + 0x7FFF_FFF8),
+        Some((0x7FFF_FFF8, PageFlags::KERNEL_DATA))
+    );
+    assert_eq!(
+        tables.translate(direct_map.hhdm_offset + 0x4000_0000 + L2_BLOCK_SIZE),
+        Some((0x4000_0000 + L2_BLOCK_SIZE, PageFlags::KERNEL_DATA))
+    );
+    assert_eq!(tables.translate(direct_map.hhdm_offset + 0x8000_0000), None);
+    assert_eq!(
+        tables.translate(direct_map.mmio_offset + 0x0900_0010),
+        Some((0x0900_0010, PageFlags::MMIO))
+    );
+    assert_eq!(tables.translate(direct_map.hhdm_offset + 0x0900_0000), None);
+}
@@ -1,7 +1,89 @@
 use tock_registers::interfaces::Writeable;
 
+pub mod frame;
 #[cfg(feature = "builtin_allocator")]
 pub mod heap;
+pub mod map;
 pub mod mmu;
 
+/// Granule arcboot loads and maps the kernel with
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Default start of the direct map of RAM, with room for 32 TiB. Halfway up a 48 bit TTBR1, which starts at 0xFFFF_0000_0000_0000 where kernels are linked
+pub const DEFAULT_HHDM_OFFSET: u64 = 0xFFFF_8000_0000_0000;
+/// Default start of the MMIO window, right after the direct map
+pub const DEFAULT_MMIO_OFFSET: u64 = 0xFFFF_A000_0000_0000;
+/// Start of the direct map with 52 bit tables, for RAM above what a 48 bit one can reach. Room for 2 PiB
+pub const WIDE_HHDM_OFFSET: u64 = 0xFFF0_0000_0000_0000;
+/// Start of the MMIO window with 52 bit tables, right after the wide direct map
+pub const WIDE_MMIO_OFFSET: u64 = 0xFFF8_0000_0000_0000;
+
+/// Where the kernel finds physical memory in TTBR1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirectMap {
+    /// RAM at paddr is mapped at hhdm_offset + paddr
+    pub hhdm_offset: u64,
+    /// MMIO at paddr is mapped at mmio_offset + paddr, as device memory
+    pub mmio_offset: u64,
+}
+
+impl DirectMap {
+    pub const fn new(hhdm_offset: u64, mmio_offset: u64) -> Self {
+        Self {
+            hhdm_offset,
+            mmio_offset,
+        }
+    }
+}
+
+impl Default for DirectMap {
+    fn default() -> Self {
+        Self::new(DEFAULT_HHDM_OFFSET, DEFAULT_MMIO_OFFSET)
+    }
+}
+
+/// How a page can be accessed. Each arch turns this into its own descriptor bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFlags {
+    pub writable: bool,
+    pub executable: bool,
+    /// Device memory (MMIO). Otherwise normal write-back cacheable memory
+    pub device: bool,
+}
+
+impl PageFlags {
+    pub const fn new(writable: bool, executable: bool, device: bool) -> Self {
+        Self {
+            writable,
+            executable,
+            device,
+        }
+    }
+
+    /// Stacks, heaps, .data and .bss
+    pub const KERNEL_DATA: PageFlags = PageFlags::new(true, false, false);
+    /// .rodata
+    pub const KERNEL_RODATA: PageFlags = PageFlags::new(false, false, false);
+    /// .text
+    pub const KERNEL_CODE: PageFlags = PageFlags::new(false, true, false);
+    /// Device registers and config spaces
+    pub const MMIO: PageFlags = PageFlags::new(true, false, true);
+
+    /// From an ELF segment's PF_W and PF_X. PF_R is implied, kernel pages are always readable
+    pub fn from_elf(p_flags: u32) -> Self {
+        use goblin::elf::program_header::{PF_W, PF_X};
+
+        Self::new(p_flags & PF_W != 0, p_flags & PF_X != 0, false)
+    }
+
+    /// Everything either allows, for a page two kernel segments share
+    pub fn union(self, other: PageFlags) -> Self {
+        Self::new(
+            self.writable || other.writable,
+            self.executable || other.executable,
+            self.device || other.device,
+        )
+    }
+}
+
 // ARC MEMORY PROTOCOL
@@ -0,0 +1,426 @@
+// ---------------
+// BOOT MENU
+// ---------------
+
+// What the boot menu does with each key and timer tick. Drawing it and reading keys is up to the firmware side, see efi::menu
+// Any key stops the countdown, so holding one down at timeout 0 still gets the menu up
+// `e` opens an EntryEditor on the selected entry. What it returns is only for this boot, arcboot.conf is never written
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use crate::config::{BootConfig, BootEntry, BootModule};
+
+/// A key press, as far as the menu cares
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuKey {
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Enter,
+    Escape,
+    Backspace,
+    Delete,
+    Char(char),
+    /// Anything else. Still stops the countdown
+    Other,
+}
+
+/// What the menu wants done after a key or tick
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuAction {
+    /// Nothing changed on screen
+    None,
+    Redraw,
+    /// Boot entries[index]
+    Boot(usize),
+    /// Open an EntryEditor on entries[index]
+    Edit(usize),
+}
+
+/// Selection and countdown over a BootConfig's entries
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Menu {
+    selected: usize,
+    n_entries: usize,
+    /// Seconds left. None once a key has been pressed
+    countdown: Option<u32>,
+}
+
+impl Menu {
+    /// Starts on the default entry, counting down from the config's timeout
+    pub fn new(config: &BootConfig) -> Self {
+        Self {
+            selected: config.default,
+            n_entries: config.entries.len(),
+            countdown: Some(config.timeout),
+        }
+    }
+
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    pub fn countdown(&self) -> Option<u32> {
+        self.countdown
+    }
+
+    /// A second has passed. Boots the selected entry when the countdown runs out
+    pub fn tick(&mut self) -> MenuAction {
+        match self.countdown {
+            Some(0) | Some(1) => {
+                self.countdown = Some(0);
+                MenuAction::Boot(self.selected)
+            }
+            Some(seconds) => {
+                self.countdown = Some(seconds - 1);
+                MenuAction::Redraw
+            }
+            None => MenuAction::None,
+        }
+    }
+
+    pub fn key(&mut self, key: MenuKey) -> MenuAction {
+        let stopped = self.countdown.take().is_some();
+        let last = self.n_entries.saturating_sub(1);
+
+        let selected = match key {
+            MenuKey::Enter => return MenuAction::Boot(self.selected),
+            MenuKey::Up => self.selected.saturating_sub(1),
+            MenuKey::Down => (self.selected + 1).min(last),
+            MenuKey::Home => 0,
+            MenuKey::End => last,
+            MenuKey::Char('e') => return MenuAction::Edit(self.selected),
+            // 1 to 9 jump straight to an entry
+            MenuKey::Char(c) => match c.to_digit(10) {
+                Some(n) if n >= 1 && (n as usize) <= self.n_entries => n as usize - 1,
+                _ => self.selected,
+            },
+            _ => self.selected,
+        };
+
+        if stopped || selected != self.selected {
+            self.selected = selected;
+            MenuAction::Redraw
+        } else {
+            MenuAction::None
+        }
+    }
+}
+
+// ---------------
+// EDITING
+// ---------------
+
+/// One line of text and a cursor into it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineEditor {
+    text: Vec<char>,
+    /// Index into text, text.len() is after the last char
+    cursor: usize,
+}
+
+impl LineEditor {
+    /// Starts with the cursor at the end
+    pub fn new(text: &str) -> Self {
+        let text: Vec<char> = text.chars().collect();
+        Self {
+            cursor: text.len(),
+            text,
+        }
+    }
+
+    pub fn text(&self) -> String {
+        self.text.iter().collect()
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Returns whether the line or cursor moved. Keys that mean nothing to a line, like Enter, are left to the caller
+    pub fn key(&mut self, key: MenuKey) -> bool {
+        let before = (self.text.len(), self.cursor);
+
+        match key {
+            MenuKey::Left => self.cursor = self.cursor.saturating_sub(1),
+            MenuKey::Right => self.cursor = (self.cursor + 1).min(self.text.len()),
+            MenuKey::Home => self.cursor = 0,
+            MenuKey::End => self.cursor = self.text.len(),
+            MenuKey::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.text.remove(self.cursor);
+            }
+            MenuKey::Delete if self.cursor < self.text.len() => {
+                self.text.remove(self.cursor);
+            }
+            MenuKey::Char(c) if !c.is_control() => {
+                self.text.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            _ => {}
+        }
+
+        before != (self.text.len(), self.cursor)
+    }
+
+    /// At most width chars of the line, scrolled so the cursor is on screen, and the cursor's column in them
+    pub fn view(&self, width: usize) -> (String, usize) {
+        let width = width.max(1);
+        // the cursor can sit after the last char, so it needs a column of its own
+        let start = (self.cursor + 1).saturating_sub(width);
+        let end = (start + width).min(self.text.len());
+
+        (self.text[start..end].iter().collect(), self.cursor - start)
+    }
+}
+
+/// What the editor wants done after a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditAction {
+    None,
+    Redraw,
+    /// Boot EntryEditor::entry()
+    Boot,
+    /// Back to the menu, dropping the changes
+    Cancel,
+}
+
+/// Editable kernel, cmdline and module lines of one entry. Up and Down move between them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryEditor {
+    entry: BootEntry,
+    /// A label and its line. Kernel, cmdline, then one `path args` line per module
+    fields: Vec<(&'static str, LineEditor)>,
+    current: usize,
+}
+
+/// Starts on it, since that is what usually needs changing
+const CMDLINE_FIELD: usize = 1;
+
+impl EntryEditor {
+    pub fn new(entry: &BootEntry) -> Self {
+        let mut fields = Vec::with_capacity(2 + entry.modules.len());
+        fields.push(("kernel", LineEditor::new(&entry.kernel)));
+        fields.push(("cmdline", LineEditor::new(&entry.cmdline)));
+        for module in &entry.modules {
+            let line = match module.args.is_empty() {
+                true => module.path.clone(),
+                false => format!("{} {}", module.path, module.args),
+            };
+            fields.push(("module", LineEditor::new(&line)));
+        }
+
+        Self {
+            entry: entry.clone(),
+            fields,
+            current: CMDLINE_FIELD,
+        }
+    }
+
+    pub fn fields(&self) -> &[(&'static str, LineEditor)] {
+        &self.fields
+    }
+
+    /// Index into fields
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn key(&mut self, key: MenuKey) -> EditAction {
+        let current = match key {
+            MenuKey::Enter => return EditAction::Boot,
+            MenuKey::Escape => return EditAction::Cancel,
+            MenuKey::Up => self.current.saturating_sub(1),
+            MenuKey::Down => (self.current + 1).min(self.fields.len() - 1),
+            key => match self.fields[self.current].1.key(key) {
+                true => return EditAction::Redraw,
+                false => return EditAction::None,
+            },
+        };
+
+        match current != self.current {
+            true => {
+                self.current = current;
+                EditAction::Redraw
+            }
+            false => EditAction::None,
+        }
+    }
+
+    /// The entry with the edits. A blank kernel line keeps the old kernel, a blank module line drops the module
+    pub fn entry(&self) -> BootEntry {
+        let mut entry = self.entry.clone();
+
+        let kernel = self.fields[0].1.text();
+        if !kernel.trim().is_empty() {
+            entry.kernel = kernel.trim().to_string();
+        }
+        entry.cmdline = self.fields[CMDLINE_FIELD].1.text().trim().to_string();
+        entry.modules = self.fields[CMDLINE_FIELD + 1..]
+            .iter()
+            .filter_map(|(_, line)| {
+                let line = line.text();
+                let line = line.trim();
+                let (path, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+
+                (!path.is_empty()).then(|| BootModule {
+                    path: path.to_string(),
+                    args: args.trim().to_string(),
+                })
+            })
+            .collect();
+
+        entry
+    }
+}
+
+// --------------
+// TEST
+// --------------
+
+#[cfg(test)]
+fn test_config() -> BootConfig {
+    BootConfig::parse(
+        "default debug\ntimeout 3\nentry release\nkernel /a.elf\nentry debug\nkernel /b.elf\nentry old\nkernel /c.elf\n",
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_menu_countdown() {
+    let mut menu = Menu::new(&test_config());
+    assert_eq!(menu.selected(), 1);
+    assert_eq!(menu.countdown(), Some(3));
+
+    assert_eq!(menu.tick(), MenuAction::Redraw);
+    assert_eq!(menu.tick(), MenuAction::Redraw);
+    assert_eq!(menu.countdown(), Some(1));
+    assert_eq!(menu.tick(), MenuAction::Boot(1));
+
+    // timeout 0 boots on the first tick
+    let mut config = test_config();
+    config.timeout = 0;
+    assert_eq!(Menu::new(&config).tick(), MenuAction::Boot(1));
+
+    // any key stops it for good
+    let mut menu = Menu::new(&test_config());
+    assert_eq!(menu.key(MenuKey::Escape), MenuAction::Redraw);
+    assert_eq!(menu.countdown(), None);
+    assert_eq!(menu.key(MenuKey::Escape), MenuAction::None);
+    for _ in 0..5 {
+        assert_eq!(menu.tick(), MenuAction::None);
+    }
+}
+
+#[test]
+fn test_menu_keys() {
+    let mut menu = Menu::new(&test_config());
+
+    assert_eq!(menu.key(MenuKey::Down), MenuAction::Redraw);
+    assert_eq!(menu.selected(), 2);
+    // stays on the last entry
+    assert_eq!(menu.key(MenuKey::Down), MenuAction::None);
+    assert_eq!(menu.key(MenuKey::Home), MenuAction::Redraw);
+    assert_eq!(menu.selected(), 0);
+    assert_eq!(menu.key(MenuKey::Up), MenuAction::None);
+    assert_eq!(menu.key(MenuKey::End), MenuAction::Redraw);
+    assert_eq!(menu.selected(), 2);
+
+    assert_eq!(menu.key(MenuKey::Char('2')), MenuAction::Redraw);
+    assert_eq!(menu.selected(), 1);
+    // no 4th entry
+    assert_eq!(menu.key(MenuKey::Char('4')), MenuAction::None);
+    assert_eq!(menu.key(MenuKey::Char('0')), MenuAction::None);
+
+    assert_eq!(menu.key(MenuKey::Enter), MenuAction::Boot(1));
+}
+
+#[test]
+fn test_line_editor() {
+    let mut line = LineEditor::new("quiet");
+    assert_eq!(line.cursor(), 5);
+
+    assert!(line.key(MenuKey::Home));
+    for c in "debug ".chars() {
+        assert!(line.key(MenuKey::Char(c)));
+    }
+    assert_eq!(line.text(), "debug quiet");
+    assert!(line.key(MenuKey::Delete));
+    assert!(line.key(MenuKey::Backspace));
+    assert_eq!(line.text(), "debuguiet");
+    assert_eq!(line.cursor(), 5);
+
+    // nothing to do at the edges
+    assert!(!line.key(MenuKey::Enter));
+    assert!(line.key(MenuKey::End));
+    assert!(!line.key(MenuKey::Right));
+    assert!(!line.key(MenuKey::Delete));
+    assert!(!line.key(MenuKey::Char('\t')));
+
+    // scrolled so the cursor after the end still shows
+    assert_eq!(line.view(4), ("iet".to_string(), 3));
+    line.key(MenuKey::Home);
+    assert_eq!(line.view(4), ("debu".to_string(), 0));
+    assert_eq!(line.view(80), ("debuguiet".to_string(), 0));
+}
+
+#[test]
+fn test_entry_editor() {
+    let config = BootConfig::parse(
+        "entry neutron\nkernel /neutron.elf\ncmdline quiet\nmodule /initrd.img initrd rw\nmodule /font.psf\n",
+    )
+    .unwrap();
+    let original = &config.entries[0];
+
+    let mut menu = Menu::new(&config);
+    assert_eq!(menu.key(MenuKey::Char('e')), MenuAction::Edit(0));
+
+    let mut editor = EntryEditor::new(original);
+    let labels: Vec<&str> = editor.fields().iter().map(|(label, _)| *label).collect();
+    assert_eq!(labels, ["kernel", "cmdline", "module", "module"]);
+    assert_eq!(editor.fields()[2].1.text(), "/initrd.img initrd rw");
+    assert_eq!(editor.entry(), *original);
+
+    // starts on the cmdline
+    assert_eq!(editor.key(MenuKey::Char(' ')), EditAction::Redraw);
+    assert_eq!(editor.key(MenuKey::Char('x')), EditAction::Redraw);
+    assert_eq!(editor.key(MenuKey::Up), EditAction::Redraw);
+    assert_eq!(editor.key(MenuKey::Up), EditAction::None);
+    for _ in 0.."/neutron.elf".len() {
+        editor.key(MenuKey::Backspace);
+    }
+    assert_eq!(editor.key(MenuKey::End), EditAction::None);
+    editor.key(MenuKey::Down);
+    editor.key(MenuKey::Down);
+    editor.key(MenuKey::Down);
+    assert_eq!(editor.current(), 3);
+    editor.key(MenuKey::Backspace);
+    editor.key(MenuKey::Backspace);
+    assert_eq!(editor.key(MenuKey::Down), EditAction::None);
+    assert_eq!(editor.key(MenuKey::Enter), EditAction::Boot);
+    assert_eq!(editor.key(MenuKey::Escape), EditAction::Cancel);
+
+    let edited = editor.entry();
+    // blank kernel keeps the old one
+    assert_eq!(edited.kernel, "/neutron.elf");
+    assert_eq!(edited.cmdline, "quiet x");
+    assert_eq!(edited.modules[0].args, "initrd rw");
+    assert_eq!(edited.modules[1].path, "/font.p");
+
+    // a blank module line drops it
+    let mut editor = EntryEditor::new(original);
+    editor.key(MenuKey::Down);
+    for _ in 0..30 {
+        editor.key(MenuKey::Backspace);
+    }
+    assert_eq!(editor.entry().modules.len(), 1);
+    // and the config itself never changes
+    assert_eq!(config.entries[0].cmdline, "quiet");
+}
@@ -1,6 +1,6 @@
 // use arcboot::memory;
 
-use arcboot::memory::map_segment;
+use arcboot_api::map_segment;
 
 extern crate std;
 
@@ -11,5 +11,8 @@ fn test_arcboot() {
     // #[cfg(target_arch = "aarch64")]
     // arcboot::arm64::trap_to_el2();
 
-    let x = map_segment(&[0; 1], 1);
+    let mut segment = [0xFFu8; 8];
+    unsafe { map_segment(&[1, 2, 3], segment.as_mut_ptr() as u64, 6) };
+
+    assert_eq!(segment, [1, 2, 3, 0, 0, 0, 0xFF, 0xFF]);
 }
@@ -0,0 +1,608 @@
+// ---------------
+// BOOT INFO TAGS
+// ---------------
+
+// Extra boot info as a list of typed tags, next to the fixed ArcServices struct. Kinda like multiboot2
+// Layout: BootInfoHeader, then tags. Each tag is a TagHeader + data, padded to 8 bytes. Ends with a TAG_END
+// Kernels skip tags they dont know, and tags can grow at the end, so older kernels keep working
+
+use alloc::vec::Vec;
+use core::{mem::size_of, str};
+
+use crate::{MemoryRegion, MemoryRegionType};
+
+/// "ARCTAGS\0"
+pub const BOOT_INFO_MAGIC: u64 = u64::from_le_bytes(*b"ARCTAGS\0");
+/// Only bumped if the header or tag layout changes. New tags, or new fields at the end of a tag, dont need it
+pub const BOOT_INFO_VERSION: u32 = 1;
+
+pub const TAG_END: u32 = 0;
+/// UTF-8 kernel command line
+pub const TAG_COMMAND_LINE: u32 = 1;
+/// A file arcboot loaded for the kernel (initrd, drivers...)
+pub const TAG_MODULE: u32 = 2;
+pub const TAG_MEMORY_MAP: u32 = 3;
+pub const TAG_FRAMEBUFFER: u32 = 4;
+/// Paddr of the ACPI RSDP
+pub const TAG_RSDP: u32 = 5;
+/// Paddr and size of the device tree blob
+pub const TAG_DTB: u32 = 6;
+/// Paddr of the EFI system table, for runtime services
+pub const TAG_EFI_SYSTEM_TABLE: u32 = 7;
+/// Random bytes for seeding the kernel's RNG
+pub const TAG_RNG_SEED: u32 = 8;
+pub const TAG_BOOT_TIMINGS: u32 = 9;
+
+/// Tags start and end on 8 byte boundaries
+const TAG_ALIGN: usize = 8;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootInfoHeader {
+    /// BOOT_INFO_MAGIC
+    pub magic: u64,
+    /// BOOT_INFO_VERSION of the writer
+    pub version: u32,
+    /// Header + all tags, in bytes
+    pub total_size: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TagHeader {
+    pub tag_type: u32,
+    /// TagHeader + data, not counting the padding after it
+    pub size: u32,
+}
+
+const HEADER_SIZE: usize = size_of::<BootInfoHeader>();
+const TAG_HEADER_SIZE: usize = size_of::<TagHeader>();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Framebuffer {
+    pub addr: u64,
+    /// Bytes per row
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bits_per_pixel: u32,
+}
+
+/// ARM generic timer ticks (CNTPCT) at each point of the boot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BootTimings {
+    /// CNTFRQ, ticks per second
+    pub frequency: u64,
+    pub arcboot_entry: u64,
+    pub kernel_loaded: u64,
+    pub kernel_entry: u64,
+}
+
+/// One memory map entry. `region_type` is kept raw since a newer arcboot may have types this kernel doesnt know
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryMapEntry {
+    pub region_type: u32,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl MemoryMapEntry {
+    /// None for types newer than this kernel. Treat those as reserved
+    pub fn region_type(&self) -> Option<MemoryRegionType> {
+        MemoryRegionType::from_u32(self.region_type)
+    }
+}
+
+/// Keeps the raw type, so a region arcboot doesnt know the type of still gets passed on as it is
+impl From<&MemoryRegion> for MemoryMapEntry {
+    fn from(region: &MemoryRegion) -> Self {
+        Self {
+            region_type: region.region_type,
+            start: region.start,
+            end: region.end,
+        }
+    }
+}
+
+/// Bytes of a memory map entry as arcboot writes it. Entries can get bigger in later versions
+const MEMORY_MAP_ENTRY_SIZE: usize = 24;
+
+/// Entries of a TAG_MEMORY_MAP, read straight out of the tag
+#[derive(Debug, Clone)]
+pub struct MemoryMapEntries<'a> {
+    entry_size: usize,
+    entries: &'a [u8],
+}
+
+impl<'a> Iterator for MemoryMapEntries<'a> {
+    type Item = MemoryMapEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.entries.len() < self.entry_size {
+            return None;
+        }
+
+        let (entry, rest) = self.entries.split_at(self.entry_size);
+        self.entries = rest;
+
+        Some(MemoryMapEntry {
+            region_type: read_u32(entry, 0),
+            start: read_u64(entry, 8),
+            end: read_u64(entry, 16),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Module<'a> {
+    /// Paddr of the first byte
+    pub start: u64,
+    /// Paddr after the last byte
+    pub end: u64,
+    pub name: &'a str,
+}
+
+/// A decoded tag. Borrows from the boot info bytes, nothing is copied except small fixed fields
+#[derive(Debug, Clone)]
+pub enum Tag<'a> {
+    CommandLine(&'a str),
+    Module(Module<'a>),
+    MemoryMap(MemoryMapEntries<'a>),
+    Framebuffer(Framebuffer),
+    Rsdp(u64),
+    Dtb {
+        addr: u64,
+        size: u64,
+    },
+    EfiSystemTable(u64),
+    RngSeed(&'a [u8]),
+    BootTimings(BootTimings),
+    /// From a newer arcboot
+    Unknown {
+        tag_type: u32,
+        data: &'a [u8],
+    },
+}
+
+impl<'a> Tag<'a> {
+    /// None if the tag is too short for its type. Longer is fine, the extra is from a newer version
+    fn decode(tag_type: u32, data: &'a [u8]) -> Option<Self> {
+        let has = |n: usize| data.len() >= n;
+
+        let tag = match tag_type {
+            TAG_COMMAND_LINE => Tag::CommandLine(str::from_utf8(data).ok()?),
+            TAG_MODULE if has(16) => Tag::Module(Module {
+                start: read_u64(data, 0),
+                end: read_u64(data, 8),
+                name: str::from_utf8(&data[16..]).ok()?,
+            }),
+            TAG_MEMORY_MAP if has(8) => {
+                let entry_size = read_u32(data, 0) as usize;
+                if entry_size < MEMORY_MAP_ENTRY_SIZE {
+                    return None;
+                }
+                Tag::MemoryMap(MemoryMapEntries {
+                    entry_size,
+                    entries: &data[8..],
+                })
+            }
+            TAG_FRAMEBUFFER if has(24) => Tag::Framebuffer(Framebuffer {
+                addr: read_u64(data, 0),
+                pitch: read_u32(data, 8),
+                width: read_u32(data, 12),
+                height: read_u32(data, 16),
+                bits_per_pixel: read_u32(data, 20),
+            }),
+            TAG_RSDP if has(8) => Tag::Rsdp(read_u64(data, 0)),
+            TAG_DTB if has(16) => Tag::Dtb {
+                addr: read_u64(data, 0),
+                size: read_u64(data, 8),
+            },
+            TAG_EFI_SYSTEM_TABLE if has(8) => Tag::EfiSystemTable(read_u64(data, 0)),
+            TAG_RNG_SEED => Tag::RngSeed(data),
+            TAG_BOOT_TIMINGS if has(32) => Tag::BootTimings(BootTimings {
+                frequency: read_u64(data, 0),
+                arcboot_entry: read_u64(data, 8),
+                kernel_loaded: read_u64(data, 16),
+                kernel_entry: read_u64(data, 24),
+            }),
+            // known, but too short
+            TAG_MODULE | TAG_MEMORY_MAP | TAG_FRAMEBUFFER | TAG_RSDP | TAG_DTB
+            | TAG_EFI_SYSTEM_TABLE | TAG_BOOT_TIMINGS => return None,
+            _ => Tag::Unknown { tag_type, data },
+        };
+
+        Some(tag)
+    }
+}
+
+// ---------------
+// READER
+// ---------------
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootInfoError {
+    /// Not boot info, or the pointer is wrong
+    BadMagic,
+    /// From a version of the format this kernel cant read
+    UnsupportedVersion(u32),
+    /// total_size or a tag's size goes past the end of the bytes
+    Truncated,
+    /// A known tag is too short or isnt valid UTF-8
+    BadTag { tag_type: u32 },
+    /// No TAG_END
+    MissingEnd,
+}
+
+/// Boot info handed over by arcboot. Checked once in parse(), after that reading it cant fail
+#[derive(Debug, Clone, Copy)]
+pub struct BootInfo<'a> {
+    /// Tags, without the header
+    tags: &'a [u8],
+}
+
+impl<'a> BootInfo<'a> {
+    /// Check the header and every tag
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, BootInfoError> {
+        if bytes.len() < HEADER_SIZE || read_u64(bytes, 0) != BOOT_INFO_MAGIC {
+            return Err(BootInfoError::BadMagic);
+        }
+
+        let version = read_u32(bytes, 8);
+        if version == 0 || version > BOOT_INFO_VERSION {
+            return Err(BootInfoError::UnsupportedVersion(version));
+        }
+
+        let total_size = read_u32(bytes, 12) as usize;
+        if total_size < HEADER_SIZE || total_size > bytes.len() {
+            return Err(BootInfoError::Truncated);
+        }
+
+        let tags = &bytes[HEADER_SIZE..total_size];
+
+        let mut offset = 0;
+        loop {
+            let (tag_type, data, next) = next_tag(tags, offset).ok_or(if offset >= tags.len() {
+                BootInfoError::MissingEnd
+            } else {
+                BootInfoError::Truncated
+            })?;
+            if tag_type == TAG_END {
+                break;
+            }
+            if Tag::decode(tag_type, data).is_none() {
+                return Err(BootInfoError::BadTag { tag_type });
+            }
+            offset = next;
+        }
+
+        Ok(Self { tags })
+    }
+
+    /// Read boot info from where arcboot put it
+    /// # Safety
+    /// `ptr` has to be readable for the header, and for total_size bytes if the magic matches
+    pub unsafe fn from_ptr(ptr: *const u8) -> Result<Self, BootInfoError> {
+        let header = core::slice::from_raw_parts(ptr, HEADER_SIZE);
+        if read_u64(header, 0) != BOOT_INFO_MAGIC {
+            return Err(BootInfoError::BadMagic);
+        }
+
+        let total_size = read_u32(header, 12) as usize;
+        Self::parse(core::slice::from_raw_parts(
+            ptr,
+            total_size.max(HEADER_SIZE),
+        ))
+    }
+
+    pub fn tags(&self) -> Tags<'a> {
+        Tags {
+            tags: self.tags,
+            offset: 0,
+        }
+    }
+
+    pub fn command_line(&self) -> Option<&'a str> {
+        self.tags().find_map(|t| match t {
+            Tag::CommandLine(cmdline) => Some(cmdline),
+            _ => None,
+        })
+    }
+
+    pub fn memory_map(&self) -> Option<MemoryMapEntries<'a>> {
+        self.tags().find_map(|t| match t {
+            Tag::MemoryMap(entries) => Some(entries),
+            _ => None,
+        })
+    }
+
+    pub fn modules(&self) -> impl Iterator<Item = Module<'a>> {
+        self.tags().filter_map(|t| match t {
+            Tag::Module(module) => Some(module),
+            _ => None,
+        })
+    }
+}
+
+/// Iterator over the tags of a parsed BootInfo, up to TAG_END
+#[derive(Debug, Clone)]
+pub struct Tags<'a> {
+    tags: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for Tags<'a> {
+    type Item = Tag<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (tag_type, data, next) = next_tag(self.tags, self.offset)?;
+        if tag_type == TAG_END {
+            return None;
+        }
+
+        self.offset = next;
+        // BootInfo::parse already checked every tag decodes
+        Tag::decode(tag_type, data)
+    }
+}
+
+/// (type, data, offset of the next tag) of the tag at `offset`. None if it doesnt fit
+fn next_tag(tags: &[u8], offset: usize) -> Option<(u32, &[u8], usize)> {
+    let header = tags.get(offset..offset.checked_add(TAG_HEADER_SIZE)?)?;
+    let tag_type = read_u32(header, 0);
+    let size = read_u32(header, 4) as usize;
+    if size < TAG_HEADER_SIZE {
+        return None;
+    }
+
+    let data = tags.get(offset + TAG_HEADER_SIZE..offset.checked_add(size)?)?;
+    Some((tag_type, data, offset + align_up(size)))
+}
+
+fn align_up(n: usize) -> usize {
+    (n + TAG_ALIGN - 1) & !(TAG_ALIGN - 1)
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&bytes[at..at + 4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[at..at + 8]);
+    u64::from_le_bytes(buf)
+}
+
+// ---------------
+// WRITER
+// ---------------
+
+/// Builds boot info for the kernel. Add tags, then finish()
+pub struct BootInfoWriter {
+    bytes: Vec<u8>,
+}
+
+impl BootInfoWriter {
+    pub fn new() -> Self {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&BOOT_INFO_MAGIC.to_le_bytes());
+        bytes.extend_from_slice(&BOOT_INFO_VERSION.to_le_bytes());
+        // total_size, filled in by finish()
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+
+        Self { bytes }
+    }
+
+    /// Add a tag of any type. The known ones have their own methods
+    pub fn tag(&mut self, tag_type: u32, data: &[u8]) -> &mut Self {
+        self.tag_parts(tag_type, &[data])
+    }
+
+    fn tag_parts(&mut self, tag_type: u32, parts: &[&[u8]]) -> &mut Self {
+        let size = TAG_HEADER_SIZE + parts.iter().map(|p| p.len()).sum::<usize>();
+
+        self.bytes.extend_from_slice(&tag_type.to_le_bytes());
+        self.bytes.extend_from_slice(&(size as u32).to_le_bytes());
+        for part in parts {
+            self.bytes.extend_from_slice(part);
+        }
+        self.bytes.resize(align_up(self.bytes.len()), 0);
+
+        self
+    }
+
+    pub fn command_line(&mut self, cmdline: &str) -> &mut Self {
+        self.tag(TAG_COMMAND_LINE, cmdline.as_bytes())
+    }
+
+    pub fn module(&mut self, start: u64, end: u64, name: &str) -> &mut Self {
+        self.tag_parts(
+            TAG_MODULE,
+            &[&start.to_le_bytes(), &end.to_le_bytes(), name.as_bytes()],
+        )
+    }
+
+    pub fn memory_map(&mut self, entries: &[MemoryMapEntry]) -> &mut Self {
+        let mut data = Vec::with_capacity(8 + entries.len() * MEMORY_MAP_ENTRY_SIZE);
+        data.extend_from_slice(&(MEMORY_MAP_ENTRY_SIZE as u32).to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        for entry in entries {
+            data.extend_from_slice(&entry.region_type.to_le_bytes());
+            data.extend_from_slice(&0u32.to_le_bytes());
+            data.extend_from_slice(&entry.start.to_le_bytes());
+            data.extend_from_slice(&entry.end.to_le_bytes());
+        }
+
+        self.tag(TAG_MEMORY_MAP, &data)
+    }
+
+    pub fn framebuffer(&mut self, framebuffer: Framebuffer) -> &mut Self {
+        self.tag_parts(
+            TAG_FRAMEBUFFER,
+            &[
+                &framebuffer.addr.to_le_bytes(),
+                &framebuffer.pitch.to_le_bytes(),
+                &framebuffer.width.to_le_bytes(),
+                &framebuffer.height.to_le_bytes(),
+                &framebuffer.bits_per_pixel.to_le_bytes(),
+            ],
+        )
+    }
+
+    pub fn rsdp(&mut self, rsdp: u64) -> &mut Self {
+        self.tag(TAG_RSDP, &rsdp.to_le_bytes())
+    }
+
+    pub fn dtb(&mut self, addr: u64, size: u64) -> &mut Self {
+        self.tag_parts(TAG_DTB, &[&addr.to_le_bytes(), &size.to_le_bytes()])
+    }
+
+    pub fn efi_system_table(&mut self, system_table: u64) -> &mut Self {
+        self.tag(TAG_EFI_SYSTEM_TABLE, &system_table.to_le_bytes())
+    }
+
+    pub fn rng_seed(&mut self, seed: &[u8]) -> &mut Self {
+        self.tag(TAG_RNG_SEED, seed)
+    }
+
+    pub fn boot_timings(&mut self, timings: BootTimings) -> &mut Self {
+        self.tag_parts(
+            TAG_BOOT_TIMINGS,
+            &[
+                &timings.frequency.to_le_bytes(),
+                &timings.arcboot_entry.to_le_bytes(),
+                &timings.kernel_loaded.to_le_bytes(),
+                &timings.kernel_entry.to_le_bytes(),
+            ],
+        )
+    }
+
+    /// Add TAG_END and fill in the size
+    pub fn finish(mut self) -> Vec<u8> {
+        self.tag(TAG_END, &[]);
+
+        let total_size = (self.bytes.len() as u32).to_le_bytes();
+        self.bytes[12..16].copy_from_slice(&total_size);
+
+        self.bytes
+    }
+}
+
+impl Default for BootInfoWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ---------------
+// TESTS
+// ---------------
+
+#[test]
+fn boot_info_round_trip() {
+    let memory_map = [
+        MemoryMapEntry {
+            region_type: MemoryRegionType::Standard as u32,
+            start: 0x4000_0000,
+            end: 0x8000_0000,
+        },
+        MemoryMapEntry {
+            region_type: MemoryRegionType::MMIO as u32,
+            start: 0x0900_0000,
+            end: 0x0900_1000,
+        },
+    ];
+    assert_eq!(
+        MemoryMapEntry::from(&MemoryRegion::new(
+            MemoryRegionType::MMIO,
+            (0x0900_0000, 0x0900_1000)
+        )),
+        memory_map[1]
+    );
+    let timings = BootTimings {
+        frequency: 62_500_000,
+        arcboot_entry: 100,
+        kernel_loaded: 200,
+        kernel_entry: 300,
+    };
+
+    let mut writer = BootInfoWriter::new();
+    writer
+        .command_line("console=ttyAMA0 quiet")
+        .memory_map(&memory_map)
+        .module(0x4800_0000, 0x4810_0000, "initrd")
+        .rsdp(0x7FFF_0000)
+        .dtb(0x4000_0000, 0x10_0000)
+        .rng_seed(&[1, 2, 3])
+        .boot_timings(timings)
+        // something from a newer arcboot
+        .tag(0x100, &[0xAA; 5]);
+    let bytes = writer.finish();
+
+    let info = BootInfo::parse(&bytes).unwrap();
+    assert_eq!(info.command_line(), Some("console=ttyAMA0 quiet"));
+    assert!(info.memory_map().unwrap().eq(memory_map.iter().copied()));
+    assert_eq!(
+        info.modules().collect::<Vec<_>>(),
+        [Module {
+            start: 0x4800_0000,
+            end: 0x4810_0000,
+            name: "initrd"
+        }]
+    );
+
+    let tags: Vec<Tag> = info.tags().collect();
+    assert_eq!(tags.len(), 8);
+    assert!(matches!(tags[3], Tag::Rsdp(0x7FFF_0000)));
+    assert!(matches!(tags[5], Tag::RngSeed(&[1, 2, 3])));
+    assert!(matches!(tags[6], Tag::BootTimings(t) if t == timings));
+    assert!(matches!(
+        tags[7],
+        Tag::Unknown {
+            tag_type: 0x100,
+            data: &[0xAA, 0xAA, 0xAA, 0xAA, 0xAA]
+        }
+    ));
+}
+
+#[test]
+fn boot_info_is_checked() {
+    let mut writer = BootInfoWriter::new();
+    writer.command_line("quiet").rsdp(0x1000);
+    let bytes = writer.finish();
+
+    assert_eq!(
+        BootInfo::parse(&bytes[..8]).err(),
+        Some(BootInfoError::BadMagic)
+    );
+    assert_eq!(
+        BootInfo::parse(&bytes[..bytes.len() - 8]).err(),
+        Some(BootInfoError::Truncated)
+    );
+
+    // a newer version of a known tag is longer, and still reads fine
+    let mut writer = BootInfoWriter::new();
+    writer.tag(TAG_RSDP, &[1, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF]);
+    let bytes = writer.finish();
+    let info = BootInfo::parse(&bytes).unwrap();
+    assert!(matches!(info.tags().next(), Some(Tag::Rsdp(1))));
+
+    // but too short is an error
+    let mut writer = BootInfoWriter::new();
+    writer.tag(TAG_RSDP, &[1, 0, 0, 0]);
+    assert_eq!(
+        BootInfo::parse(&writer.finish()).err(),
+        Some(BootInfoError::BadTag { tag_type: TAG_RSDP })
+    );
+
+    // no end tag
+    let mut bytes = BootInfoWriter::new().finish();
+    bytes.truncate(HEADER_SIZE);
+    bytes[12..16].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+    assert_eq!(
+        BootInfo::parse(&bytes).err(),
+        Some(BootInfoError::MissingEnd)
+    );
+}
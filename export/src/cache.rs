@@ -0,0 +1,162 @@
+// ---------------
+// AARCH64 CACHE AND TLB MAINTENANCE
+// ---------------
+
+// By VA, so whatever is passed has to be mapped. Every operation waits for itself to finish (DSB) before returning
+// Shared with kernels so arcboot and the kernel agree on what "cleaned" means
+
+use core::arch::asm;
+
+/// CTR_EL0.IDC. Cleaning to PoU isnt needed for the instruction side to see data writes
+const CTR_IDC: u64 = 1 << 28;
+/// CTR_EL0.DIC. Invalidating the instruction cache isnt needed for it to see data writes
+const CTR_DIC: u64 = 1 << 29;
+
+fn ctr() -> u64 {
+    let ctr: u64;
+    unsafe { asm!("mrs {}, ctr_el0", out(reg) ctr, options(nomem, nostack)) };
+
+    ctr
+}
+
+/// Smallest data cache line in bytes, from CTR_EL0.DminLine
+pub fn dcache_line_size() -> u64 {
+    4 << ((ctr() >> 16) & 0xF)
+}
+
+/// Smallest instruction cache line in bytes, from CTR_EL0.IminLine
+pub fn icache_line_size() -> u64 {
+    4 << (ctr() & 0xF)
+}
+
+/// Run op on the address of every line start..start + size touches
+macro_rules! by_line {
+    ($op:literal, $start:expr, $size:expr, $line:expr) => {{
+        let line = $line;
+        let end = $start + $size;
+        let mut addr = $start & !(line - 1);
+        while addr < end {
+            asm!(concat!($op, ", {}"), in(reg) addr, options(nostack));
+            addr += line;
+        }
+    }};
+}
+
+/// DC CVAC. Write dirty lines back to the point of coherency, e.g. for something that reads memory with its caches off
+/// # Safety
+/// start..start + size has to be mapped
+pub unsafe fn clean_dcache_range(start: u64, size: u64) {
+    by_line!("dc cvac", start, size, dcache_line_size());
+    asm!("dsb sy", options(nostack));
+}
+
+/// DC CIVAC. Write dirty lines back and drop them, e.g. before something writes memory behind the caches' back
+/// # Safety
+/// start..start + size has to be mapped
+pub unsafe fn clean_invalidate_dcache_range(start: u64, size: u64) {
+    by_line!("dc civac", start, size, dcache_line_size());
+    asm!("dsb sy", options(nostack));
+}
+
+/// DC CVAU. Write dirty lines back to the point of unification, where instruction fetches can see them
+/// # Safety
+/// start..start + size has to be mapped
+pub unsafe fn clean_dcache_range_pou(start: u64, size: u64) {
+    by_line!("dc cvau", start, size, dcache_line_size());
+    asm!("dsb ish", options(nostack));
+}
+
+/// IC IVAU. Drop the instruction cache lines, so the next fetch goes to the point of unification
+/// # Safety
+/// start..start + size has to be mapped
+pub unsafe fn invalidate_icache_range(start: u64, size: u64) {
+    by_line!("ic ivau", start, size, icache_line_size());
+    asm!("dsb ish", "isb", options(nostack));
+}
+
+/// IC IALLU. Drop the whole instruction cache of this core
+pub fn invalidate_icache_all() {
+    unsafe { asm!("ic iallu", "dsb nsh", "isb", options(nostack)) };
+}
+
+/// Make code written through the data cache executable. Skips whatever CTR_EL0 says the core doesnt need
+/// # Safety
+/// start..start + size has to be mapped
+pub unsafe fn sync_code(start: u64, size: u64) {
+    let ctr = ctr();
+    if ctr & CTR_IDC == 0 {
+        clean_dcache_range_pou(start, size);
+    } else {
+        asm!("dsb ish", options(nostack));
+    }
+    if ctr & CTR_DIC == 0 {
+        invalidate_icache_range(start, size);
+    } else {
+        asm!("isb", options(nostack));
+    }
+}
+
+// TLB operations are broadcast to the inner shareable domain. VAs go in as VA[55:12], whatever the granule
+
+/// TLBI VAAE1IS. Every ASID's entries for the page (or block) at vaddr
+pub fn tlbi_va(vaddr: u64) {
+    let operand = (vaddr >> 12) & ((1 << 44) - 1);
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "tlbi vaae1is, {}",
+            "dsb ish",
+            "isb",
+            in(reg) operand,
+            options(nostack)
+        )
+    };
+}
+
+/// TLBI VAE1IS. The entries for the page (or block) at vaddr tagged with asid, and global ones
+pub fn tlbi_va_asid(vaddr: u64, asid: u16) {
+    let operand = (asid as u64) << 48 | (vaddr >> 12) & ((1 << 44) - 1);
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "tlbi vae1is, {}",
+            "dsb ish",
+            "isb",
+            in(reg) operand,
+            options(nostack)
+        )
+    };
+}
+
+/// TLBI ASIDE1IS. Every non global entry tagged with asid
+pub fn tlbi_asid(asid: u16) {
+    let operand = (asid as u64) << 48;
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "tlbi aside1is, {}",
+            "dsb ish",
+            "isb",
+            in(reg) operand,
+            options(nostack)
+        )
+    };
+}
+
+/// TLBI VMALLE1IS. Every EL1&0 entry
+pub fn tlbi_all() {
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "tlbi vmalle1is",
+            "dsb ish",
+            "isb",
+            options(nostack)
+        )
+    };
+}
+
+/// Make table writes visible to the table walker. Enough for new entries, which the TLB never held
+pub fn tables_written() {
+    unsafe { asm!("dsb ishst", "isb", options(nostack)) };
+}
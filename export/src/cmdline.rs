@@ -0,0 +1,209 @@
+// ---------------
+// COMMAND LINE
+// ---------------
+
+// Splitting the kernel command line from TAG_COMMAND_LINE into arguments. Nothing is copied, args borrow from the line
+// Args are separated by whitespace. Double quotes group spaces into one arg and are dropped from its key and value, like Linux:
+//
+//   console=ttyAMA0 init="/bin/sh -x" quiet -- single
+//
+// is console=ttyAMA0, init=/bin/sh -x and quiet. Everything after a lone -- is left for init, see CommandLine::init_args()
+
+/// A kernel command line, see ArcServices::command_line()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandLine<'a> {
+    line: &'a str,
+}
+
+impl<'a> CommandLine<'a> {
+    pub fn new(line: &'a str) -> Self {
+        Self { line }
+    }
+
+    /// The whole line, as arcboot passed it
+    pub fn as_str(&self) -> &'a str {
+        self.line
+    }
+
+    /// The kernel's args, up to a lone --
+    pub fn args(&self) -> Args<'a> {
+        Args {
+            rest: self.line,
+            stop_at_separator: true,
+        }
+    }
+
+    /// Args after the first lone --, for init. Empty if there isnt one
+    pub fn init_args(&self) -> Args<'a> {
+        let mut args = self.args();
+        while args.next().is_some() {}
+        // args stopped right before the --, or at the end
+        let rest = args.rest.trim_start();
+        let rest = rest.strip_prefix("--").unwrap_or(rest);
+
+        Args {
+            rest,
+            stop_at_separator: false,
+        }
+    }
+
+    /// The last arg called key, since later args override earlier ones
+    pub fn get(&self, key: &str) -> Option<Arg<'a>> {
+        self.args().filter(|arg| arg.key == key).last()
+    }
+
+    /// Whether key was passed at all, with or without a value
+    pub fn has(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// The value of the last key=value. None for a bare key too
+    pub fn value(&self, key: &str) -> Option<&'a str> {
+        self.get(key)?.value
+    }
+}
+
+/// One `key` or `key=value`, with quotes taken off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Arg<'a> {
+    pub key: &'a str,
+    pub value: Option<&'a str>,
+}
+
+impl<'a> Arg<'a> {
+    /// Split a single token at its first = outside quotes
+    pub fn parse(token: &'a str) -> Self {
+        let mut quoted = false;
+        for (i, c) in token.char_indices() {
+            match c {
+                '"' => quoted = !quoted,
+                '=' if !quoted => {
+                    return Self {
+                        key: unquote(&token[..i]),
+                        value: Some(unquote(&token[i + 1..])),
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Self {
+            key: unquote(token),
+            value: None,
+        }
+    }
+}
+
+/// Iterator over the args of a CommandLine
+#[derive(Debug, Clone)]
+pub struct Args<'a> {
+    rest: &'a str,
+    stop_at_separator: bool,
+}
+
+impl<'a> Iterator for Args<'a> {
+    type Item = Arg<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = self.rest.trim_start();
+        if line.is_empty() {
+            self.rest = line;
+            return None;
+        }
+
+        // the token ends at whitespace outside quotes. An unclosed quote runs to the end
+        let mut quoted = false;
+        let end = line
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    quoted = !quoted;
+                }
+                c.is_whitespace() && !quoted
+            })
+            .map_or(line.len(), |(i, _)| i);
+        let token = &line[..end];
+
+        if token == "--" && self.stop_at_separator {
+            // left in rest for init_args()
+            self.rest = line;
+            return None;
+        }
+        self.rest = &line[end..];
+
+        Some(Arg::parse(token))
+    }
+}
+
+/// Drop the quotes around a key or value, or the leading one of an unclosed quote
+fn unquote(s: &str) -> &str {
+    let s = s.strip_prefix('"').unwrap_or(s);
+    s.strip_suffix('"').unwrap_or(s)
+}
+
+// ---------------
+// TESTS
+// ---------------
+
+#[cfg(test)]
+fn keys<'a>(args: Args<'a>) -> alloc::vec::Vec<&'a str> {
+    args.map(|arg| arg.key).collect()
+}
+
+#[test]
+fn command_line_args() {
+    let cmdline = CommandLine::new(
+        "  console=ttyAMA0 init=\"/bin/sh -x\" quiet\t\"a b\" log=info log=debug ",
+    );
+
+    let args: alloc::vec::Vec<Arg> = cmdline.args().collect();
+    assert_eq!(args.len(), 6);
+    assert_eq!(
+        args[0],
+        Arg {
+            key: "console",
+            value: Some("ttyAMA0")
+        }
+    );
+    assert_eq!(args[1].value, Some("/bin/sh -x"));
+    assert_eq!(
+        args[3],
+        Arg {
+            key: "a b",
+            value: None
+        }
+    );
+
+    assert_eq!(cmdline.value("init"), Some("/bin/sh -x"));
+    // the last one wins
+    assert_eq!(cmdline.value("log"), Some("debug"));
+    assert!(cmdline.has("quiet"));
+    assert_eq!(cmdline.value("quiet"), None);
+    assert!(!cmdline.has("console=ttyAMA0"));
+
+    assert_eq!(Arg::parse("a=b=c").value, Some("b=c"));
+    assert_eq!(Arg::parse("\"a=b\"=c").key, "a=b");
+    assert_eq!(Arg::parse("empty=").value, Some(""));
+    // unclosed quotes run to the end of the line
+    assert_eq!(keys(CommandLine::new("x=\"a b c").args()), ["x"]);
+    assert_eq!(CommandLine::new("").args().count(), 0);
+}
+
+#[test]
+fn command_line_init_args() {
+    let cmdline = CommandLine::new("quiet -- single  --verbose -- x");
+    assert_eq!(keys(cmdline.args()), ["quiet"]);
+    // only the first -- splits
+    assert_eq!(
+        keys(cmdline.init_args()),
+        ["single", "--verbose", "--", "x"]
+    );
+    assert!(!cmdline.has("single"));
+
+    // -- inside a token, or quoted, is just an arg
+    let cmdline = CommandLine::new("a--b \"--\" c");
+    assert_eq!(keys(cmdline.args()), ["a--b", "--", "c"]);
+    assert_eq!(cmdline.init_args().count(), 0);
+
+    assert_eq!(keys(CommandLine::new("--").init_args()).len(), 0);
+}
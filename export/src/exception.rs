@@ -0,0 +1,509 @@
+// ---------------
+// AARCH64 EXCEPTIONS
+// ---------------
+
+// What a vector table entry saves, and what ESR_ELx says about a synchronous exception.
+// Plain data, so arcboot's handlers and a kernel's can share the decoding
+
+use crate::InterruptHandler;
+use core::fmt;
+
+/// Bytes between vector table entries
+pub const VECTOR_ENTRY_SIZE: u64 = 0x80;
+/// VBAR_ELx needs the table 2K aligned
+pub const VECTOR_TABLE_ALIGN: u64 = 2048;
+/// 4 sources of 4 kinds
+pub const VECTOR_ENTRIES: usize = 16;
+
+/// Everything the interrupted code had in registers. The layout is fixed, the vector table's stp's write it
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrapFrame {
+    /// x0 to x30. x29 is the frame pointer, x30 the link register
+    pub x: [u64; 31],
+    /// Where eret goes back to
+    pub elr: u64,
+    /// PSTATE of the interrupted code
+    pub spsr: u64,
+    /// Syndrome. Only means anything for sync and SError
+    pub esr: u64,
+    /// Faulting address, for aborts
+    pub far: u64,
+    /// VectorSlot::index of the entry that was taken
+    pub slot: u64,
+}
+
+/// Size the vector table's sub sp allocates
+pub const TRAP_FRAME_SIZE: u64 = core::mem::size_of::<TrapFrame>() as u64;
+
+impl TrapFrame {
+    pub fn vector_slot(&self) -> Option<VectorSlot> {
+        VectorSlot::from_index(self.slot as usize)
+    }
+
+    pub fn syndrome(&self) -> Esr {
+        Esr(self.esr)
+    }
+
+    /// Step over the instruction that trapped, e.g. a BRK that was only there to stop. SVCs already point past theirs
+    pub fn skip_instruction(&mut self) {
+        let length = if self.syndrome().is_32bit_instruction() {
+            4
+        } else {
+            2
+        };
+        self.elr += length;
+    }
+}
+
+/// Register dump, 3 to a line
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, reg) in self.x.iter().enumerate() {
+            let sep = if i % 3 == 2 || i == 30 { "\n" } else { "  " };
+            write!(f, "x{i:<2} = {reg:#018X}{sep}")?;
+        }
+        writeln!(f, "ELR  = {:#018X}  SPSR = {:#018X}", self.elr, self.spsr)?;
+        write!(f, "ESR  = {:#018X}  FAR  = {:#018X}", self.esr, self.far)
+    }
+}
+
+// ---------------
+// VECTOR SLOTS
+// ---------------
+
+/// Where the exception came from. Picks the 512 byte quarter of the table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionSource {
+    /// Same EL, running on SP_EL0
+    CurrentSp0,
+    /// Same EL, running on SP_ELx. Where arcboot and most kernels are
+    CurrentSpx,
+    /// A lower EL running AArch64
+    LowerA64,
+    /// A lower EL running AArch32
+    LowerA32,
+}
+
+/// Which entry of the quarter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorKind {
+    Sync,
+    Irq,
+    Fiq,
+    SError,
+}
+
+/// One of the 16 entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VectorSlot {
+    pub source: ExceptionSource,
+    pub kind: VectorKind,
+}
+
+impl VectorSlot {
+    const SOURCES: [ExceptionSource; 4] = [
+        ExceptionSource::CurrentSp0,
+        ExceptionSource::CurrentSpx,
+        ExceptionSource::LowerA64,
+        ExceptionSource::LowerA32,
+    ];
+    const KINDS: [VectorKind; 4] = [
+        VectorKind::Sync,
+        VectorKind::Irq,
+        VectorKind::Fiq,
+        VectorKind::SError,
+    ];
+
+    pub const fn new(source: ExceptionSource, kind: VectorKind) -> Self {
+        Self { source, kind }
+    }
+
+    pub fn from_index(index: usize) -> Option<Self> {
+        (index < VECTOR_ENTRIES)
+            .then(|| Self::new(Self::SOURCES[index / 4], Self::KINDS[index % 4]))
+    }
+
+    /// Entry number, table order
+    pub fn index(&self) -> usize {
+        self.source as usize * 4 + self.kind as usize
+    }
+
+    /// Offset of the entry from VBAR_ELx
+    pub fn offset(&self) -> u64 {
+        self.index() as u64 * VECTOR_ENTRY_SIZE
+    }
+}
+
+impl fmt::Display for VectorSlot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let source = match self.source {
+            ExceptionSource::CurrentSp0 => "current EL (SP0)",
+            ExceptionSource::CurrentSpx => "current EL (SPx)",
+            ExceptionSource::LowerA64 => "lower EL (AArch64)",
+            ExceptionSource::LowerA32 => "lower EL (AArch32)",
+        };
+        let kind = match self.kind {
+            VectorKind::Sync => "Sync",
+            VectorKind::Irq => "IRQ",
+            VectorKind::Fiq => "FIQ",
+            VectorKind::SError => "SError",
+        };
+
+        write!(f, "{kind} from {source}")
+    }
+}
+
+// ---------------
+// LINKED HANDLERS
+// ---------------
+
+/// What #[register_exception_handler] leaves in the arcboot_exception_handlers section, one per handler
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ExceptionHandlerDescriptor {
+    /// VectorSlot::index
+    pub slot: u64,
+    pub handler: InterruptHandler,
+}
+
+impl ExceptionHandlerDescriptor {
+    pub const fn new(slot: usize, handler: InterruptHandler) -> Self {
+        Self {
+            slot: slot as u64,
+            handler,
+        }
+    }
+
+    pub fn vector_slot(&self) -> Option<VectorSlot> {
+        VectorSlot::from_index(self.slot as usize)
+    }
+}
+
+/// Every descriptor #[register_exception_handler] put in the kernel, for ArcServices::register_handlers
+/// ld and lld define __start_/__stop_ for sections with C identifier names, as long as there is one handler and the linker script keeps
+/// the section (KEEP(*(arcboot_exception_handlers)) if it garbage collects)
+#[cfg(target_os = "none")]
+#[inline]
+pub fn linked_exception_handlers() -> &'static [ExceptionHandlerDescriptor] {
+    extern "C" {
+        static __start_arcboot_exception_handlers: ExceptionHandlerDescriptor;
+        static __stop_arcboot_exception_handlers: ExceptionHandlerDescriptor;
+    }
+
+    unsafe {
+        let start = &__start_arcboot_exception_handlers as *const ExceptionHandlerDescriptor;
+        let stop = &__stop_arcboot_exception_handlers as *const ExceptionHandlerDescriptor;
+        core::slice::from_raw_parts(start, stop.offset_from(start) as usize)
+    }
+}
+
+// ---------------
+// SYNDROME
+// ---------------
+
+/// ESR_EL1 or ESR_EL2, same layout. EC in 31:26, IL in 25, ISS in 24:0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Esr(pub u64);
+
+// Exception classes decoded below
+const EC_UNKNOWN: u8 = 0x00;
+const EC_WFX: u8 = 0x01;
+const EC_FP_ACCESS: u8 = 0x07;
+const EC_ILLEGAL_STATE: u8 = 0x0E;
+const EC_SVC64: u8 = 0x15;
+const EC_HVC64: u8 = 0x16;
+const EC_SMC64: u8 = 0x17;
+const EC_SYSREG: u8 = 0x18;
+const EC_IABT_LOWER: u8 = 0x20;
+const EC_IABT_CURRENT: u8 = 0x21;
+const EC_PC_ALIGNMENT: u8 = 0x22;
+const EC_DABT_LOWER: u8 = 0x24;
+const EC_DABT_CURRENT: u8 = 0x25;
+const EC_SP_ALIGNMENT: u8 = 0x26;
+const EC_SERROR: u8 = 0x2F;
+const EC_BRK64: u8 = 0x3C;
+
+impl Esr {
+    pub fn class(&self) -> u8 {
+        ((self.0 >> 26) & 0x3F) as u8
+    }
+
+    pub fn iss(&self) -> u32 {
+        (self.0 & 0x1FF_FFFF) as u32
+    }
+
+    /// IL. Clear for 16 bit T32 instructions, so only AArch32 lower ELs see it clear
+    pub fn is_32bit_instruction(&self) -> bool {
+        self.0 & (1 << 25) != 0
+    }
+
+    pub fn decode(&self) -> Exception {
+        let iss = self.iss();
+        let imm16 = iss as u16;
+
+        match self.class() {
+            EC_UNKNOWN => Exception::Unknown,
+            EC_WFX => Exception::WaitForInterrupt,
+            EC_FP_ACCESS => Exception::FpAccess,
+            EC_ILLEGAL_STATE => Exception::IllegalState,
+            EC_SVC64 => Exception::Svc(imm16),
+            EC_HVC64 => Exception::Hvc(imm16),
+            EC_SMC64 => Exception::Smc(imm16),
+            EC_SYSREG => Exception::SystemRegister(iss),
+            EC_IABT_LOWER | EC_IABT_CURRENT => Exception::InstructionAbort {
+                lower_el: self.class() == EC_IABT_LOWER,
+                fault: FaultStatus::from_code(iss as u8 & 0x3F),
+            },
+            EC_PC_ALIGNMENT => Exception::PcAlignment,
+            EC_DABT_LOWER | EC_DABT_CURRENT => Exception::DataAbort {
+                lower_el: self.class() == EC_DABT_LOWER,
+                write: iss & (1 << 6) != 0,
+                // FnV. FAR isnt valid when it is set
+                far_valid: iss & (1 << 10) == 0,
+                fault: FaultStatus::from_code(iss as u8 & 0x3F),
+            },
+            EC_SP_ALIGNMENT => Exception::SpAlignment,
+            EC_SERROR => Exception::SError(iss),
+            EC_BRK64 => Exception::Brk(imm16),
+            class => Exception::Other { class, iss },
+        }
+    }
+}
+
+/// What ESR says happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    /// Undefined instruction, mostly
+    Unknown,
+    /// Trapped WFI or WFE
+    WaitForInterrupt,
+    /// FP or SIMD used while CPACR traps them
+    FpAccess,
+    /// ERET to a bad PSTATE
+    IllegalState,
+    Svc(u16),
+    Hvc(u16),
+    Smc(u16),
+    /// Trapped MSR, MRS or system instruction. The ISS says which
+    SystemRegister(u32),
+    InstructionAbort {
+        lower_el: bool,
+        fault: FaultStatus,
+    },
+    PcAlignment,
+    DataAbort {
+        lower_el: bool,
+        write: bool,
+        far_valid: bool,
+        fault: FaultStatus,
+    },
+    SpAlignment,
+    SError(u32),
+    Brk(u16),
+    Other {
+        class: u8,
+        iss: u32,
+    },
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown => write!(f, "Unknown reason (undefined instruction?)"),
+            Self::WaitForInterrupt => write!(f, "Trapped WFI/WFE"),
+            Self::FpAccess => write!(f, "FP/SIMD access while trapped"),
+            Self::IllegalState => write!(f, "Illegal execution state"),
+            Self::Svc(imm) => write!(f, "SVC #{imm:#X}"),
+            Self::Hvc(imm) => write!(f, "HVC #{imm:#X}"),
+            Self::Smc(imm) => write!(f, "SMC #{imm:#X}"),
+            Self::SystemRegister(iss) => write!(f, "Trapped system register access (ISS {iss:#X})"),
+            Self::InstructionAbort { lower_el, fault } => {
+                let from = if *lower_el { "lower EL" } else { "current EL" };
+                write!(f, "Instruction abort from {from}: {fault}")
+            }
+            Self::PcAlignment => write!(f, "PC alignment fault"),
+            Self::DataAbort {
+                lower_el,
+                write,
+                far_valid,
+                fault,
+            } => {
+                let from = if *lower_el { "lower EL" } else { "current EL" };
+                let access = if *write { "write" } else { "read" };
+                let far = if *far_valid { "" } else { ", FAR not valid" };
+                write!(f, "Data abort ({access}) from {from}: {fault}{far}")
+            }
+            Self::SpAlignment => write!(f, "SP alignment fault"),
+            Self::SError(iss) => write!(f, "SError (ISS {iss:#X})"),
+            Self::Brk(imm) => write!(f, "BRK #{imm:#X}"),
+            Self::Other { class, iss } => write!(f, "Exception class {class:#X} (ISS {iss:#X})"),
+        }
+    }
+}
+
+/// DFSC or IFSC, bits 5:0 of an abort's ISS. Levels are the table level the walk stopped at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultStatus {
+    AddressSize(u8),
+    Translation(u8),
+    AccessFlag(u8),
+    Permission(u8),
+    SyncExternal,
+    Alignment,
+    TlbConflict,
+    Other(u8),
+}
+
+impl FaultStatus {
+    pub fn from_code(code: u8) -> Self {
+        let level = code & 0b11;
+        match code {
+            0b00_0000..=0b00_0011 => Self::AddressSize(level),
+            0b00_0100..=0b00_0111 => Self::Translation(level),
+            0b00_1000..=0b00_1011 => Self::AccessFlag(level),
+            0b00_1100..=0b00_1111 => Self::Permission(level),
+            0b01_0000 => Self::SyncExternal,
+            0b10_0001 => Self::Alignment,
+            0b11_0000 => Self::TlbConflict,
+            code => Self::Other(code),
+        }
+    }
+}
+
+impl fmt::Display for FaultStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AddressSize(level) => write!(f, "address size fault, level {level}"),
+            Self::Translation(level) => write!(f, "translation fault, level {level}"),
+            Self::AccessFlag(level) => write!(f, "access flag fault, level {level}"),
+            Self::Permission(level) => write!(f, "permission fault, level {level}"),
+            Self::SyncExternal => write!(f, "synchronous external abort"),
+            Self::Alignment => write!(f, "alignment fault"),
+            Self::TlbConflict => write!(f, "TLB conflict"),
+            Self::Other(code) => write!(f, "fault status {code:#X}"),
+        }
+    }
+}
+
+// ---------------
+// TESTS
+// ---------------
+
+#[test]
+fn trap_frame_layout() {
+    // the vector table's offsets
+    assert_eq!(TRAP_FRAME_SIZE, 288);
+    assert_eq!(TRAP_FRAME_SIZE % 16, 0);
+    let frame = TrapFrame {
+        x: [0; 31],
+        elr: 0,
+        spsr: 0,
+        esr: 0,
+        far: 0,
+        slot: 0,
+    };
+    let base = &frame as *const TrapFrame as usize;
+    assert_eq!(&frame.elr as *const u64 as usize - base, 248);
+    assert_eq!(&frame.esr as *const u64 as usize - base, 264);
+    assert_eq!(&frame.slot as *const u64 as usize - base, 280);
+}
+
+#[test]
+fn vector_slots() {
+    for index in 0..VECTOR_ENTRIES {
+        assert_eq!(VectorSlot::from_index(index).unwrap().index(), index);
+    }
+    assert_eq!(VectorSlot::from_index(VECTOR_ENTRIES), None);
+
+    let slot = VectorSlot::from_index(5).unwrap();
+    assert_eq!(slot.source, ExceptionSource::CurrentSpx);
+    assert_eq!(slot.kind, VectorKind::Irq);
+    assert_eq!(slot.offset(), 0x280);
+    assert_eq!(
+        VectorSlot::new(ExceptionSource::LowerA32, VectorKind::SError).offset(),
+        0x780
+    );
+}
+
+#[test]
+fn decode_syndromes() {
+    // ldr from an unmapped page at EL1: EC 0x25, IL, DFSC level 3 translation fault
+    let esr = Esr(0x25 << 26 | 1 << 25 | 0b000111);
+    assert_eq!(
+        esr.decode(),
+        Exception::DataAbort {
+            lower_el: false,
+            write: false,
+            far_valid: true,
+            fault: FaultStatus::Translation(3),
+        }
+    );
+
+    // write to a read only page, FnV set
+    let esr = Esr(0x24 << 26 | 1 << 25 | 1 << 10 | 1 << 6 | 0b001111);
+    assert_eq!(
+        esr.decode(),
+        Exception::DataAbort {
+            lower_el: true,
+            write: true,
+            far_valid: false,
+            fault: FaultStatus::Permission(3),
+        }
+    );
+
+    let esr = Esr(0x21 << 26 | 1 << 25 | 0b001001);
+    assert_eq!(
+        esr.decode(),
+        Exception::InstructionAbort {
+            lower_el: false,
+            fault: FaultStatus::AccessFlag(1),
+        }
+    );
+
+    assert_eq!(
+        Esr(0x15 << 26 | 1 << 25 | 0x42).decode(),
+        Exception::Svc(0x42)
+    );
+    assert_eq!(
+        Esr(0x3C << 26 | 1 << 25 | 0xF000).decode(),
+        Exception::Brk(0xF000)
+    );
+    assert_eq!(
+        Esr(0x2F << 26 | 1 << 25 | 0x2).decode(),
+        Exception::SError(0x2)
+    );
+    assert_eq!(Esr(0).decode(), Exception::Unknown);
+    assert_eq!(
+        Esr(0x3F << 26).decode(),
+        Exception::Other {
+            class: 0x3F,
+            iss: 0
+        }
+    );
+
+    assert_eq!(FaultStatus::from_code(0b010000), FaultStatus::SyncExternal);
+    assert_eq!(FaultStatus::from_code(0b100001), FaultStatus::Alignment);
+    assert_eq!(FaultStatus::from_code(0b111111), FaultStatus::Other(0x3F));
+}
+
+#[test]
+fn skip_instruction() {
+    let mut frame = TrapFrame {
+        x: [0; 31],
+        elr: 0x1000,
+        spsr: 0,
+        esr: 0x3C << 26 | 1 << 25,
+        far: 0,
+        slot: 4,
+    };
+    frame.skip_instruction();
+    assert_eq!(frame.elr, 0x1004);
+    assert_eq!(
+        frame.vector_slot(),
+        Some(VectorSlot::new(
+            ExceptionSource::CurrentSpx,
+            VectorKind::Sync
+        ))
+    );
+}
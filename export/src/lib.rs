@@ -7,17 +7,48 @@ use alloc::vec;
 // ARCBOOT API
 // ---------------
 
-use alloc::vec::Vec;
+use alloc::{string::String, vec::Vec};
+use bootinfo::{BootInfo, BootInfoError};
+use cmdline::CommandLine;
+use core::{
+    marker::PhantomData,
+    mem::{align_of, size_of, size_of_val},
+};
+use exception::{ExceptionHandlerDescriptor, TrapFrame, VectorKind, VectorSlot};
 
-#[repr(C)]
+pub mod bootinfo;
+#[cfg(target_arch = "aarch64")]
+pub mod cache;
+pub mod cmdline;
+pub mod exception;
+pub mod mmu;
+pub mod note;
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DeviceType {
-    USBController,
-    PCIeController,
-    MainProcessor,
-    DRAM,
+    USBController = 0,
+    PCIeController = 1,
+    MainProcessor = 2,
+    DRAM = 3,
     /// ROM, not mass storage (usb/pcie)
-    FlashMemory,
-    Unknown,
+    FlashMemory = 4,
+    Unknown = 5,
+}
+
+impl DeviceType {
+    /// None for values this build doesnt know about
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(Self::USBController),
+            1 => Some(Self::PCIeController),
+            2 => Some(Self::MainProcessor),
+            3 => Some(Self::DRAM),
+            4 => Some(Self::FlashMemory),
+            5 => Some(Self::Unknown),
+            _ => None,
+        }
+    }
 }
 
 // Neutron doesnt have to touch the registers directly,
@@ -32,56 +63,138 @@ impl PageTableTTBR1 {
     pub fn new(phys_addr_base: u64) -> Self {
         Self { phys_addr_base }
     }
+
+    /// Paddr of the root table TTBR1 points at when the kernel starts
+    pub fn phys_addr_base(&self) -> u64 {
+        self.phys_addr_base
+    }
 }
 
 /// For ArcAPI only. When exposing to userspace (rust std), use neutron memory regions
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MemoryRegionType {
-    Standard,
-    MMIO,
-    ACPI,
+    /// Free RAM
+    Standard = 0,
+    /// Device registers. Map as device memory
+    MMIO = 1,
+    /// ACPI NVS. Has to be preserved, even across sleep states
+    ACPI = 2,
+    /// ACPI tables. Free RAM once the kernel is done reading them
+    AcpiReclaimable = 3,
+    /// EFI runtime services code and data. Has to stay mapped if the kernel uses runtime services
+    Runtime = 4,
+    /// Firmware reserved, unusable or unknown. Dont touch
+    Reserved = 5,
+    /// arcboot's image, heap, page tables and the ArcServices handoff. Free RAM once the kernel is done with ArcServices
+    Bootloader = 6,
+    /// Non volatile RAM (NVDIMM)
+    Persistent = 7,
+    /// Frames arcboot allocated for the kernel: page tables, segments, boot stack and heap. In use
+    BootAllocated = 8,
+    /// Files arcboot loaded for the kernel, see ArcServices::modules(). Free RAM once the kernel is done with them
+    BootModule = 9,
+}
+
+impl MemoryRegionType {
+    /// None for values this build doesnt know about
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(Self::Standard),
+            1 => Some(Self::MMIO),
+            2 => Some(Self::ACPI),
+            3 => Some(Self::AcpiReclaimable),
+            4 => Some(Self::Runtime),
+            5 => Some(Self::Reserved),
+            6 => Some(Self::Bootloader),
+            7 => Some(Self::Persistent),
+            8 => Some(Self::BootAllocated),
+            9 => Some(Self::BootModule),
+            _ => None,
+        }
+    }
+
+    /// Whether the kernel can use it as RAM straight away
+    pub fn is_usable(&self) -> bool {
+        *self == Self::Standard
+    }
+
+    /// Backed by RAM, whatever it is being used for. What goes in the direct map
+    pub fn is_ram(&self) -> bool {
+        !matches!(self, Self::MMIO | Self::Reserved)
+    }
 }
 
 pub type AddressRange = (u64, u64);
 
 /// Get the address range (4K granule)
 pub fn address_range_4k(start_addr: u64, n_pages: u64) -> AddressRange {
-    let res = (start_addr, start_addr + n_pages * 4096);
-
-    res
+    (start_addr, start_addr + n_pages * 4096)
 }
 
 #[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct MemoryRegion {
-    region_type: MemoryRegionType,
-    address_range: AddressRange,
+    /// A MemoryRegionType, kept raw since a newer arcboot may have types this kernel doesnt know
+    region_type: u32,
+    /// Keeps the layout free of padding, so the whole handoff can be checksummed
+    reserved: u32,
+    start: u64,
+    end: u64,
 }
 
 impl MemoryRegion {
     pub fn new(region_type: MemoryRegionType, address_range: AddressRange) -> Self {
         Self {
-            region_type,
-            address_range,
+            region_type: region_type as u32,
+            reserved: 0,
+            start: address_range.0,
+            end: address_range.1,
         }
     }
+
+    /// None for types newer than this kernel. Treat those as reserved
+    pub fn region_type(&self) -> Option<MemoryRegionType> {
+        MemoryRegionType::from_u32(self.region_type)
+    }
+
+    /// (start, end), end exclusive
+    pub fn address_range(&self) -> AddressRange {
+        (self.start, self.end)
+    }
 }
 
 // Maybe wrap around ACPI.. I think its a good idea
 
 #[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ArcDevice {
-    device_type: DeviceType,
-    numa_id: usize,
+    /// A DeviceType, raw like MemoryRegion::region_type
+    device_type: u32,
+    reserved: u32,
+    numa_id: u64,
 }
 
 impl ArcDevice {
-    pub fn new(device_type: DeviceType, numa_id: usize) -> Self {
+    pub fn new(device_type: DeviceType, numa_id: u64) -> Self {
         Self {
-            device_type,
+            device_type: device_type as u32,
+            reserved: 0,
             numa_id,
         }
     }
+
+    /// None for types newer than this kernel
+    pub fn device_type(&self) -> Option<DeviceType> {
+        DeviceType::from_u32(self.device_type)
+    }
+
+    pub fn numa_id(&self) -> u64 {
+        self.numa_id
+    }
 }
 
+#[derive(Default)]
 pub struct MemoryMap {
     memory_regions: Vec<MemoryRegion>,
 }
@@ -94,13 +207,9 @@ impl MemoryMap {
     pub fn push(&mut self, memory_region: MemoryRegion) {
         self.memory_regions.push(memory_region);
     }
-}
 
-impl Default for MemoryMap {
-    fn default() -> Self {
-        Self {
-            memory_regions: Default::default(),
-        }
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.memory_regions
     }
 }
 
@@ -110,20 +219,142 @@ impl Default for MemoryMap {
 
 pub struct ArcMemory {}
 
+/// Bumped whenever ArcServices changes. Kernels say which they need with an arcboot note
+pub const ARC_SERVICES_VERSION: u32 = 1;
+
+/// First 8 bytes of ArcServices. "ARCSERV\0"
+pub const ARC_SERVICES_MAGIC: u64 = u64::from_le_bytes(*b"ARCSERV\0");
+
+/// An array in the handoff block. `ptr` is a kernel vaddr, so it means the same thing no matter what compiler or allocator built the kernel
+#[repr(C)]
+pub struct ArcSlice<T> {
+    ptr: u64,
+    len: u64,
+    _marker: PhantomData<T>,
+}
+
+impl<T> ArcSlice<T> {
+    const fn empty() -> Self {
+        Self {
+            ptr: 0,
+            len: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Bytes the elements take up
+    fn size(&self) -> u64 {
+        self.len * size_of::<T>() as u64
+    }
+
+    fn new(ptr: u64, len: usize) -> Self {
+        Self {
+            ptr,
+            len: len as u64,
+            _marker: PhantomData,
+        }
+    }
+
+    fn as_slice(&self) -> &[T] {
+        if self.len == 0 {
+            return &[];
+        }
+        // ArcServices::from_ptr checked it is inside the handoff block
+        unsafe { core::slice::from_raw_parts(self.ptr as *const T, self.len as usize) }
+    }
+}
+
+/// Handed to the kernel's entry point as a pointer. Everything it points to is in one block right after it, and nothing in it is owned by arcboot's allocator
 #[repr(C)]
 pub struct ArcServices {
+    /// ARC_SERVICES_MAGIC
+    magic: u64,
+    /// ARC_SERVICES_VERSION of the arcboot that wrote it
+    version: u32,
+    /// size_of::<ArcServices>() for that version. Newer versions only add fields to the end
+    header_size: u32,
+    /// Header + arrays, in bytes
+    total_size: u64,
+    /// FNV-1a of all total_size bytes, with this field as 0
+    checksum: u64,
     paging: PageTableTTBR1,
-    devices: Vec<ArcDevice>,
-    memory_map: MemoryMap,
+    devices: ArcSlice<ArcDevice>,
+    memory_map: ArcSlice<MemoryRegion>,
     interrupts: ArcInterrupts,
+    /// What arcboot added to the kernel's link addresses (KASLR). 0 if the kernel was loaded where it was linked
+    kaslr_slide: u64,
+    /// Tag list from bootinfo::BootInfoWriter
+    boot_info: ArcSlice<u8>,
+    /// RAM at paddr is at hhdm_offset + paddr in TTBR1
+    hhdm_offset: u64,
+    /// MMIO at paddr is at mmio_offset + paddr in TTBR1, as device memory
+    mmio_offset: u64,
+    /// Kernel vaddr of the exception::VECTOR_ENTRIES handlers (Option<InterruptHandler>) arcboot's vector table calls. 0 if there is no table
+    interrupt_handlers: u64,
+    modules: ArcSlice<ArcModule>,
 }
 
-pub type InterruptHandler = fn();
+/// A file arcboot loaded for the kernel, like an initrd. Page aligned, in a BootModule region
+#[repr(C)]
+pub struct ArcModule {
+    start: u64,
+    end: u64,
+    /// Where it is in TTBR1, through the direct map
+    vaddr: u64,
+    /// Path it was loaded from, UTF-8
+    name: ArcSlice<u8>,
+    /// The rest of its `module` line in arcboot.conf, UTF-8
+    args: ArcSlice<u8>,
+}
 
+impl ArcModule {
+    /// Paddr of the first byte and after the last byte. The frames go on to the next page boundary
+    pub fn address_range(&self) -> AddressRange {
+        (self.start, self.end)
+    }
+
+    pub fn vaddr(&self) -> u64 {
+        self.vaddr
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(self.name.as_slice()).unwrap_or_default()
+    }
+
+    pub fn args(&self) -> &str {
+        core::str::from_utf8(self.args.as_slice()).unwrap_or_default()
+    }
+
+    /// The module's bytes, read through vaddr
+    /// # Safety
+    /// The kernel has to still have the direct map arcboot set up, and not have reused the module's frames
+    pub unsafe fn bytes(&self) -> &[u8] {
+        core::slice::from_raw_parts(self.vaddr as *const u8, self.size() as usize)
+    }
+}
+
+/// Kernel entry point. Gets the handoff block written by ArcServicesBuilder::write, check it with ArcServices::from_ptr
+pub type ArcEntry = extern "C" fn(*mut ArcServices) -> !;
+
+/// Called by arcboot's vector table with everything the interrupted code had in registers. Returning erets to frame.elr
+pub type InterruptHandler = extern "C" fn(&mut TrapFrame);
+
+#[repr(C)]
 pub struct InterruptArm64 {
     vector_table_start: u64,
 }
 
+impl InterruptArm64 {
+    pub fn new(vector_table_start: u64) -> Self {
+        Self { vector_table_start }
+    }
+}
+
+#[repr(C)]
 pub struct ArcInterrupts {
     arm64: InterruptArm64,
 }
@@ -134,7 +365,244 @@ impl ArcInterrupts {
     }
 }
 
+/// Why an ArcServices handoff couldnt be written or read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandoffError {
+    /// Buffer given to ArcServicesBuilder::write is smaller than handoff_size()
+    BufferTooSmall,
+    /// Not an ArcServices, or the pointer is wrong
+    BadMagic,
+    /// Written by an arcboot too old for this kernel's ArcServices
+    UnsupportedVersion(u32),
+    /// header_size or total_size dont make sense
+    BadSize,
+    /// Something changed the block after arcboot wrote it
+    BadChecksum,
+    /// An array points outside the handoff block
+    ArrayOutOfBounds,
+}
+
+/// Why an interrupt handler couldnt be registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptError {
+    /// arcboot didnt hand over a vector table, VBAR_EL1 is whatever it was
+    NoVectorTable,
+    /// A descriptor's slot isnt one of the VECTOR_ENTRIES
+    BadSlot(u64),
+}
+
 impl ArcServices {
+    /// Check the handoff block at `ptr` and get a reference to it. Call this first thing in the kernel entry
+    /// # Safety
+    /// `ptr` has to be readable for at least size_of::<ArcServices>() bytes, and for total_size bytes if the magic matches
+    pub unsafe fn from_ptr<'a>(ptr: *const ArcServices) -> Result<&'a ArcServices, HandoffError> {
+        if ptr.is_null() || !(ptr as usize).is_multiple_of(align_of::<ArcServices>()) {
+            return Err(HandoffError::BadMagic);
+        }
+
+        let services = &*ptr;
+        if services.magic != ARC_SERVICES_MAGIC {
+            return Err(HandoffError::BadMagic);
+        }
+        if services.version == 0 || (services.header_size as usize) < size_of::<ArcServices>() {
+            return Err(HandoffError::UnsupportedVersion(services.version));
+        }
+        if services.total_size < services.header_size as u64 {
+            return Err(HandoffError::BadSize);
+        }
+
+        let block = core::slice::from_raw_parts(ptr as *const u8, services.total_size as usize);
+        if checksum(block) != services.checksum {
+            return Err(HandoffError::BadChecksum);
+        }
+
+        // arrays have to be fully inside the block
+        let start = ptr as u64;
+        let end = start + services.total_size;
+        let in_block = |ptr: u64, size: u64| {
+            size == 0 || (ptr >= start && ptr.checked_add(size).is_some_and(|e| e <= end))
+        };
+        if !in_block(services.devices.ptr, services.devices.size())
+            || !in_block(services.memory_map.ptr, services.memory_map.size())
+            || !in_block(services.boot_info.ptr, services.boot_info.size())
+            || !in_block(services.modules.ptr, services.modules.size())
+        {
+            return Err(HandoffError::ArrayOutOfBounds);
+        }
+        let modules = services.modules.as_slice();
+        if modules
+            .iter()
+            .any(|m| !in_block(m.name.ptr, m.name.size()) || !in_block(m.args.ptr, m.args.size()))
+        {
+            return Err(HandoffError::ArrayOutOfBounds);
+        }
+
+        Ok(services)
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn paging(&self) -> &PageTableTTBR1 {
+        &self.paging
+    }
+
+    pub fn devices(&self) -> &[ArcDevice] {
+        self.devices.as_slice()
+    }
+
+    pub fn memory_regions(&self) -> &[MemoryRegion] {
+        self.memory_map.as_slice()
+    }
+
+    pub fn kaslr_slide(&self) -> u64 {
+        self.kaslr_slide
+    }
+
+    /// Where the direct map of all RAM starts
+    pub fn hhdm_offset(&self) -> u64 {
+        self.hhdm_offset
+    }
+
+    /// Where the window of all MMIO regions starts
+    pub fn mmio_offset(&self) -> u64 {
+        self.mmio_offset
+    }
+
+    /// The tags arcboot wrote
+    pub fn boot_info(&self) -> Result<BootInfo<'_>, BootInfoError> {
+        BootInfo::parse(self.boot_info.as_slice())
+    }
+
+    /// The command line from the boot entry, if arcboot passed one
+    pub fn command_line(&self) -> Option<CommandLine<'_>> {
+        let boot_info = self.boot_info().ok()?;
+        boot_info.command_line().map(CommandLine::new)
+    }
+
+    /// Files from the boot entry's `module` lines, in the order they were listed
+    pub fn modules(&self) -> &[ArcModule] {
+        self.modules.as_slice()
+    }
+
+    /// What VBAR_EL1 points at when the kernel is entered. Mapped executable in TTBR1, 0 if arcboot didnt set one up
+    pub fn vector_table(&self) -> u64 {
+        self.interrupts.arm64.vector_table_start
+    }
+
+    /// Send every kind exception, from any source, to handler. Whatever was registered before is replaced
+    /// Only until the kernel points VBAR_EL1 at its own table
+    pub fn register_interrupt_handler(
+        &self,
+        kind: VectorKind,
+        handler: InterruptHandler,
+    ) -> Result<(), InterruptError> {
+        self.set_kind_handler(kind, Some(handler))
+    }
+
+    /// kind exceptions go back to parking the core, since there is nobody to report them to
+    pub fn unregister_interrupt_handler(&self, kind: VectorKind) -> Result<(), InterruptError> {
+        self.set_kind_handler(kind, None)
+    }
+
+    /// Handler for one entry of the table, e.g. only sync exceptions from EL0
+    pub fn set_slot_handler(
+        &self,
+        slot: VectorSlot,
+        handler: Option<InterruptHandler>,
+    ) -> Result<(), InterruptError> {
+        if self.interrupt_handlers == 0 {
+            return Err(InterruptError::NoVectorTable);
+        }
+
+        // not part of the handoff block, so the checksum still holds. One aligned store, the vector table never sees half a pointer
+        let table = self.interrupt_handlers as *mut Option<InterruptHandler>;
+        unsafe { core::ptr::write_volatile(table.add(slot.index()), handler) };
+
+        Ok(())
+    }
+
+    /// Register every descriptor, e.g. exception::linked_exception_handlers() for the ones #[register_exception_handler] made
+    pub fn register_handlers(
+        &self,
+        descriptors: &[ExceptionHandlerDescriptor],
+    ) -> Result<(), InterruptError> {
+        for descriptor in descriptors {
+            let slot = descriptor
+                .vector_slot()
+                .ok_or(InterruptError::BadSlot(descriptor.slot))?;
+            self.set_slot_handler(slot, Some(descriptor.handler))?;
+        }
+
+        Ok(())
+    }
+
+    fn set_kind_handler(
+        &self,
+        kind: VectorKind,
+        handler: Option<InterruptHandler>,
+    ) -> Result<(), InterruptError> {
+        for index in 0..exception::VECTOR_ENTRIES {
+            let slot = VectorSlot::from_index(index).expect("index is below VECTOR_ENTRIES");
+            if slot.kind == kind {
+                self.set_slot_handler(slot, handler)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// FNV-1a, 64 bit. Over the whole handoff block with the checksum field zeroed
+fn checksum(block: &[u8]) -> u64 {
+    // magic, version, header_size, total_size come first
+    const CHECKSUM_OFFSET: usize = 24;
+
+    let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+    for (i, byte) in block.iter().enumerate() {
+        let byte = if (CHECKSUM_OFFSET..CHECKSUM_OFFSET + 8).contains(&i) {
+            0
+        } else {
+            *byte
+        };
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100_0000_01B3);
+    }
+
+    hash
+}
+
+/// What arcboot fills in before writing the ArcServices handoff for the kernel
+pub struct ArcServicesBuilder {
+    paging: PageTableTTBR1,
+    devices: Vec<ArcDevice>,
+    memory_map: MemoryMap,
+    interrupts: ArcInterrupts,
+    kaslr_slide: u64,
+    boot_info: Vec<u8>,
+    hhdm_offset: u64,
+    mmio_offset: u64,
+    interrupt_handlers: u64,
+    modules: Vec<ModuleInfo>,
+}
+
+/// What ArcServicesBuilder needs to write an ArcModule
+struct ModuleInfo {
+    range: AddressRange,
+    vaddr: u64,
+    name: String,
+    args: String,
+}
+
+impl ModuleInfo {
+    /// The module's strings, after the modules array
+    fn strings_size(&self) -> usize {
+        self.name.len() + self.args.len()
+    }
+}
+
+impl ArcServicesBuilder {
     pub fn new(
         paging: PageTableTTBR1,
         devices: Vec<ArcDevice>,
@@ -146,11 +614,166 @@ impl ArcServices {
             devices,
             memory_map,
             interrupts,
+            kaslr_slide: 0,
+            boot_info: bootinfo::BootInfoWriter::new().finish(),
+            hhdm_offset: 0,
+            mmio_offset: 0,
+            interrupt_handlers: 0,
+            modules: Vec::new(),
         }
     }
 
-    pub fn register_interrupt_handler(&mut self, interrupt_id: u64, handler: InterruptHandler) {
-        // cfg aarch64, use the vector table
+    pub fn set_kaslr_slide(&mut self, kaslr_slide: u64) {
+        self.kaslr_slide = kaslr_slide;
+    }
+
+    /// Paddr of the root of the kernel's TTBR1 tables
+    pub fn set_paging(&mut self, ttbr1_root: u64) {
+        self.paging = PageTableTTBR1::new(ttbr1_root);
+    }
+
+    /// The final map, with whatever arcboot allocated for the kernel marked as BootAllocated
+    pub fn set_memory_map(&mut self, memory_map: MemoryMap) {
+        self.memory_map = memory_map;
+    }
+
+    /// Offsets of the RAM direct map and the MMIO window arcboot put in TTBR1
+    pub fn set_direct_map(&mut self, hhdm_offset: u64, mmio_offset: u64) {
+        self.hhdm_offset = hhdm_offset;
+        self.mmio_offset = mmio_offset;
+    }
+
+    /// The vector table arcboot points VBAR_EL1 at, and the handlers it calls. Both kernel vaddrs
+    pub fn set_interrupts(&mut self, vector_table_start: u64, handlers: u64) {
+        self.interrupts = ArcInterrupts::new(InterruptArm64::new(vector_table_start));
+        self.interrupt_handlers = handlers;
+    }
+
+    /// Bytes from BootInfoWriter::finish
+    pub fn set_boot_info(&mut self, boot_info: Vec<u8>) {
+        self.boot_info = boot_info;
+    }
+
+    /// A loaded module. range is its paddrs, vaddr where the kernel sees it
+    pub fn add_module(&mut self, range: AddressRange, vaddr: u64, name: &str, args: &str) {
+        self.modules.push(ModuleInfo {
+            range,
+            vaddr,
+            name: name.into(),
+            args: args.into(),
+        });
+    }
+
+    /// Bytes needed for the handoff block
+    pub fn handoff_size(&self) -> usize {
+        self.modules_offset()
+            + self.modules.len() * size_of::<ArcModule>()
+            + self
+                .modules
+                .iter()
+                .map(ModuleInfo::strings_size)
+                .sum::<usize>()
+    }
+
+    /// After boot_info, which can be any length
+    fn modules_offset(&self) -> usize {
+        let size = size_of::<ArcServices>()
+            + size_of_val(self.devices.as_slice())
+            + size_of_val(self.memory_map.regions())
+            + self.boot_info.len();
+        size.div_ceil(align_of::<ArcModule>()) * align_of::<ArcModule>()
+    }
+
+    /// Write the handoff block into `buf`, which the kernel will see at `vaddr`. Returns a pointer to pass to the kernel's entry, in the kernel's address space
+    pub fn write(&self, buf: &mut [u64], vaddr: u64) -> Result<*mut ArcServices, HandoffError> {
+        let total_size = self.handoff_size();
+        if buf.len() * 8 < total_size {
+            return Err(HandoffError::BufferTooSmall);
+        }
+
+        let block =
+            unsafe { core::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, total_size) };
+        block.fill(0);
+
+        let devices_offset = size_of::<ArcServices>();
+        let regions_offset = devices_offset + size_of_val(self.devices.as_slice());
+        let boot_info_offset = regions_offset + size_of_val(self.memory_map.regions());
+        let modules_offset = self.modules_offset();
+        let strings_offset = modules_offset + self.modules.len() * size_of::<ArcModule>();
+
+        let services = ArcServices {
+            magic: ARC_SERVICES_MAGIC,
+            version: ARC_SERVICES_VERSION,
+            header_size: size_of::<ArcServices>() as u32,
+            total_size: total_size as u64,
+            checksum: 0,
+            paging: PageTableTTBR1::new(self.paging.phys_addr_base),
+            devices: ArcSlice {
+                ptr: vaddr + devices_offset as u64,
+                len: self.devices.len() as u64,
+                ..ArcSlice::empty()
+            },
+            memory_map: ArcSlice {
+                ptr: vaddr + regions_offset as u64,
+                len: self.memory_map.regions().len() as u64,
+                ..ArcSlice::empty()
+            },
+            interrupts: ArcInterrupts::new(InterruptArm64::new(
+                self.interrupts.arm64.vector_table_start,
+            )),
+            kaslr_slide: self.kaslr_slide,
+            boot_info: ArcSlice {
+                ptr: vaddr + boot_info_offset as u64,
+                len: self.boot_info.len() as u64,
+                ..ArcSlice::empty()
+            },
+            hhdm_offset: self.hhdm_offset,
+            mmio_offset: self.mmio_offset,
+            interrupt_handlers: self.interrupt_handlers,
+            modules: ArcSlice::new(vaddr + modules_offset as u64, self.modules.len()),
+        };
+
+        unsafe {
+            let base = block.as_mut_ptr();
+            core::ptr::write(base as *mut ArcServices, services);
+            core::ptr::copy_nonoverlapping(
+                self.devices.as_ptr(),
+                base.add(devices_offset) as *mut ArcDevice,
+                self.devices.len(),
+            );
+            core::ptr::copy_nonoverlapping(
+                self.memory_map.regions().as_ptr(),
+                base.add(regions_offset) as *mut MemoryRegion,
+                self.memory_map.regions().len(),
+            );
+        }
+        block[boot_info_offset..boot_info_offset + self.boot_info.len()]
+            .copy_from_slice(&self.boot_info);
+
+        // each module, then all of their names and args
+        let mut string_offset = strings_offset;
+        let mut string = |block: &mut [u8], s: &str| {
+            block[string_offset..string_offset + s.len()].copy_from_slice(s.as_bytes());
+            let slice = ArcSlice::new(vaddr + string_offset as u64, s.len());
+            string_offset += s.len();
+            slice
+        };
+        for (i, module) in self.modules.iter().enumerate() {
+            let arc_module = ArcModule {
+                start: module.range.0,
+                end: module.range.1,
+                vaddr: module.vaddr,
+                name: string(block, &module.name),
+                args: string(block, &module.args),
+            };
+            let at = modules_offset + i * size_of::<ArcModule>();
+            unsafe { core::ptr::write(block.as_mut_ptr().add(at) as *mut ArcModule, arc_module) };
+        }
+
+        let sum = checksum(block);
+        unsafe { (*(buf.as_mut_ptr() as *mut ArcServices)).checksum = sum };
+
+        Ok(vaddr as *mut ArcServices)
     }
 }
 
@@ -161,18 +784,21 @@ impl ArcServices {
 
 // DEFAULTS
 
-pub type DefaultServices = ArcServices;
+pub type DefaultServices = ArcServicesBuilder;
 
 // for testing
 
-/// Should be called by arcboot to make the structures and passed to neutron entry
+/// Should be called by arcboot to make the structures, then written out for the kernel entry
 pub fn make_default() -> DefaultServices {
     let device = ArcDevice::new(DeviceType::DRAM, 0);
     let devices = vec![device];
     let memory_map = MemoryMap::default();
-    let service = ArcServices::new(PageTableTTBR1::new(0x4000_0000), devices, memory_map, 0x0);
-
-    service
+    ArcServicesBuilder::new(
+        PageTableTTBR1::new(0),
+        devices,
+        memory_map,
+        ArcInterrupts::new(InterruptArm64::new(0)),
+    )
 }
 
 /// Set the stack pointer at a certain location. Which should have been mapped already
@@ -190,9 +816,26 @@ pub fn set_stack(vaddr: u64) {
     }
 }
 
-/// Map a 4K aligned segment to somewhere in TTBR1 VA space
+/// Copy a segment's file bytes to the start of `dest` and zero the rest of it (.bss)
+pub fn load_segment(segment: &[u8], dest: &mut [u8]) {
+    if segment.len() > dest.len() {
+        panic!("load_segment segment is bigger than its destination!");
+    }
+
+    let (file_part, bss) = dest.split_at_mut(segment.len());
+    file_part.copy_from_slice(segment);
+    bss.fill(0);
+}
+
+/// Copy a segment to `vaddr` and zero it up to `mem_size` bytes
 /// Usually copies from boot stack/heap -> address
-pub fn map_segment(segment: &[u8], vaddr: u64) {}
+///
+/// # Safety
+/// vaddr..vaddr + mem_size has to be mapped, writable, and not hold anything still in use. It cant overlap segment
+pub unsafe fn map_segment(segment: &[u8], vaddr: u64, mem_size: u64) {
+    let dest = core::slice::from_raw_parts_mut(vaddr as *mut u8, mem_size as usize);
+    load_segment(segment, dest);
+}
 
 // ---------------
 // TESTS
@@ -207,3 +850,181 @@ fn basics() {
 fn make_default_arc_services() {
     make_default();
 }
+
+#[test]
+fn arc_services_handoff() {
+    let mut builder = make_default();
+    builder.memory_map.push(MemoryRegion::new(
+        MemoryRegionType::Standard,
+        address_range_4k(0x4000_0000, 16),
+    ));
+    builder.set_kaslr_slide(0x20_0000);
+    builder.set_paging(0x4010_0000);
+    builder.set_direct_map(0xFFFF_8000_0000_0000, 0xFFFF_A000_0000_0000);
+    let mut boot_info = bootinfo::BootInfoWriter::new();
+    boot_info.command_line("quiet");
+    builder.set_boot_info(boot_info.finish());
+
+    // pretend the kernel sees the buffer where we do
+    let mut buf = vec![0u64; builder.handoff_size().div_ceil(8)];
+    let vaddr = buf.as_ptr() as u64;
+    let services = builder.write(&mut buf, vaddr).unwrap();
+
+    let services = unsafe { ArcServices::from_ptr(services) }.unwrap();
+    assert_eq!(services.version(), ARC_SERVICES_VERSION);
+    assert_eq!(services.kaslr_slide(), 0x20_0000);
+    assert_eq!(services.paging().phys_addr_base(), 0x4010_0000);
+    assert_eq!(services.hhdm_offset(), 0xFFFF_8000_0000_0000);
+    assert_eq!(services.mmio_offset(), 0xFFFF_A000_0000_0000);
+    assert_eq!(services.boot_info().unwrap().command_line(), Some("quiet"));
+    assert!(services.command_line().unwrap().has("quiet"));
+    assert_eq!(services.devices(), &[ArcDevice::new(DeviceType::DRAM, 0)]);
+    assert_eq!(
+        services.memory_regions()[0].address_range(),
+        (0x4000_0000, 0x4001_0000)
+    );
+    assert_eq!(
+        services.memory_regions()[0].region_type(),
+        Some(MemoryRegionType::Standard)
+    );
+
+    // any change to the block gets caught
+    let last = buf.len() - 1;
+    buf[last] ^= 1;
+    assert_eq!(
+        unsafe { ArcServices::from_ptr(buf.as_ptr() as *const ArcServices) }.err(),
+        Some(HandoffError::BadChecksum)
+    );
+    buf[0] = 0;
+    assert_eq!(
+        unsafe { ArcServices::from_ptr(buf.as_ptr() as *const ArcServices) }.err(),
+        Some(HandoffError::BadMagic)
+    );
+
+    assert_eq!(
+        builder.write(&mut [0; 4], vaddr).err(),
+        Some(HandoffError::BufferTooSmall)
+    );
+}
+
+#[test]
+fn unknown_types() {
+    // written by a newer arcboot
+    let mut region = MemoryRegion::new(MemoryRegionType::Standard, (0, 0x1000));
+    region.region_type = 100;
+    assert_eq!(region.region_type(), None);
+
+    let mut device = ArcDevice::new(DeviceType::DRAM, 0);
+    device.device_type = 100;
+    assert_eq!(device.device_type(), None);
+    assert_eq!(DeviceType::from_u32(3), Some(DeviceType::DRAM));
+}
+
+#[test]
+fn arc_services_modules() {
+    let initrd = [0xA5u8; 100];
+    let mut builder = make_default();
+    // an odd sized boot info, so the modules need aligning
+    builder.set_boot_info(vec![0; 13]);
+    builder.add_module(
+        (0x4800_0000, 0x4800_0064),
+        initrd.as_ptr() as u64,
+        "/boot/initrd.img",
+        "initrd rw",
+    );
+    builder.add_module((0x4800_1000, 0x4800_1000), 0, "/empty", "");
+
+    let mut buf = vec![0u64; builder.handoff_size().div_ceil(8)];
+    let vaddr = buf.as_ptr() as u64;
+    let services =
+        unsafe { ArcServices::from_ptr(builder.write(&mut buf, vaddr).unwrap()) }.unwrap();
+
+    let modules = services.modules();
+    assert_eq!(modules.len(), 2);
+    assert_eq!(modules[0].address_range(), (0x4800_0000, 0x4800_0064));
+    assert_eq!(modules[0].name(), "/boot/initrd.img");
+    assert_eq!(modules[0].args(), "initrd rw");
+    assert_eq!(unsafe { modules[0].bytes() }, initrd);
+    assert_eq!(modules[1].size(), 0);
+    assert_eq!(modules[1].args(), "");
+
+    // a name pointing outside the block
+    let name_ptr = (&modules[1].name.ptr as *const u64 as u64 - vaddr) as usize / 8;
+    buf[name_ptr] = 0x10;
+    let sum = checksum(unsafe {
+        core::slice::from_raw_parts(buf.as_ptr() as *const u8, builder.handoff_size())
+    });
+    // checksum is the 4th u64
+    buf[3] = sum;
+    assert_eq!(
+        unsafe { ArcServices::from_ptr(buf.as_ptr() as *const ArcServices) }.err(),
+        Some(HandoffError::ArrayOutOfBounds)
+    );
+}
+
+#[cfg(test)]
+extern "C" fn test_handler(frame: &mut TrapFrame) {
+    frame.skip_instruction();
+}
+
+#[test]
+fn interrupt_handlers() {
+    let write = |builder: &ArcServicesBuilder, buf: &mut Vec<u64>| {
+        buf.resize(builder.handoff_size().div_ceil(8), 0);
+        let vaddr = buf.as_ptr() as u64;
+        builder.write(buf, vaddr).unwrap()
+    };
+
+    // no table handed over
+    let mut buf = Vec::new();
+    let services = unsafe { ArcServices::from_ptr(write(&make_default(), &mut buf)) }.unwrap();
+    assert_eq!(
+        services.register_interrupt_handler(VectorKind::Irq, test_handler),
+        Err(InterruptError::NoVectorTable)
+    );
+
+    let mut handlers: Vec<Option<InterruptHandler>> = vec![None; exception::VECTOR_ENTRIES];
+    let mut builder = make_default();
+    builder.set_interrupts(0xFFFF_C000_0000_0000, handlers.as_mut_ptr() as u64);
+    let mut buf = Vec::new();
+    let services = unsafe { ArcServices::from_ptr(write(&builder, &mut buf)) }.unwrap();
+    assert_eq!(services.vector_table(), 0xFFFF_C000_0000_0000);
+
+    // one per source
+    services
+        .register_interrupt_handler(VectorKind::Sync, test_handler)
+        .unwrap();
+    let registered: Vec<usize> = (0..handlers.len())
+        .filter(|i| handlers[*i].is_some())
+        .collect();
+    assert_eq!(registered, [0, 4, 8, 12]);
+
+    let mut frame = TrapFrame {
+        x: [0; 31],
+        elr: 0x1000,
+        spsr: 0,
+        esr: 1 << 25,
+        far: 0,
+        slot: 4,
+    };
+    handlers[4].unwrap()(&mut frame);
+    assert_eq!(frame.elr, 0x1004);
+
+    let el0_sync = VectorSlot::new(exception::ExceptionSource::LowerA64, VectorKind::Sync);
+    services.set_slot_handler(el0_sync, None).unwrap();
+    assert!(handlers[8].is_none());
+    services
+        .unregister_interrupt_handler(VectorKind::Sync)
+        .unwrap();
+    assert!(handlers.iter().all(|h| h.is_none()));
+
+    // what #[register_exception_handler(irq_el1h)] would have linked in
+    services
+        .register_handlers(&[ExceptionHandlerDescriptor::new(5, test_handler)])
+        .unwrap();
+    assert!(handlers[5].is_some());
+    assert_eq!(
+        services.register_handlers(&[ExceptionHandlerDescriptor::new(16, test_handler)]),
+        Err(InterruptError::BadSlot(16))
+    );
+}
@@ -0,0 +1,452 @@
+// ---------------
+// AARCH64 MMU CONFIG
+// ---------------
+
+// MAIR_EL1 and TCR_EL1, described instead of hand assembled. arcboot builds its handoff registers from this,
+// and a kernel that wants the same memory attributes (or just the same attribute indices in its descriptors) can build the same config
+
+use core::fmt;
+
+/// Translation granule. Page size, and the size of every table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Granule {
+    K4,
+    K16,
+    K64,
+}
+
+impl Granule {
+    pub const fn shift(self) -> u32 {
+        match self {
+            Self::K4 => 12,
+            Self::K16 => 14,
+            Self::K64 => 16,
+        }
+    }
+
+    pub const fn size(self) -> u64 {
+        1 << self.shift()
+    }
+
+    /// VA bits each level resolves. A table is one granule of 8 byte entries
+    pub const fn bits_per_level(self) -> u32 {
+        self.shift() - 3
+    }
+
+    /// Entries that can share one TLB entry with the contiguous hint
+    pub const fn contiguous_entries(self) -> u64 {
+        match self {
+            Self::K4 => 16,
+            Self::K16 => 128,
+            Self::K64 => 32,
+        }
+    }
+
+    /// TCR_EL1.TG0. Not the same encoding as TG1
+    const fn tg0(self) -> u64 {
+        match self {
+            Self::K4 => 0b00,
+            Self::K16 => 0b10,
+            Self::K64 => 0b01,
+        }
+    }
+
+    /// TCR_EL1.TG1
+    const fn tg1(self) -> u64 {
+        match self {
+            Self::K4 => 0b10,
+            Self::K16 => 0b01,
+            Self::K64 => 0b11,
+        }
+    }
+}
+
+// ---------------
+// MAIR
+// ---------------
+
+/// A memory type for one of the 8 MAIR_EL1 attribute slots
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAttribute {
+    /// Normal, inner and outer write-back non-transient, read and write allocate. RAM
+    NormalWriteBack,
+    /// Normal, inner and outer non-cacheable. Framebuffers, DMA buffers without coherency
+    NormalNonCacheable,
+    /// Device, no gathering, no reordering, no early write ack. Strictest, for registers with side effects
+    DeviceNgnrne,
+    /// Device, no gathering, no reordering, early write ack. Most MMIO
+    DeviceNgnre,
+}
+
+impl MemoryAttribute {
+    /// The 8 bit Attr<n> encoding
+    pub const fn encoding(self) -> u8 {
+        match self {
+            Self::NormalWriteBack => 0xFF,
+            Self::NormalNonCacheable => 0x44,
+            Self::DeviceNgnrne => 0x00,
+            Self::DeviceNgnre => 0x04,
+        }
+    }
+
+    pub const fn is_device(self) -> bool {
+        matches!(self, Self::DeviceNgnrne | Self::DeviceNgnre)
+    }
+}
+
+// Attribute indices arcboot's page tables use (AttrIndx in the descriptors)
+
+pub const ATTR_INDEX_DEVICE: usize = 0;
+pub const ATTR_INDEX_NORMAL: usize = 1;
+pub const ATTR_INDEX_NORMAL_NC: usize = 2;
+pub const ATTR_INDEX_DEVICE_NGNRE: usize = 3;
+
+/// What arcboot puts in MAIR_EL1 before entering the kernel
+pub const ARCBOOT_ATTRIBUTES: [Option<MemoryAttribute>; 8] = [
+    Some(MemoryAttribute::DeviceNgnrne),
+    Some(MemoryAttribute::NormalWriteBack),
+    Some(MemoryAttribute::NormalNonCacheable),
+    Some(MemoryAttribute::DeviceNgnre),
+    None,
+    None,
+    None,
+    None,
+];
+
+// ---------------
+// TCR
+// ---------------
+
+/// SHn, for table walks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shareability {
+    NonShareable = 0b00,
+    Outer = 0b10,
+    Inner = 0b11,
+}
+
+/// IRGNn and ORGNn, for table walks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cacheability {
+    NonCacheable = 0b00,
+    /// Write-back, read and write allocate
+    WriteBack = 0b01,
+    /// Write-through, read allocate
+    WriteThrough = 0b10,
+    /// Write-back, read allocate only
+    WriteBackNoWriteAllocate = 0b11,
+}
+
+/// How one half of the address space is translated. TTBR0 is the bottom, TTBR1 the top
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TranslationRegion {
+    pub granule: Granule,
+    /// 64 - TnSZ
+    pub va_bits: u32,
+    pub shareability: Shareability,
+    pub inner: Cacheability,
+    pub outer: Cacheability,
+}
+
+impl TranslationRegion {
+    /// Inner shareable, write-back walks. What RAM backed tables want
+    pub const fn new(granule: Granule, va_bits: u32) -> Self {
+        Self {
+            granule,
+            va_bits,
+            shareability: Shareability::Inner,
+            inner: Cacheability::WriteBack,
+            outer: Cacheability::WriteBack,
+        }
+    }
+
+    /// TnSZ, EPDn = 0, IRGNn, ORGNn, SHn and TGn, from bit 0 of the TTBR0 half
+    fn bits(&self, tg: u64) -> u64 {
+        (64 - self.va_bits as u64)
+            | (self.inner as u64) << 8
+            | (self.outer as u64) << 10
+            | (self.shareability as u64) << 12
+            | tg << 14
+    }
+}
+
+/// Why an MmuConfig couldnt be built
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmuConfigError {
+    /// VA size isnt 25 to 48 bits, or 52
+    VaBits(u32),
+    /// PA size isnt one of 32, 36, 40, 42, 44, 48 or 52
+    PaBits(u32),
+    /// 52 bit VAs or PAs with a 4K or 16K granule need LPA2
+    NeedsLpa2,
+    /// LPA2 with a 64K granule. 64K has its own 52 bit format
+    Lpa2Granule,
+    /// Attribute index is 8 or more
+    AttributeIndex(usize),
+}
+
+impl fmt::Display for MmuConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::VaBits(bits) => write!(f, "{bits} bit VAs are not supported"),
+            Self::PaBits(bits) => write!(f, "{bits} bit PAs are not supported"),
+            Self::NeedsLpa2 => write!(f, "52 bit addresses with a 4K or 16K granule need LPA2"),
+            Self::Lpa2Granule => write!(f, "LPA2 does not apply to the 64K granule"),
+            Self::AttributeIndex(index) => write!(f, "MAIR has no attribute {index}"),
+        }
+    }
+}
+
+/// Describes MAIR_EL1 and TCR_EL1. Both halves start with walks disabled (EPDn = 1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MmuConfigBuilder {
+    attributes: [Option<MemoryAttribute>; 8],
+    ttbr0: Option<TranslationRegion>,
+    ttbr1: Option<TranslationRegion>,
+    pa_bits: u32,
+    lpa2: bool,
+    bad_index: Option<usize>,
+}
+
+impl MmuConfigBuilder {
+    /// No attributes, no walks, 48 bit PAs
+    pub const fn new() -> Self {
+        Self {
+            attributes: [None; 8],
+            ttbr0: None,
+            ttbr1: None,
+            pa_bits: 48,
+            lpa2: false,
+            bad_index: None,
+        }
+    }
+
+    /// Set MAIR Attr<index>. Unset ones are 0, i.e. Device-nGnRnE
+    pub fn set_attribute(&mut self, index: usize, attribute: MemoryAttribute) {
+        match self.attributes.get_mut(index) {
+            Some(slot) => *slot = Some(attribute),
+            None => self.bad_index = Some(index),
+        }
+    }
+
+    /// Every attribute at once, like ARCBOOT_ATTRIBUTES
+    pub fn set_attributes(&mut self, attributes: [Option<MemoryAttribute>; 8]) {
+        self.attributes = attributes;
+    }
+
+    /// None disables TTBR0 walks, so every lower half access faults
+    pub fn set_ttbr0(&mut self, region: Option<TranslationRegion>) {
+        self.ttbr0 = region;
+    }
+
+    /// None disables TTBR1 walks, so every upper half access faults
+    pub fn set_ttbr1(&mut self, region: Option<TranslationRegion>) {
+        self.ttbr1 = region;
+    }
+
+    /// TCR_EL1.IPS. Shouldnt be more than ID_AA64MMFR0_EL1.PARange
+    pub fn set_pa_bits(&mut self, pa_bits: u32) {
+        self.pa_bits = pa_bits;
+    }
+
+    /// TCR_EL1.DS. 52 bit addresses with 4K and 16K, OA[51:50] moves into descriptor bits[9:8] and walks use SHn
+    pub fn set_lpa2(&mut self, lpa2: bool) {
+        self.lpa2 = lpa2;
+    }
+
+    /// Check the combination and work out the register values
+    pub fn build(&self) -> Result<MmuConfig, MmuConfigError> {
+        if let Some(index) = self.bad_index {
+            return Err(MmuConfigError::AttributeIndex(index));
+        }
+
+        let regions = [self.ttbr0, self.ttbr1];
+        let enabled = regions.iter().flatten();
+        for region in enabled.clone() {
+            if !(25..=48).contains(&region.va_bits) && region.va_bits != 52 {
+                return Err(MmuConfigError::VaBits(region.va_bits));
+            }
+            let is_64k = region.granule == Granule::K64;
+            if self.lpa2 && is_64k {
+                return Err(MmuConfigError::Lpa2Granule);
+            }
+            if region.va_bits == 52 && !is_64k && !self.lpa2 {
+                return Err(MmuConfigError::NeedsLpa2);
+            }
+        }
+
+        let ips = match self.pa_bits {
+            32 => 0,
+            36 => 1,
+            40 => 2,
+            42 => 3,
+            44 => 4,
+            48 => 5,
+            52 => 6,
+            bits => return Err(MmuConfigError::PaBits(bits)),
+        };
+        // 64K tables carry PA[51:48] without LPA2
+        let all_64k = enabled.clone().all(|r| r.granule == Granule::K64);
+        if self.pa_bits == 52 && !self.lpa2 && !all_64k {
+            return Err(MmuConfigError::NeedsLpa2);
+        }
+
+        let mair = self
+            .attributes
+            .iter()
+            .enumerate()
+            .map(|(index, attr)| (attr.map_or(0, |a| a.encoding()) as u64) << (index * 8))
+            .fold(0, |mair, attr| mair | attr);
+
+        let mut tcr = ips << 32;
+        tcr |= match self.ttbr0 {
+            Some(region) => region.bits(region.granule.tg0()),
+            None => TCR_EPD0,
+        };
+        tcr |= match self.ttbr1 {
+            Some(region) => region.bits(region.granule.tg1()) << 16,
+            None => TCR_EPD1,
+        };
+        if self.lpa2 {
+            tcr |= TCR_DS;
+        }
+
+        Ok(MmuConfig {
+            attributes: self.attributes,
+            mair,
+            tcr,
+        })
+    }
+}
+
+impl Default for MmuConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const TCR_EPD0: u64 = 1 << 7;
+const TCR_EPD1: u64 = 1 << 23;
+const TCR_DS: u64 = 1 << 59;
+
+/// A checked MAIR_EL1 and TCR_EL1. ASIDs come from TTBR0 and are 8 bits, TBI is off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MmuConfig {
+    attributes: [Option<MemoryAttribute>; 8],
+    mair: u64,
+    tcr: u64,
+}
+
+impl MmuConfig {
+    pub fn mair(&self) -> u64 {
+        self.mair
+    }
+
+    pub fn tcr(&self) -> u64 {
+        self.tcr
+    }
+
+    pub fn attribute(&self, index: usize) -> Option<MemoryAttribute> {
+        self.attributes.get(index).copied().flatten()
+    }
+
+    /// AttrIndx to put in descriptors for attribute, if MAIR has it
+    pub fn index_of(&self, attribute: MemoryAttribute) -> Option<usize> {
+        self.attributes.iter().position(|&a| a == Some(attribute))
+    }
+}
+
+// ---------------
+// TESTS
+// ---------------
+
+#[test]
+fn mmu_config_values() {
+    let mut builder = MmuConfigBuilder::new();
+    builder.set_attributes(ARCBOOT_ATTRIBUTES);
+    builder.set_ttbr1(Some(TranslationRegion::new(Granule::K4, 48)));
+    let config = builder.build().unwrap();
+
+    assert_eq!(config.mair(), 0x04_44_FF_00);
+    assert_eq!(
+        config.index_of(MemoryAttribute::DeviceNgnrne),
+        Some(ATTR_INDEX_DEVICE)
+    );
+    assert_eq!(
+        config.index_of(MemoryAttribute::NormalWriteBack),
+        Some(ATTR_INDEX_NORMAL)
+    );
+    assert_eq!(
+        config.attribute(ATTR_INDEX_NORMAL_NC),
+        Some(MemoryAttribute::NormalNonCacheable)
+    );
+    assert_eq!(
+        config.attribute(ATTR_INDEX_DEVICE_NGNRE),
+        Some(MemoryAttribute::DeviceNgnre)
+    );
+    assert_eq!(config.attribute(4), None);
+
+    // IPS = 48 bits, TG1 = 4K, SH1 = inner, ORGN1 = IRGN1 = WB, T1SZ = 16, EPD0
+    assert_eq!(
+        config.tcr(),
+        5 << 32 | 0b10 << 30 | 0b11 << 28 | 1 << 26 | 1 << 24 | 16 << 16 | 1 << 7
+    );
+
+    // identity mapped TTBR0 next to a 64K TTBR1, 52 bit PAs the 64K way
+    let mut lower = TranslationRegion::new(Granule::K64, 52);
+    lower.shareability = Shareability::Outer;
+    lower.inner = Cacheability::NonCacheable;
+    builder.set_ttbr0(Some(lower));
+    builder.set_ttbr1(Some(TranslationRegion::new(Granule::K64, 52)));
+    builder.set_pa_bits(52);
+    let tcr = builder.build().unwrap().tcr();
+    assert_eq!(tcr & 0xFFFF, 0b01 << 14 | 0b10 << 12 | 1 << 10 | 12);
+    assert_eq!((tcr >> 30) & 0b11, 0b11);
+    assert_eq!((tcr >> 32) & 0b111, 6);
+    assert_eq!(tcr & (TCR_EPD0 | TCR_EPD1 | TCR_DS), 0);
+
+    // 4K with 52 bit VAs sets DS
+    builder.set_ttbr0(None);
+    builder.set_ttbr1(Some(TranslationRegion::new(Granule::K4, 52)));
+    builder.set_lpa2(true);
+    let tcr = builder.build().unwrap().tcr();
+    assert_eq!(tcr & (TCR_EPD0 | TCR_DS), TCR_EPD0 | TCR_DS);
+    assert_eq!((tcr >> 16) & 0x3F, 12);
+}
+
+#[test]
+fn mmu_config_errors() {
+    let build = |ttbr1, pa_bits, lpa2| {
+        let mut builder = MmuConfigBuilder::new();
+        builder.set_ttbr1(Some(ttbr1));
+        builder.set_pa_bits(pa_bits);
+        builder.set_lpa2(lpa2);
+        builder.build()
+    };
+
+    assert_eq!(
+        build(TranslationRegion::new(Granule::K4, 49), 48, false),
+        Err(MmuConfigError::VaBits(49))
+    );
+    assert_eq!(
+        build(TranslationRegion::new(Granule::K4, 48), 50, false),
+        Err(MmuConfigError::PaBits(50))
+    );
+    assert_eq!(
+        build(TranslationRegion::new(Granule::K16, 52), 48, false),
+        Err(MmuConfigError::NeedsLpa2)
+    );
+    assert_eq!(
+        build(TranslationRegion::new(Granule::K4, 48), 52, false),
+        Err(MmuConfigError::NeedsLpa2)
+    );
+    assert_eq!(
+        build(TranslationRegion::new(Granule::K64, 48), 48, true),
+        Err(MmuConfigError::Lpa2Granule)
+    );
+    assert!(build(TranslationRegion::new(Granule::K64, 48), 52, false).is_ok());
+
+    let mut builder = MmuConfigBuilder::new();
+    builder.set_attribute(8, MemoryAttribute::NormalWriteBack);
+    assert_eq!(builder.build(), Err(MmuConfigError::AttributeIndex(8)));
+}
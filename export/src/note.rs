@@ -0,0 +1,256 @@
+// ---------------
+// ARCBOOT KERNEL NOTE
+// ---------------
+
+// A kernel tells arcboot what it needs through an ELF note in a PT_NOTE segment.
+// Put it in with arcboot_note! and make sure your linker script keeps .note.arcboot
+
+use core::mem::size_of;
+
+/// Note name. 4 bytes (with the NUL) so the desc after it stays 8 byte aligned
+pub const ARCBOOT_NOTE_NAME: [u8; 4] = *b"ARC\0";
+
+/// Note type for KernelRequirements
+pub const NT_ARCBOOT_REQUIREMENTS: u32 = 1;
+
+/// Layout version of KernelRequirements. Bump when adding fields to the end
+pub const ARCBOOT_NOTE_VERSION: u32 = 1;
+
+// CPU features a kernel can require. Bits of KernelRequirements::cpu_features
+
+/// Floating point
+pub const CPU_FEATURE_FP: u64 = 1 << 0;
+/// Advanced SIMD (NEON)
+pub const CPU_FEATURE_ASIMD: u64 = 1 << 1;
+/// LSE atomics
+pub const CPU_FEATURE_ATOMICS: u64 = 1 << 2;
+/// Privileged access never
+pub const CPU_FEATURE_PAN: u64 = 1 << 3;
+/// RNDR random numbers
+pub const CPU_FEATURE_RNDR: u64 = 1 << 4;
+/// Scalable vector extension
+pub const CPU_FEATURE_SVE: u64 = 1 << 5;
+/// Pointer authentication
+pub const CPU_FEATURE_PAUTH: u64 = 1 << 6;
+/// Branch target identification
+pub const CPU_FEATURE_BTI: u64 = 1 << 7;
+
+// Defaults for when a kernel has no note, or leaves a field as 0
+
+pub const DEFAULT_BOOT_STACK_SIZE: u64 = 16 * 4096;
+pub const DEFAULT_BOOT_HEAP_SIZE: u64 = 16 * 4096;
+/// Top of the boot stack. The last 64K of the address space stays unmapped, so it's aligned for every granule
+pub const DEFAULT_BOOT_STACK_TOP: u64 = 0xFFFF_FFFF_FFFF_0000;
+pub const DEFAULT_BOOT_HEAP_START: u64 = 0xFFFF_FFFF_0000_0000;
+/// Kernels are entered at EL1 unless they ask for something else
+pub const DEFAULT_ENTRY_EL: u32 = 1;
+
+/// What a kernel needs from arcboot. 0 in any field means "arcboot's default"
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelRequirements {
+    /// ARCBOOT_NOTE_VERSION the kernel was built against
+    pub note_version: u32,
+    /// Oldest ArcServices version the kernel can read
+    pub min_services_version: u32,
+    /// Bytes of boot stack, mapped below stack_top
+    pub stack_size: u64,
+    /// Bytes of boot heap, mapped from heap_start
+    pub heap_size: u64,
+    /// Where SP points at entry. Page aligned, in the higher half
+    pub stack_top: u64,
+    /// Page aligned, in the higher half
+    pub heap_start: u64,
+    /// Exception level to enter the kernel at
+    pub entry_el: u32,
+    pub reserved: u32,
+    /// CPU_FEATURE_* bits that have to be present
+    pub cpu_features: u64,
+}
+
+impl KernelRequirements {
+    pub const fn new(
+        min_services_version: u32,
+        stack_size: u64,
+        heap_size: u64,
+        stack_top: u64,
+        heap_start: u64,
+        entry_el: u32,
+        cpu_features: u64,
+    ) -> Self {
+        Self {
+            note_version: ARCBOOT_NOTE_VERSION,
+            min_services_version,
+            stack_size,
+            heap_size,
+            stack_top,
+            heap_start,
+            entry_el,
+            reserved: 0,
+            cpu_features,
+        }
+    }
+
+    /// Replace every 0 field with arcboot's default
+    pub fn with_defaults(mut self) -> Self {
+        let default = Self::default();
+
+        if self.stack_size == 0 {
+            self.stack_size = default.stack_size;
+        }
+        if self.heap_size == 0 {
+            self.heap_size = default.heap_size;
+        }
+        if self.stack_top == 0 {
+            self.stack_top = default.stack_top;
+        }
+        if self.heap_start == 0 {
+            self.heap_start = default.heap_start;
+        }
+        if self.entry_el == 0 {
+            self.entry_el = default.entry_el;
+        }
+
+        self
+    }
+}
+
+impl Default for KernelRequirements {
+    /// What arcboot does for kernels without a note
+    fn default() -> Self {
+        Self::new(
+            0,
+            DEFAULT_BOOT_STACK_SIZE,
+            DEFAULT_BOOT_HEAP_SIZE,
+            DEFAULT_BOOT_STACK_TOP,
+            DEFAULT_BOOT_HEAP_START,
+            DEFAULT_ENTRY_EL,
+            0,
+        )
+    }
+}
+
+/// A whole ELF note holding KernelRequirements, as it sits in .note.arcboot
+#[repr(C, align(8))]
+pub struct ArcbootNote {
+    pub namesz: u32,
+    pub descsz: u32,
+    pub n_type: u32,
+    pub name: [u8; 4],
+    pub desc: KernelRequirements,
+}
+
+impl ArcbootNote {
+    pub const fn new(desc: KernelRequirements) -> Self {
+        Self {
+            namesz: ARCBOOT_NOTE_NAME.len() as u32,
+            descsz: size_of::<KernelRequirements>() as u32,
+            n_type: NT_ARCBOOT_REQUIREMENTS,
+            name: ARCBOOT_NOTE_NAME,
+            desc,
+        }
+    }
+}
+
+/// Declare a kernel's KernelRequirements. Emits the note into .note.arcboot
+#[macro_export]
+macro_rules! arcboot_note {
+    ($requirements:expr) => {
+        #[used]
+        #[link_section = ".note.arcboot"]
+        static ARCBOOT_NOTE: $crate::note::ArcbootNote =
+            $crate::note::ArcbootNote::new($requirements);
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoteError {
+    /// A note's header or data goes past the end of the segment
+    Truncated,
+    /// The arcboot note is from a newer/older format arcboot cant read
+    UnsupportedVersion(u32),
+}
+
+/// Notes pad their name and desc out to 4 bytes
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+/// Look through the notes of a PT_NOTE segment for the arcboot requirements note
+pub fn find_requirements(notes: &[u8]) -> Result<Option<KernelRequirements>, NoteError> {
+    let read_u32 = |at: usize| -> Result<u32, NoteError> {
+        let bytes = notes.get(at..at + 4).ok_or(NoteError::Truncated)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+
+    let mut offset = 0;
+    while offset < notes.len() {
+        let namesz = read_u32(offset)? as usize;
+        let descsz = read_u32(offset + 4)? as usize;
+        let n_type = read_u32(offset + 8)?;
+
+        // name and desc are both padded to 4 bytes
+        let name_start = offset + 12;
+        let desc_start = name_start + align4(namesz);
+        let next = desc_start + align4(descsz);
+
+        let name = notes
+            .get(name_start..name_start + namesz)
+            .ok_or(NoteError::Truncated)?;
+        let desc = notes
+            .get(desc_start..desc_start + descsz)
+            .ok_or(NoteError::Truncated)?;
+
+        if name == ARCBOOT_NOTE_NAME && n_type == NT_ARCBOOT_REQUIREMENTS {
+            // desc may be padded past KernelRequirements, only the start is read
+            if desc.len() < size_of::<KernelRequirements>() {
+                return Err(NoteError::Truncated);
+            }
+
+            let requirements =
+                unsafe { core::ptr::read_unaligned(desc.as_ptr() as *const KernelRequirements) };
+            if requirements.note_version == 0 || requirements.note_version > ARCBOOT_NOTE_VERSION {
+                return Err(NoteError::UnsupportedVersion(requirements.note_version));
+            }
+
+            return Ok(Some(requirements));
+        }
+
+        offset = next;
+    }
+
+    Ok(None)
+}
+
+// ---------------
+// TESTS
+// ---------------
+
+#[test]
+fn note_round_trip() {
+    let requirements = KernelRequirements::new(1, 0x10000, 0, 0, 0, 1, CPU_FEATURE_FP);
+    let note = ArcbootNote::new(requirements);
+
+    // another vendor's note first, like a GNU build id
+    let mut notes = alloc::vec::Vec::new();
+    notes.extend_from_slice(&4u32.to_le_bytes());
+    notes.extend_from_slice(&8u32.to_le_bytes());
+    notes.extend_from_slice(&3u32.to_le_bytes());
+    notes.extend_from_slice(b"GNU\0");
+    notes.extend_from_slice(&[0xAB; 8]);
+    notes.extend_from_slice(unsafe {
+        core::slice::from_raw_parts(&note as *const _ as *const u8, size_of::<ArcbootNote>())
+    });
+
+    assert_eq!(find_requirements(&notes), Ok(Some(requirements)));
+    assert_eq!(find_requirements(&notes[..24]), Ok(None));
+    assert_eq!(
+        find_requirements(&notes[..notes.len() - 8]),
+        Err(NoteError::Truncated)
+    );
+
+    let defaults = requirements.with_defaults();
+    assert_eq!(defaults.stack_size, 0x10000);
+    assert_eq!(defaults.heap_size, DEFAULT_BOOT_HEAP_SIZE);
+    assert_eq!(defaults.stack_top, DEFAULT_BOOT_STACK_TOP);
+}
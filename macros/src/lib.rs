@@ -1,6 +1,8 @@
 #![no_std]
 
+extern crate alloc;
 extern crate proc_macro;
+use alloc::string::ToString;
 use proc_macro::TokenStream;
 
 // NOTES:
@@ -18,14 +20,85 @@ pub fn arc_entry(args: TokenStream, item: TokenStream) -> TokenStream {
     .into()
 }
 
+/// Vector table entries, in table order. The names Linux gives its entry points
+const VECTOR_NAMES: [&str; 16] = [
+    "sync_el1t",
+    "irq_el1t",
+    "fiq_el1t",
+    "error_el1t",
+    "sync_el1h",
+    "irq_el1h",
+    "fiq_el1h",
+    "error_el1h",
+    "sync_el0_64",
+    "irq_el0_64",
+    "fiq_el0_64",
+    "error_el0_64",
+    "sync_el0_32",
+    "irq_el0_32",
+    "fiq_el0_32",
+    "error_el0_32",
+];
+
+/// `#[register_exception_handler(sync_el1h)]` on a `fn(frame: &mut TrapFrame)`, or a fn with no arguments
+/// Makes it extern "C" and puts an ExceptionHandlerDescriptor for it in the arcboot_exception_handlers section,
+/// which arcboot_api::exception::linked_exception_handlers reads back
 #[proc_macro_attribute]
 pub fn register_exception_handler(args: TokenStream, item: TokenStream) -> TokenStream {
-    let input = syn::parse_macro_input!(item as syn::ItemFn);
+    let entry = syn::parse_macro_input!(args as syn::Ident);
+    let mut input = syn::parse_macro_input!(item as syn::ItemFn);
+
+    let slot = match VECTOR_NAMES.iter().position(|name| entry == *name) {
+        Some(slot) => slot,
+        None => {
+            return syn::Error::new(
+                entry.span(),
+                "expected a vector table entry, like sync_el1h, irq_el0_64 or error_el1t",
+            )
+            .to_compile_error()
+            .into()
+        }
+    };
+
+    // the vector table calls it with x0 = the trap frame
+    if let Some(abi) = input.sig.abi.as_ref().and_then(|abi| abi.name.as_ref()) {
+        if abi.value() != "C" {
+            return syn::Error::new_spanned(abi, "exception handlers are always extern \"C\"")
+                .to_compile_error()
+                .into();
+        }
+    }
+    input.sig.abi = Some(syn::parse_quote!(extern "C"));
+    match input.sig.inputs.len() {
+        0 => input
+            .sig
+            .inputs
+            .push(syn::parse_quote!(_frame: &mut ::arcboot_api::exception::TrapFrame)),
+        1 => {}
+        _ => {
+            return syn::Error::new_spanned(
+                &input.sig.inputs,
+                "exception handlers only get the trap frame",
+            )
+            .to_compile_error()
+            .into()
+        }
+    }
+
+    // a wrong signature fails here, as a mismatch with InterruptHandler
+    let name = &input.sig.ident;
+    let descriptor = quote::format_ident!(
+        "__ARCBOOT_EXCEPTION_HANDLER_{}",
+        name.to_string().to_uppercase()
+    );
 
     quote::quote! {
-        // .exceptions is an executable, rdonly section linked by arcboot
-        #[link_section = ".exceptions"]
         #input
+
+        #[used]
+        #[link_section = "arcboot_exception_handlers"]
+        static #descriptor: ::arcboot_api::exception::ExceptionHandlerDescriptor =
+            ::arcboot_api::exception::ExceptionHandlerDescriptor::new(#slot, #name);
     }
     .into()
 }
@@ -12,7 +12,7 @@ extern crate log;
 extern crate alloc;
 
 #[cfg(feature = "builtin_allocator")]
-use arcboot::memory::heap::init_heap;
+use arcboot::memory::heap::{init_heap, HEAP_PAGES, HEAP_START};
 
 use aarch64::regs::{
     CurrentEL, ELR_EL2, ELR_EL3, HCR_EL2, MAIR_EL1, SCTLR_EL1, SP, SPSR_EL3, SP_EL1,
@@ -21,16 +21,40 @@ use aarch64::regs::{
 };
 use acpi::{AcpiHandler, AcpiTables, PhysicalMapping};
 use alloc::{
+    format,
     string::String,
     vec::{self, Vec},
 };
-use arcboot::{efi::{acpi::get_acpi_tables, MemoryMapEFI}, arm64::memory::setup_kernel_tables};
-use arcboot::efi::get_mem_map;
+use arcboot::boot::{
+    enter_kernel, load_modules, reserve_kernel_segments, KernelImage, LoadOptions,
+};
+use arcboot::config::{BootConfig, BootEntry, BootModule, CONFIG_PATH};
+use arcboot::efi::{
+    create_arc_memory_from_uefi, create_boot_frames_from_uefi, get_mem_map, uefi_region_containing,
+};
+use arcboot::efi::{
+    file::{BootVolume, FileBuffer},
+    menu::choose_entry,
+};
+use arcboot::memory::{frame::FrameAllocator, DirectMap, WIDE_HHDM_OFFSET, WIDE_MMIO_OFFSET};
+use arcboot::{
+    arm64::{
+        cache,
+        handoff::Arm64Handoff,
+        interrupt::KernelVectors,
+        memory::{
+            pick_translation_mode, setup_kernel_tables, Granule, KernelSegmentMapper,
+            TranslationMode,
+        },
+    },
+    efi::{acpi::get_acpi_tables, MemoryMapEFI},
+};
 use arcboot::{
     efi::{acpi::AcpiHandle, AlignToMemoryDescriptor},
     logger::init_runtime_logger,
     print_serial_line,
 };
+use arcboot_api::bootinfo::{BootInfoWriter, MemoryMapEntry};
 
 use arcboot::*;
 
@@ -46,7 +70,7 @@ use uefi::{
     prelude::*,
     proto::console::{serial::Serial, text::Output},
     table::{
-        boot::{MemoryType, OpenProtocolAttributes, OpenProtocolParams},
+        boot::{MemoryDescriptor, MemoryType, OpenProtocolAttributes, OpenProtocolParams},
         cfg::{self, ConfigTableEntry},
         runtime::VariableVendor,
         Runtime,
@@ -96,7 +120,11 @@ fn efi_main(image: Handle, mut system_table: SystemTable<Boot>) -> Status {
     use uefi::table::boot::AllocateType::Address;
 
     // IF TOO MUCH (0x4000_0000), WILL FAIL!
-    let status = bt.allocate_pages(Address(0x4000_0000), MemoryType::LOADER_DATA, 0x4000);
+    let status = bt.allocate_pages(
+        Address(HEAP_START as u64),
+        MemoryType::LOADER_DATA,
+        HEAP_PAGES,
+    );
 
     match status {
         Ok(s) => info!("Allocate page success!"),
@@ -130,10 +158,6 @@ fn efi_main(image: Handle, mut system_table: SystemTable<Boot>) -> Status {
     let sp = SP.get();
     info!("Stack pointer EL1 = {sp:#04X}");
 
-    // Retrieve a handle to the file system the image was booted from
-    bt.get_image_file_system(image)
-        .expect("Failed to retrieve boot file system");
-
     // Get the memory map size (note is pretty useless unless we use Alloc)
     let _size = bt.memory_map_size().map_size;
     info!("Memory map size (bytes)= {_size}");
@@ -156,6 +180,94 @@ fn efi_main(image: Handle, mut system_table: SystemTable<Boot>) -> Status {
 
     info!("Initialising boot protocol");
 
+    // What the kernel's arcboot note gets checked against
+    let mut load_options = LoadOptions::default();
+    load_options.cpu_features = arcboot::arm64::cpu::features();
+    load_options.current_el = arcboot::arm64::cpu::current_el();
+
+    // Randomise where relocatable kernels go, unless built for debugging
+    #[cfg(not(feature = "no_kaslr"))]
+    {
+        load_options.kaslr_seed = arcboot::efi::kaslr_seed(system_table.boot_services());
+        if load_options.kaslr_seed.is_none() {
+            warn!("No RNG available, KASLR is off");
+        }
+    }
+    // for the kernel's own RNG, passed as a TAG_RNG_SEED
+    let rng_seed = arcboot::efi::rng_seed(system_table.boot_services());
+
+    // kernels are linked in the top of a 48 bit TTBR1, which a 52 bit one still covers. 52 bits costs a level of tables though, so only when RAM needs it
+    let high_ram = create_arc_memory_from_uefi(&mem_map, &[])
+        .regions()
+        .iter()
+        .any(|region| region.address_range().1 > 1 << 48);
+    let wide_modes = [
+        TranslationMode::new(Granule::K4, 52).unwrap(),
+        TranslationMode::new(Granule::K16, 52).unwrap(),
+        TranslationMode::new(Granule::K64, 52).unwrap(),
+    ];
+    let modes = [
+        TranslationMode::DEFAULT,
+        TranslationMode::new(Granule::K16, 48).unwrap(),
+        TranslationMode::new(Granule::K64, 48).unwrap(),
+    ];
+    let translation_mode = high_ram
+        .then(|| pick_translation_mode(&wide_modes))
+        .flatten()
+        .or_else(|| pick_translation_mode(&modes))
+        .expect("No supported translation granule");
+    if translation_mode.va_bits() == 52 {
+        // the default direct map only has room for 32 TiB
+        load_options.direct_map = DirectMap::new(WIDE_HHDM_OFFSET, WIDE_MMIO_OFFSET);
+    } else if high_ram {
+        warn!("RAM above 48 bits, but 52 bit tables arent supported. It wont be in the direct map");
+    }
+    // the kernel is mapped in whole pages of the granule
+    load_options.page_size = translation_mode.page_size();
+
+    // -----------
+    // BOOT ENTRY
+    // -----------
+
+    // the config, and the kernel it points at, have to be read while boot services are up
+    let mut config = BootVolume::open(system_table.boot_services(), image)
+        .expect("Failed to open the boot volume")
+        .load_config()
+        .unwrap_or_else(|err| {
+            error!("{CONFIG_PATH} {err}, using the defaults");
+            BootConfig::default()
+        });
+    // arguments arcboot was started with, e.g. from the UEFI shell, replace every entry's cmdline. They can still be edited in the menu
+    if let Some(cmdline) = arcboot::efi::load_options_cmdline(system_table.boot_services(), image) {
+        info!("Using the command line arcboot was started with: {cmdline}");
+        for entry in &mut config.entries {
+            entry.cmdline = cmdline.clone();
+        }
+    }
+    // the chosen entry's kernel is checked while another can still be picked. One that wont load is reported and taken out of the menu
+    let (entry, files) = loop {
+        if config.entries.is_empty() {
+            panic!("No boot entry has a kernel arcboot can load");
+        }
+        let entry = choose_entry(&mut system_table, &config);
+        let mut options = load_options.clone();
+        entry.apply(&mut options);
+        match load_entry(system_table.boot_services(), image, &entry, &options) {
+            Ok(files) => {
+                load_options = options;
+                break (entry, files);
+            }
+            Err(err) => {
+                error!("{err}");
+                config.remove_entry(&entry.id);
+            }
+        }
+    };
+    info!(
+        "Booting {} ({}) with `{}`",
+        entry.title, entry.kernel, entry.cmdline
+    );
+
     // IF HYPERVISOR feature is on, trap into EL2 instead since we are at EL1 for riscv, trap to H-Mode
     #[cfg(feature = "archypervisor")]
     arcboot::arm64::trap_to_el2();
@@ -167,9 +279,13 @@ fn efi_main(image: Handle, mut system_table: SystemTable<Boot>) -> Status {
 
     info!("max_mmap_size: {}", &max_mmap_size);
 
-    let (st, _iter) = system_table
+    let (st, mmap_iter) = system_table
         .exit_boot_services(image, &mut mmap_storage[..])
         .expect("Failed to exit boot services");
+    let efi_memory_map: Vec<MemoryDescriptor> = mmap_iter.copied().collect();
+
+    // UEFI's vectors are no use now. Faults from here on get a register dump
+    arcboot::arm64::interrupt::install();
 
     // -----------
     // LOAD ARCBOOT DRIVERS
@@ -244,29 +360,66 @@ fn efi_main(image: Handle, mut system_table: SystemTable<Boot>) -> Status {
 
     info!("Setting up Arc Memory Protocol...");
 
-    use arcboot_api::MemoryMap;
-    let memory_map = MemoryMap::default();
-    setup_kernel_tables(memory_map);
-
+    use arcboot_api::address_range_4k;
+    // the heap at HEAP_START and the files read off the boot volume are arcboot's, even if the firmware lost track of them
+    let mut arcboot_allocations =
+        alloc::vec![address_range_4k(HEAP_START as u64, HEAP_PAGES as u64)];
+    arcboot_allocations.push(files.kernel_img.address_range());
+    arcboot_allocations.extend(files.modules.iter().map(|(_, file)| file.address_range()));
+    arcboot_allocations.extend(
+        files
+            .device_tree
+            .iter()
+            .map(|(_, file)| file.address_range()),
+    );
+    // the firmware's stack we are still on, and where exit_boot_services wrote the map. Both would be Standard otherwise
+    let sp = SP.get();
+    arcboot_allocations.extend(uefi_region_containing(&efi_memory_map, sp));
+    let mmap_start = mmap_storage.as_ptr() as u64;
+    arcboot_allocations.push((mmap_start, mmap_start + mmap_storage.len() as u64));
+    let memory_map = create_arc_memory_from_uefi(&efi_memory_map, &arcboot_allocations);
+    // every frame the kernel's tables, stack, heap and segments use comes from here. Only conventional memory outside arcboot_allocations,
+    // boot services memory still has the firmware's TTBR0 tables in it until the handoff. The kernel gets that as Standard
+    let frames = FrameAllocator::new(&create_boot_frames_from_uefi(
+        &efi_memory_map,
+        &arcboot_allocations,
+    ));
     // Maybe setup memory in the kernel. Could then hand off mmap_storage to the kernel to give it an idea of the memory map
     // st.set_virtual_address_map(map, new_system_table_virtual_addr); Or use a custom format
-    #[cfg(target_arch = "aarch64")]
-    arcboot::arm64::memory::setup();
+    // the MMU is only reconfigured by the handoff, right before the kernel runs
 
     info!("Attempting to Load Kernel...");
 
-    // HAND OFF TO KERNEL. Search for an arcboot compliant kernel ELF img in the standard location on the main configured NeFS or EFI boot config where DEFAULT_KERNEL_PARTITION=drive<number>partiton<number>
-    // NOTE: before kernel loads userspace, do TLBI ALLE0 to clear TLB
-    // PASS: the runtime services table, RSDP pointer, and thats pretty much it
-    load_arcboot_kernel();
-
     // GET ACPI RSDT. AARCH64, in the kernel
     // get_acpi_tables(rt, config_table);
 
-    // Kernel returns or traps to EL2 with reset exception/shutdown exception
-    info!("Shutting down arcboot!");
+    // the tags for this boot, copied in next to ArcServices. load_arcboot_kernel adds the ones for what it loads
+    let mut boot_info = BootInfoWriter::new();
+    boot_info
+        .command_line(&entry.cmdline)
+        .efi_system_table(st.as_ptr() as u64);
+    match arcboot::efi::acpi::rsdp(config_table) {
+        Some(rsdp) => {
+            boot_info.rsdp(rsdp);
+        }
+        None => warn!("No ACPI RSDP in the config table"),
+    }
+    if let Some(seed) = &rng_seed {
+        boot_info.rng_seed(seed);
+    }
 
-    arcboot::efi::shutdown(st);
+    // HAND OFF TO KERNEL. The boot entry's kernel, read off the boot volume before boot services exited
+    // NOTE: before kernel loads userspace, do TLBI ALLE0 to clear TLB
+    // PASS: the runtime services table, RSDP pointer, and thats pretty much it
+    load_arcboot_kernel(
+        &load_options,
+        &entry,
+        files,
+        &memory_map,
+        frames,
+        translation_mode,
+        boot_info,
+    )
 }
 
 /// Move the mem descriptors here
@@ -276,8 +429,183 @@ fn handle_memory_map(bootservices: &BootServices) -> MemoryMapEFI {
     res
 }
 
-/// Load kernel
-fn load_arcboot_kernel() {}
+/// What load_entry read off the boot volume for an entry. The files stay in their LOADER_DATA pages until the kernel is loaded
+struct EntryFiles {
+    kernel_img: FileBuffer,
+    /// Parsed from kernel_img
+    kernel: KernelImage,
+    modules: Vec<(BootModule, FileBuffer)>,
+    /// Read like a module, but passed as a TAG_DTB
+    device_tree: Option<(BootModule, FileBuffer)>,
+}
+
+/// Read the entry's kernel, modules and device tree off the boot volume, and check the kernel parses and has somewhere to go
+/// Whatever was read is freed again if it doesnt
+fn load_entry(
+    bt: &BootServices,
+    image: Handle,
+    entry: &BootEntry,
+    load_options: &LoadOptions,
+) -> Result<EntryFiles, String> {
+    let mut volume = BootVolume::open(bt, image).expect("Failed to open the boot volume");
+    let kernel_img = volume
+        .read_file(&entry.kernel)
+        .map_err(|err| format!("Could not read the kernel: {err}"))?;
+    let kernel = match KernelImage::parse(&kernel_img, load_options) {
+        Ok(kernel) => kernel,
+        Err(err) => {
+            volume.free_file(kernel_img);
+            return Err(format!(
+                "{} is not a kernel arcboot can load: {err}",
+                entry.kernel
+            ));
+        }
+    };
+    // a dry run on the conventional memory free now. The files and heap are LOADER_DATA, so already out of it
+    let mut frames = FrameAllocator::new(&create_boot_frames_from_uefi(&get_mem_map(bt), &[]));
+    if let Err(err) =
+        reserve_kernel_segments(&mut frames, &mut kernel.clone(), load_options.page_size)
+    {
+        volume.free_file(kernel_img);
+        return Err(format!("Could not place {}: {err}", entry.kernel));
+    }
+
+    // the initrd and any other blobs, copied into place after the kernel
+    let mut modules = Vec::new();
+    for module in &entry.modules {
+        match volume.read_file(&module.path) {
+            Ok(bytes) => {
+                info!("Module {} ({} bytes)", module.path, bytes.len());
+                modules.push((module.clone(), bytes));
+            }
+            Err(err) => {
+                volume.free_file(kernel_img);
+                for (_, file) in modules {
+                    volume.free_file(file);
+                }
+                return Err(format!("Could not read a boot module: {err}"));
+            }
+        }
+    }
+
+    let device_tree = match &entry.device_tree {
+        Some(path) => match volume.read_file(path) {
+            Ok(bytes) => {
+                info!("Device tree {path} ({} bytes)", bytes.len());
+                let module = BootModule {
+                    path: path.clone(),
+                    args: String::new(),
+                };
+                Some((module, bytes))
+            }
+            Err(err) => {
+                volume.free_file(kernel_img);
+                for (_, file) in modules {
+                    volume.free_file(file);
+                }
+                return Err(format!("Could not read the device tree: {err}"));
+            }
+        },
+        None => None,
+    };
+
+    Ok(EntryFiles {
+        kernel_img,
+        kernel,
+        modules,
+        device_tree,
+    })
+}
+
+/// Load the entry's kernel, modules and device tree from what load_entry read, build its tables, then enter it through the handoff
+/// boot_info has the tags that dont depend on what gets loaded. The memory map and device tree ones are added here
+fn load_arcboot_kernel(
+    load_options: &LoadOptions,
+    entry: &BootEntry,
+    files: EntryFiles,
+    memory_map: &arcboot_api::MemoryMap,
+    mut frames: FrameAllocator,
+    translation_mode: TranslationMode,
+    mut boot_info: BootInfoWriter,
+) -> ! {
+    let mut kernel = files.kernel;
+    // before anything else allocates, so nothing lands where the segments go. Relocatable kernels get their frames here
+    reserve_kernel_segments(&mut frames, &mut kernel, translation_mode.page_size())
+        .unwrap_or_else(|err| panic!("Could not place {}: {err}", entry.kernel));
+
+    let mut kernel_tables = setup_kernel_tables(
+        &mut frames,
+        &kernel.requirements,
+        memory_map,
+        &load_options.direct_map,
+        translation_mode,
+    );
+    kernel.load(
+        &files.kernel_img,
+        &mut KernelSegmentMapper::new(&mut kernel_tables, &mut frames),
+    );
+    // still identity mapped, like the kernel's segments
+    let mut write = |paddr: u64, size: u64, bytes: &[u8]| unsafe {
+        core::ptr::copy_nonoverlapping(bytes.as_ptr(), paddr as *mut u8, bytes.len());
+        core::ptr::write_bytes(
+            (paddr + bytes.len() as u64) as *mut u8,
+            0,
+            (size - bytes.len() as u64) as usize,
+        );
+        cache::clean_dcache_range(paddr, size);
+    };
+    // the kernel reaches modules through the direct map, so they only need frames
+    let loaded_modules = load_modules(
+        &files.modules,
+        &mut frames,
+        &load_options.direct_map,
+        kernel_tables.page_size(),
+        &mut write,
+    )
+    .unwrap_or_else(|err| panic!("Could not load the boot modules: {err}"));
+    // copied like a module, but passed as a TAG_DTB instead of a TAG_MODULE
+    let loaded_dtb = files.device_tree.as_ref().map(|dtb| {
+        load_modules(
+            core::slice::from_ref(dtb),
+            &mut frames,
+            &load_options.direct_map,
+            kernel_tables.page_size(),
+            &mut write,
+        )
+        .unwrap_or_else(|err| panic!("Could not load the device tree: {err}"))
+        .remove(0)
+    });
+
+    // the vector table the kernel starts with, until it installs its own
+    let kernel_vectors =
+        KernelVectors::new(&mut kernel_tables, &load_options.direct_map, &mut frames)
+            .unwrap_or_else(|err| panic!("Could not map the kernel's vector table: {err}"));
+    // the identity mapped tables the switch to the kernel's tables runs on
+    let handoff = Arm64Handoff::new(&kernel_tables, &kernel_vectors, &mut frames)
+        .unwrap_or_else(|err| panic!("Could not set up the MMU handoff: {err}"));
+    // what the kernel gets, with everything allocated above marked as in use
+    let kernel_memory_map = frames.memory_map(memory_map);
+    info!("{} frames left for the kernel", frames.free_frames());
+
+    let memory_map_entries: Vec<MemoryMapEntry> = kernel_memory_map
+        .regions()
+        .iter()
+        .map(MemoryMapEntry::from)
+        .collect();
+    boot_info.memory_map(&memory_map_entries);
+    if let Some(dtb) = &loaded_dtb {
+        boot_info.dtb(dtb.range.0, dtb.range.1 - dtb.range.0);
+    }
+
+    enter_kernel(
+        &kernel,
+        kernel_memory_map,
+        &load_options.direct_map,
+        boot_info,
+        &loaded_modules,
+        handoff,
+    )
+}
 
 // ----------------
 // PANIC